@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+
+use crate::font::{Font, Glyph, MAX_GLYPH_SIZE};
+
+/// A BDF bitmap font, with every glyph moved into a cell of the same size so it can go on the grid.
+///
+/// The cell is as wide as most glyphs advance, and as tall as `FONT_ASCENT` plus `FONT_DESCENT` (or the font bounding box, if those are missing).
+/// Glyphs that stick out of the cell get cut off.
+pub struct BdfFont {
+    width: usize,
+    height: usize,
+    /// Bytes per glyph, `height` rows of `width.div_ceil(8)` bytes.
+    glyph_len: usize,
+    glyphs: Vec<u8>,
+    chars: HashMap<char, usize>,
+}
+
+/// A glyph as it appears in the file, before it's been put in a cell.
+struct RawGlyph {
+    encoding: Option<char>,
+    dwidth: Option<usize>,
+    /// Width, height, x offset and y offset of the bitmap from the origin, y going up.
+    bbx: Option<[i32; 4]>,
+    rows: Vec<Vec<u8>>,
+}
+
+impl BdfFont {
+    pub fn parse(text: &str) -> Result<BdfFont, String> {
+        let mut lines = text.lines().map(str::trim).enumerate();
+        let Some((_, first)) = lines.next() else {
+            return Err("Empty BDF font".to_string());
+        };
+        if !first.starts_with("STARTFONT") {
+            return Err("Not a BDF font (no STARTFONT)".to_string());
+        }
+        let mut font_bbx: Option<[i32; 4]> = None;
+        let mut ascent: Option<i32> = None;
+        let mut descent: Option<i32> = None;
+        let mut raw_glyphs: Vec<RawGlyph> = vec![];
+        let mut current: Option<RawGlyph> = None;
+        let mut in_bitmap = false;
+        for (line_no, line) in lines {
+            let bad = |what: &str| format!("Bad {what} on line {}: {line}", line_no + 1);
+            let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
+            if in_bitmap {
+                let glyph = current.as_mut().expect("Only in a bitmap inside a glyph");
+                if keyword == "ENDCHAR" {
+                    in_bitmap = false;
+                    raw_glyphs.extend(current.take());
+                } else {
+                    glyph
+                        .rows
+                        .push(parse_hex_row(line).ok_or_else(|| bad("bitmap row"))?);
+                }
+                continue;
+            }
+            match keyword {
+                "FONTBOUNDINGBOX" => {
+                    font_bbx = Some(parse_bbx(args).ok_or_else(|| bad("FONTBOUNDINGBOX"))?)
+                }
+                "FONT_ASCENT" => {
+                    ascent = Some(parse_metric(args).ok_or_else(|| bad("FONT_ASCENT"))?)
+                }
+                "FONT_DESCENT" => {
+                    descent = Some(parse_metric(args).ok_or_else(|| bad("FONT_DESCENT"))?)
+                }
+                "STARTCHAR" => {
+                    current = Some(RawGlyph {
+                        encoding: None,
+                        dwidth: None,
+                        bbx: None,
+                        rows: vec![],
+                    })
+                }
+                "ENCODING" => {
+                    let glyph = current
+                        .as_mut()
+                        .ok_or_else(|| bad("ENCODING outside a glyph"))?;
+                    //-1 means the glyph has no encoding, and can't be reached
+                    let encoding: i64 = args
+                        .split_whitespace()
+                        .next()
+                        .and_then(|e| e.parse().ok())
+                        .ok_or_else(|| bad("ENCODING"))?;
+                    glyph.encoding = u32::try_from(encoding).ok().and_then(char::from_u32);
+                }
+                "DWIDTH" => {
+                    let glyph = current
+                        .as_mut()
+                        .ok_or_else(|| bad("DWIDTH outside a glyph"))?;
+                    glyph.dwidth = args.split_whitespace().next().and_then(|w| w.parse().ok());
+                }
+                "BBX" => {
+                    let glyph = current.as_mut().ok_or_else(|| bad("BBX outside a glyph"))?;
+                    glyph.bbx = Some(parse_bbx(args).ok_or_else(|| bad("BBX"))?);
+                }
+                "BITMAP" => {
+                    if current.is_none() {
+                        return Err(bad("BITMAP outside a glyph"));
+                    }
+                    in_bitmap = true;
+                }
+                _ => {}
+            }
+        }
+        let font_bbx = font_bbx.ok_or("BDF font has no FONTBOUNDINGBOX")?;
+        let [fbb_w, fbb_h, fbb_x, fbb_y] = font_bbx;
+        let ascent = ascent.unwrap_or(fbb_h + fbb_y);
+        let descent = descent.unwrap_or(-fbb_y);
+        let height = usize::try_from(ascent + descent)
+            .ok()
+            .filter(|&h| h > 0)
+            .ok_or("BDF font has no height")?;
+        //The most common advance, so a handful of double width glyphs don't make every cell double width
+        let mut widths: HashMap<usize, usize> = HashMap::new();
+        for dwidth in raw_glyphs.iter().filter_map(|g| g.dwidth) {
+            *widths.entry(dwidth).or_default() += 1;
+        }
+        let width = widths
+            .into_iter()
+            .max_by_key(|&(w, count)| (count, w))
+            .map_or(fbb_w as usize, |(w, _)| w);
+        if width == 0 || raw_glyphs.is_empty() {
+            return Err("BDF font has no glyphs".to_string());
+        }
+        if width > MAX_GLYPH_SIZE || height > MAX_GLYPH_SIZE {
+            return Err(format!(
+                "Glyphs are {width}x{height}, bigger than {MAX_GLYPH_SIZE}x{MAX_GLYPH_SIZE}"
+            ));
+        }
+        let row_len = width.div_ceil(8);
+        let glyph_len = row_len * height;
+        //Glyphs are placed relative to the origin, which sits on the baseline, as far right as the leftmost glyph needs
+        let origin_x = (-fbb_x).max(0);
+        let mut glyphs = vec![0u8; glyph_len * raw_glyphs.len()];
+        let mut chars = HashMap::new();
+        for (index, raw) in raw_glyphs.iter().enumerate() {
+            if let Some(c) = raw.encoding {
+                chars.entry(c).or_insert(index);
+            }
+            let [w, h, x_off, y_off] = raw.bbx.unwrap_or(font_bbx);
+            let cell = &mut glyphs[index * glyph_len..(index + 1) * glyph_len];
+            let top = ascent - (y_off + h);
+            let left = origin_x + x_off;
+            for (src_y, row) in raw.rows.iter().take(h.max(0) as usize).enumerate() {
+                let y = top + src_y as i32;
+                if !(0..height as i32).contains(&y) {
+                    continue;
+                }
+                for src_x in 0..w.max(0) as usize {
+                    let set = row
+                        .get(src_x / 8)
+                        .is_some_and(|byte| byte & (0x80 >> (src_x % 8)) != 0);
+                    let x = left + src_x as i32;
+                    if set && (0..width as i32).contains(&x) {
+                        let (x, y) = (x as usize, y as usize);
+                        cell[y * row_len + x / 8] |= 0x80 >> (x % 8);
+                    }
+                }
+            }
+        }
+        Ok(BdfFont {
+            width,
+            height,
+            glyph_len,
+            glyphs,
+            chars,
+        })
+    }
+}
+
+fn parse_ints<const N: usize>(args: &str) -> Option<[i32; N]> {
+    let mut out = [0; N];
+    let mut parts = args.split_whitespace();
+    for slot in out.iter_mut() {
+        *slot = parts.next()?.parse().ok()?;
+    }
+    Some(out)
+}
+
+/// A bounding box, as long as it's no bigger than [`MAX_GLYPH_SIZE`] and no further than that from the origin,
+/// so placing its pixels can neither overflow nor take forever.
+fn parse_bbx(args: &str) -> Option<[i32; 4]> {
+    let bbx @ [w, h, x, y] = parse_ints(args)?;
+    let limit = MAX_GLYPH_SIZE as i32;
+    let size = 0..=limit;
+    let offset = -limit..=limit;
+    (size.contains(&w) && size.contains(&h) && offset.contains(&x) && offset.contains(&y))
+        .then_some(bbx)
+}
+
+/// An ascent or descent, held to the same limit.
+fn parse_metric(args: &str) -> Option<i32> {
+    let limit = MAX_GLYPH_SIZE as i32;
+    args.trim()
+        .parse()
+        .ok()
+        .filter(|metric| (-limit..=limit).contains(metric))
+}
+
+fn parse_hex_row(line: &str) -> Option<Vec<u8>> {
+    if !line.len().is_multiple_of(2) || !line.is_ascii() {
+        return None;
+    }
+    (0..line.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&line[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl Font for BdfFont {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+    fn glyph_count(&self) -> usize {
+        self.glyphs.len() / self.glyph_len
+    }
+    fn glyph_index(&self, c: char) -> Option<usize> {
+        self.chars.get(&c).copied()
+    }
+    fn glyph(&self, index: usize) -> Option<Glyph<'_>> {
+        self.glyphs
+            .get(index * self.glyph_len..(index + 1) * self.glyph_len)
+            .map(Glyph::Bitmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two glyphs in an 8 by 4 cell: 'A' a 4 by 2 block, sitting on the baseline one pixel in,
+    /// and 'B' a single pixel in the descent, with no BBX of its own so it gets the font's.
+    const FONT: &str = "STARTFONT 2.1
+FONTBOUNDINGBOX 8 4 0 -1
+FONT_ASCENT 3
+FONT_DESCENT 1
+CHARS 2
+STARTCHAR A
+ENCODING 65
+DWIDTH 8 0
+BBX 4 2 1 0
+BITMAP
+F0
+F0
+ENDCHAR
+STARTCHAR B
+ENCODING 66
+DWIDTH 8 0
+BITMAP
+00
+00
+00
+80
+ENDCHAR
+ENDFONT
+";
+
+    fn rows(font: &BdfFont, c: char) -> Vec<u8> {
+        match font.glyph(font.glyph_index(c).unwrap()) {
+            Some(Glyph::Bitmap(rows)) => rows.to_vec(),
+            _ => panic!("No bitmap for {c}"),
+        }
+    }
+
+    #[test]
+    fn glyphs_are_placed_in_the_cell() {
+        let font = BdfFont::parse(FONT).unwrap();
+        assert_eq!(font.dimensions(), (8, 4));
+        assert_eq!(font.glyph_count(), 2);
+        assert_eq!(rows(&font, 'A'), [0x00, 0x78, 0x78, 0x00]);
+        assert_eq!(rows(&font, 'B'), [0x00, 0x00, 0x00, 0x80]);
+        assert_eq!(font.glyph_index('C'), None);
+    }
+
+    #[test]
+    fn truncated_or_malformed_fonts_are_refused() {
+        assert!(BdfFont::parse("").is_err());
+        assert!(BdfFont::parse("STARTFONT 2.1\n").is_err());
+        assert!(BdfFont::parse(&FONT.replace("FONTBOUNDINGBOX 8 4 0 -1\n", "")).is_err());
+        assert!(BdfFont::parse(&FONT.replace("F0\nF0", "F0\nFG")).is_err());
+        assert!(BdfFont::parse(&FONT.replace("BBX 4 2 1 0", "BBX 4 2")).is_err());
+        //Cut off in the middle of a glyph, so there's nothing left to draw
+        let cut = &FONT[..FONT.find("STARTCHAR A").unwrap() + 20];
+        assert!(BdfFont::parse(cut).is_err());
+    }
+
+    #[test]
+    fn huge_sizes_are_refused() {
+        let huge_bbx = FONT.replace("BBX 4 2 1 0", "BBX 2000000000 2 1 0");
+        assert!(BdfFont::parse(&huge_bbx).is_err());
+        let far_bbx = FONT.replace("BBX 4 2 1 0", "BBX 4 2 -2147483648 0");
+        assert!(BdfFont::parse(&far_bbx).is_err());
+        let huge_font_bbx = FONT.replace("FONTBOUNDINGBOX 8 4 0 -1", "FONTBOUNDINGBOX 8 4000 0 -1");
+        assert!(BdfFont::parse(&huge_font_bbx).is_err());
+        let huge_ascent = FONT.replace("FONT_ASCENT 3", "FONT_ASCENT 2147483647");
+        assert!(BdfFont::parse(&huge_ascent).is_err());
+        let huge_advance = FONT.replace("DWIDTH 8 0", "DWIDTH 100000 0");
+        assert!(BdfFont::parse(&huge_advance).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use std::time::{Duration, Instant};
+
+use crate::{callbacks::TermCallbacks, depth::parse_hex};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BellStyle {
+    /// Don't show anything, just count.
+    Off,
+    /// Flash the whole terminal grid in reverse video.
+    Screen,
+    /// Flash the area around the terminal grid.
+    Border,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BellConfig {
+    pub style: BellStyle,
+    /// How long a single flash stays up for.
+    pub flash_duration: Duration,
+    /// Bells that arrive within this long of the last one we showed are swallowed, so `yes $'\a'` can't strobe the screen.
+    pub min_interval: Duration,
+    /// 0xRRGGBB, only used by [`BellStyle::Border`].
+    pub border_color: u32,
+    /// Remember bells that ring while the terminal is in the background, and flash for them when it's back.
+    pub notify_in_background: bool,
+}
+
+impl Default for BellConfig {
+    fn default() -> Self {
+        BellConfig {
+            style: BellStyle::Screen,
+            flash_duration: Duration::from_millis(100),
+            min_interval: Duration::from_millis(500),
+            border_color: 0xFF8000,
+            notify_in_background: true,
+        }
+    }
+}
+
+/// A change to the bell, from port 9039.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BellCommand {
+    /// With a new border colour, if one was given.
+    Style(BellStyle, Option<u32>),
+    FlashDuration(Duration),
+    MinInterval(Duration),
+    NotifyInBackground(bool),
+    /// Whether anyone's looking at the terminal, as far as the other end knows.
+    Focused(bool),
+}
+
+impl BellCommand {
+    /// `screen`, `border`, `border #ff8000`, `off`, `flash 100` and `interval 500` in milliseconds, `notify on`, `notify off`, `background` or `foreground`.
+    pub fn parse(text: &str) -> Option<BellCommand> {
+        let mut words = text.split_whitespace();
+        let millis = |word: Option<&str>| word?.parse().ok().map(Duration::from_millis);
+        let command = match words.next()? {
+            "screen" => BellCommand::Style(BellStyle::Screen, None),
+            "off" => BellCommand::Style(BellStyle::Off, None),
+            "border" => match words.next() {
+                None => BellCommand::Style(BellStyle::Border, None),
+                Some(color) => BellCommand::Style(BellStyle::Border, Some(parse_hex(color)?)),
+            },
+            "flash" => BellCommand::FlashDuration(millis(words.next())?),
+            "interval" => BellCommand::MinInterval(millis(words.next())?),
+            "notify" => BellCommand::NotifyInBackground(match words.next()? {
+                "on" => true,
+                "off" => false,
+                _ => return None,
+            }),
+            "background" => BellCommand::Focused(false),
+            "foreground" => BellCommand::Focused(true),
+            _ => return None,
+        };
+        words.next().is_none().then_some(command)
+    }
+}
+
+/// Watches the bell counts in [`TermCallbacks`] and decides when to flash.
+pub struct Bell {
+    pub config: BellConfig,
+    seen_audible: usize,
+    seen_visual: usize,
+    last_flash: Option<Instant>,
+    /// Whether anyone's looking at the terminal, see [`BellCommand::Focused`].
+    focused: bool,
+    /// Set when a bell rang in the background, until the terminal's back in the foreground.
+    pub pending_notification: bool,
+}
+
+impl Bell {
+    pub fn new(config: BellConfig) -> Bell {
+        Bell {
+            config,
+            seen_audible: 0,
+            seen_visual: 0,
+            last_flash: None,
+            focused: true,
+            pending_notification: false,
+        }
+    }
+    /// Call after every write to the parser. Returns true if a flash was started.
+    pub fn update(&mut self, callbacks: &TermCallbacks, now: Instant) -> bool {
+        let rang = callbacks.audible_bell_count != self.seen_audible
+            || callbacks.visual_bell_count != self.seen_visual;
+        self.seen_audible = callbacks.audible_bell_count;
+        self.seen_visual = callbacks.visual_bell_count;
+        if !rang {
+            return false;
+        }
+        if !self.focused {
+            self.pending_notification |= self.config.notify_in_background;
+            return false;
+        }
+        if self
+            .last_flash
+            .is_some_and(|last| now.saturating_duration_since(last) < self.config.min_interval)
+        {
+            return false;
+        }
+        self.flash(now)
+    }
+    fn flash(&mut self, now: Instant) -> bool {
+        if self.config.style == BellStyle::Off {
+            return false;
+        }
+        self.last_flash = Some(now);
+        true
+    }
+    /// Coming back to the foreground flashes for any bell that rang while it was away.
+    pub fn set_focused(&mut self, focused: bool, now: Instant) {
+        if focused && !self.focused && std::mem::take(&mut self.pending_notification) {
+            self.flash(now);
+        }
+        self.focused = focused;
+    }
+    pub fn apply(&mut self, command: BellCommand, now: Instant) {
+        match command {
+            BellCommand::Style(style, color) => {
+                self.config.style = style;
+                if let Some(color) = color {
+                    self.config.border_color = color;
+                }
+            }
+            BellCommand::FlashDuration(duration) => self.config.flash_duration = duration,
+            BellCommand::MinInterval(interval) => self.config.min_interval = interval,
+            BellCommand::NotifyInBackground(notify) => {
+                self.config.notify_in_background = notify;
+                self.pending_notification &= notify;
+            }
+            BellCommand::Focused(focused) => self.set_focused(focused, now),
+        }
+    }
+    pub fn flashing(&self, now: Instant) -> bool {
+        self.last_flash
+            .is_some_and(|last| now.saturating_duration_since(last) < self.config.flash_duration)
+    }
+    pub fn screen_flash(&self, now: Instant) -> bool {
+        self.config.style == BellStyle::Screen && self.flashing(now)
+    }
+    /// The colour to fill around the grid with, if the border should be flashing right now.
+    pub fn border_flash(&self, now: Instant) -> Option<u32> {
+        (self.config.style == BellStyle::Border && self.flashing(now))
+            .then_some(self.config.border_color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(callbacks: &mut TermCallbacks) {
+        callbacks.audible_bell_count += 1;
+    }
+
+    fn millis(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn bells_close_together_only_flash_once() {
+        let mut bell = Bell::new(BellConfig::default());
+        let mut callbacks = TermCallbacks::default();
+        let start = Instant::now();
+        assert!(!bell.update(&callbacks, start));
+        ring(&mut callbacks);
+        assert!(bell.update(&callbacks, start));
+        //Nothing new, then a bell inside the interval, then one after it
+        assert!(!bell.update(&callbacks, start + millis(10)));
+        ring(&mut callbacks);
+        assert!(!bell.update(&callbacks, start + millis(499)));
+        ring(&mut callbacks);
+        callbacks.visual_bell_count += 1;
+        assert!(bell.update(&callbacks, start + millis(500)));
+        //Swallowed bells don't push the next flash back
+        ring(&mut callbacks);
+        assert!(!bell.update(&callbacks, start + millis(700)));
+        ring(&mut callbacks);
+        assert!(bell.update(&callbacks, start + millis(1000)));
+    }
+
+    #[test]
+    fn flashes_last_as_long_as_configured() {
+        let mut bell = Bell::new(BellConfig::default());
+        let mut callbacks = TermCallbacks::default();
+        let start = Instant::now();
+        assert!(!bell.flashing(start));
+        ring(&mut callbacks);
+        bell.update(&callbacks, start);
+        assert!(bell.screen_flash(start));
+        assert!(bell.screen_flash(start + millis(99)));
+        assert!(!bell.screen_flash(start + millis(100)));
+        assert_eq!(bell.border_flash(start), None);
+        bell.apply(BellCommand::Style(BellStyle::Border, Some(0x00FF00)), start);
+        bell.apply(BellCommand::FlashDuration(millis(300)), start);
+        assert!(!bell.screen_flash(start));
+        assert_eq!(bell.border_flash(start + millis(299)), Some(0x00FF00));
+        assert_eq!(bell.border_flash(start + millis(300)), None);
+    }
+
+    #[test]
+    fn off_never_flashes() {
+        let mut bell = Bell::new(BellConfig::default());
+        bell.apply(BellCommand::Style(BellStyle::Off, None), Instant::now());
+        let mut callbacks = TermCallbacks::default();
+        let start = Instant::now();
+        ring(&mut callbacks);
+        assert!(!bell.update(&callbacks, start));
+        assert!(!bell.flashing(start));
+        //Turning it back on doesn't flash for bells it already saw
+        bell.apply(BellCommand::Style(BellStyle::Screen, None), start);
+        assert!(!bell.update(&callbacks, start + millis(1)));
+    }
+
+    #[test]
+    fn bells_in_the_background_flash_on_coming_back() {
+        let mut bell = Bell::new(BellConfig::default());
+        let mut callbacks = TermCallbacks::default();
+        let start = Instant::now();
+        bell.apply(BellCommand::Focused(false), start);
+        ring(&mut callbacks);
+        assert!(!bell.update(&callbacks, start));
+        assert!(bell.pending_notification);
+        assert!(!bell.flashing(start));
+        let back = start + millis(5000);
+        bell.apply(BellCommand::Focused(true), back);
+        assert!(!bell.pending_notification);
+        assert!(bell.screen_flash(back));
+        //Only once
+        bell.apply(BellCommand::Focused(true), back + millis(1000));
+        assert!(!bell.flashing(back + millis(1000)));
+        //Unless it's been told not to
+        bell.apply(BellCommand::NotifyInBackground(false), back);
+        bell.apply(BellCommand::Focused(false), back);
+        ring(&mut callbacks);
+        bell.update(&callbacks, back + millis(2000));
+        assert!(!bell.pending_notification);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            BellCommand::parse("border #00ff00\n"),
+            Some(BellCommand::Style(BellStyle::Border, Some(0x00FF00)))
+        );
+        assert_eq!(
+            BellCommand::parse("border"),
+            Some(BellCommand::Style(BellStyle::Border, None))
+        );
+        assert_eq!(
+            BellCommand::parse("off"),
+            Some(BellCommand::Style(BellStyle::Off, None))
+        );
+        assert_eq!(
+            BellCommand::parse("flash 250"),
+            Some(BellCommand::FlashDuration(millis(250)))
+        );
+        assert_eq!(
+            BellCommand::parse("interval 0"),
+            Some(BellCommand::MinInterval(millis(0)))
+        );
+        assert_eq!(
+            BellCommand::parse("notify off"),
+            Some(BellCommand::NotifyInBackground(false))
+        );
+        assert_eq!(
+            BellCommand::parse("background"),
+            Some(BellCommand::Focused(false))
+        );
+        for bad in [
+            "",
+            "loud",
+            "border red",
+            "flash",
+            "flash -1",
+            "notify maybe",
+            "screen now",
+        ] {
+            assert_eq!(BellCommand::parse(bad), None, "{bad:?}");
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use std::ops::Range;
+
+use Weight::{Double as D, Heavy as H, Light as L, None as N};
+
+/// How thick a line is. Heavy is twice as thick as light, double is two light lines with a light line's gap between.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Weight {
+    None,
+    Light,
+    Heavy,
+    Double,
+}
+
+/// The left, right, up and down arms of every plain line character from U+2500 to U+257F.
+/// Dashes, arcs and diagonals are drawn separately and have no arms here.
+#[rustfmt::skip]
+const LINES: [[Weight; 4]; 128] = [
+    [L, L, N, N], [H, H, N, N], [N, N, L, L], [N, N, H, H], // ─━│┃
+    [N, N, N, N], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ┄┅┆┇
+    [N, N, N, N], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ┈┉┊┋
+    [N, L, N, L], [N, H, N, L], [N, L, N, H], [N, H, N, H], // ┌┍┎┏
+    [L, N, N, L], [H, N, N, L], [L, N, N, H], [H, N, N, H], // ┐┑┒┓
+    [N, L, L, N], [N, H, L, N], [N, L, H, N], [N, H, H, N], // └┕┖┗
+    [L, N, L, N], [H, N, L, N], [L, N, H, N], [H, N, H, N], // ┘┙┚┛
+    [N, L, L, L], [N, H, L, L], [N, L, H, L], [N, L, L, H], // ├┝┞┟
+    [N, L, H, H], [N, H, H, L], [N, H, L, H], [N, H, H, H], // ┠┡┢┣
+    [L, N, L, L], [H, N, L, L], [L, N, H, L], [L, N, L, H], // ┤┥┦┧
+    [L, N, H, H], [H, N, H, L], [H, N, L, H], [H, N, H, H], // ┨┩┪┫
+    [L, L, N, L], [H, L, N, L], [L, H, N, L], [H, H, N, L], // ┬┭┮┯
+    [L, L, N, H], [H, L, N, H], [L, H, N, H], [H, H, N, H], // ┰┱┲┳
+    [L, L, L, N], [H, L, L, N], [L, H, L, N], [H, H, L, N], // ┴┵┶┷
+    [L, L, H, N], [H, L, H, N], [L, H, H, N], [H, H, H, N], // ┸┹┺┻
+    [L, L, L, L], [H, L, L, L], [L, H, L, L], [H, H, L, L], // ┼┽┾┿
+    [L, L, H, L], [L, L, L, H], [L, L, H, H], [H, L, H, L], // ╀╁╂╃
+    [L, H, H, L], [H, L, L, H], [L, H, L, H], [H, H, H, L], // ╄╅╆╇
+    [H, H, L, H], [H, L, H, H], [L, H, H, H], [H, H, H, H], // ╈╉╊╋
+    [N, N, N, N], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ╌╍╎╏
+    [D, D, N, N], [N, N, D, D], [N, D, N, L], [N, L, N, D], // ═║╒╓
+    [N, D, N, D], [D, N, N, L], [L, N, N, D], [D, N, N, D], // ╔╕╖╗
+    [N, D, L, N], [N, L, D, N], [N, D, D, N], [D, N, L, N], // ╘╙╚╛
+    [L, N, D, N], [D, N, D, N], [N, D, L, L], [N, L, D, D], // ╜╝╞╟
+    [N, D, D, D], [D, N, L, L], [L, N, D, D], [D, N, D, D], // ╠╡╢╣
+    [D, D, N, L], [L, L, N, D], [D, D, N, D], [D, D, L, N], // ╤╥╦╧
+    [L, L, D, N], [D, D, D, N], [D, D, L, L], [L, L, D, D], // ╨╩╪╫
+    [D, D, D, D], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ╬╭╮╯
+    [N, N, N, N], [N, N, N, N], [N, N, N, N], [N, N, N, N], // ╰╱╲╳
+    [L, N, N, N], [N, N, L, N], [N, L, N, N], [N, N, N, L], // ╴╵╶╷
+    [H, N, N, N], [N, N, H, N], [N, H, N, N], [N, N, N, H], // ╸╹╺╻
+    [L, H, N, N], [N, N, L, H], [H, L, N, N], [N, N, H, L], // ╼╽╾╿
+];
+
+/// How many characters [`covers`]: 128 box drawing, 32 block elements and 256 braille.
+pub const COVERED: usize = 128 + 32 + 256;
+
+/// Whether `c` is drawn by [`draw`] instead of coming from the font: box drawing, block elements and braille.
+pub fn covers(c: char) -> bool {
+    matches!(c, '\u{2500}'..='\u{259F}' | '\u{2800}'..='\u{28FF}')
+}
+
+/// `c` drawn to fill a `width` by `height` cell exactly, as one byte of coverage per pixel, or `None` if [`covers`] doesn't.
+///
+/// Lines always run through the same pixels whatever character they're in, so they join up with the cells around them.
+pub fn draw(c: char, width: usize, height: usize) -> Option<Vec<u8>> {
+    let mut canvas = Canvas::new(width, height);
+    let weight = |heavy: bool| if heavy { Weight::Heavy } else { Weight::Light };
+    match c {
+        '\u{2504}'..='\u{250B}' => {
+            let i = c as usize - 0x2504;
+            canvas.dashes(weight(i % 2 == 1), (i / 2) % 2 == 1, 3 + i / 4);
+        }
+        '\u{254C}'..='\u{254F}' => {
+            let i = c as usize - 0x254C;
+            canvas.dashes(weight(i % 2 == 1), i / 2 == 1, 2);
+        }
+        '\u{256D}' => canvas.arc(1.0, 1.0),
+        '\u{256E}' => canvas.arc(-1.0, 1.0),
+        '\u{256F}' => canvas.arc(-1.0, -1.0),
+        '\u{2570}' => canvas.arc(1.0, -1.0),
+        '\u{2571}' => canvas.diagonal(true),
+        '\u{2572}' => canvas.diagonal(false),
+        '\u{2573}' => {
+            canvas.diagonal(true);
+            canvas.diagonal(false);
+        }
+        '\u{2500}'..='\u{257F}' => canvas.lines(LINES[c as usize - 0x2500]),
+        '\u{2580}'..='\u{259F}' => canvas.block(c),
+        '\u{2800}'..='\u{28FF}' => canvas.braille((c as u32 - 0x2800) as u8),
+        _ => return None,
+    }
+    Some(canvas.pixels)
+}
+
+/// `thickness` pixels in the middle of `size`, rounding up and left when it can't be exactly in the middle.
+fn band(size: usize, thickness: usize) -> Range<usize> {
+    let start = (size - thickness.min(size)) / 2;
+    start..start + thickness
+}
+
+/// A cell's worth of coverage to draw shapes into.
+pub struct Canvas {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+    /// Thickness of a light line, in pixels.
+    pub light: usize,
+}
+
+impl Canvas {
+    pub fn new(width: usize, height: usize) -> Canvas {
+        Canvas {
+            width,
+            height,
+            pixels: vec![0; width * height],
+            light: (width.min(height) / 8).max(1),
+        }
+    }
+    fn thickness(&self, weight: Weight) -> usize {
+        match weight {
+            Weight::None => 0,
+            Weight::Light => self.light,
+            Weight::Heavy => self.light * 2,
+            Weight::Double => self.light * 3,
+        }
+    }
+    /// Sets every pixel in the rectangle, clipped to the cell.
+    pub fn rect(&mut self, x: Range<usize>, y: Range<usize>, value: u8) {
+        let x = x.start.min(self.width)..x.end.min(self.width);
+        for y in y.start..y.end.min(self.height) {
+            let row = y * self.width;
+            if x.start < x.end {
+                self.pixels[row + x.start..row + x.end].fill(value);
+            }
+        }
+    }
+    /// Adds some coverage to a pixel, for anti-aliased shapes that might overlap.
+    pub fn plot(&mut self, x: usize, y: usize, coverage: f32) {
+        let pixel = &mut self.pixels[y * self.width + x];
+        *pixel = (*pixel).max((coverage.clamp(0.0, 1.0) * 255.0).round() as u8);
+    }
+    /// Arms out from the middle of the cell to the middle of each edge.
+    fn lines(&mut self, [left, right, up, down]: [Weight; 4]) {
+        let (width, height) = (self.width, self.height);
+        let across = self.thickness(left).max(self.thickness(right));
+        let along = self.thickness(up).max(self.thickness(down));
+        //Arms reach right through the line crossing them, so corners come out square
+        let x_middle = band(width, if along == 0 { across } else { along });
+        let y_middle = band(height, if across == 0 { along } else { across });
+        let x_gap = band(width, self.light);
+        let y_gap = band(height, self.light);
+        let double_across = left == Weight::Double || right == Weight::Double;
+        let double_along = up == Weight::Double || down == Weight::Double;
+        //Where each arm goes, and where its gap goes if it's double. Meeting another double line, the gap stops at that line's gap,
+        //so the inner lines turn the corner instead of crossing
+        let horizontal = [
+            (left, 0..x_middle.end, 0..x_gap.end),
+            (right, x_middle.start..width, x_gap.start..width),
+        ];
+        let vertical = [
+            (up, 0..y_middle.end, 0..y_gap.end),
+            (down, y_middle.start..height, y_gap.start..height),
+        ];
+        //Double lines all go down before any of their gaps are cut, and single lines go on top, so they aren't cut by the gaps
+        for (weight, span, _) in horizontal.iter().filter(|arm| arm.0 == Weight::Double) {
+            self.rect(span.clone(), band(height, self.thickness(*weight)), 0xFF);
+        }
+        for (weight, span, _) in vertical.iter().filter(|arm| arm.0 == Weight::Double) {
+            self.rect(band(width, self.thickness(*weight)), span.clone(), 0xFF);
+        }
+        for (_, span, gap_span) in horizontal.iter().filter(|arm| arm.0 == Weight::Double) {
+            let span = if double_along { gap_span } else { span };
+            self.rect(span.clone(), y_gap.clone(), 0);
+        }
+        for (_, span, gap_span) in vertical.iter().filter(|arm| arm.0 == Weight::Double) {
+            let span = if double_across { gap_span } else { span };
+            self.rect(x_gap.clone(), span.clone(), 0);
+        }
+        for (weight, span, _) in horizontal.iter().filter(|arm| arm.0 != Weight::Double) {
+            self.rect(span.clone(), band(height, self.thickness(*weight)), 0xFF);
+        }
+        for (weight, span, _) in vertical.iter().filter(|arm| arm.0 != Weight::Double) {
+            self.rect(band(width, self.thickness(*weight)), span.clone(), 0xFF);
+        }
+    }
+    /// A line broken into `count` dashes, one per equal share of the cell so the gaps stay even across cells.
+    fn dashes(&mut self, weight: Weight, vertical: bool, count: usize) {
+        let (length, breadth) = if vertical {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        };
+        let across = band(breadth, self.thickness(weight));
+        for i in 0..count {
+            let (start, end) = (i * length / count, (i + 1) * length / count);
+            //Too short for a dash and a gap
+            if end - start < 2 {
+                continue;
+            }
+            let gap = ((end - start) / 3).max(1);
+            let dash = start + gap / 2..end - (gap - gap / 2);
+            if vertical {
+                self.rect(across.clone(), dash, 0xFF);
+            } else {
+                self.rect(dash, across.clone(), 0xFF);
+            }
+        }
+    }
+    /// A rounded corner joining the middle of the left (`dx` -1) or right (1) edge to the middle of the top (`dy` -1) or bottom (1) one.
+    fn arc(&mut self, dx: f32, dy: f32) {
+        let (width, height) = (self.width, self.height);
+        let thickness = self.light as f32;
+        let x_band = band(width, self.light);
+        let y_band = band(height, self.light);
+        //The middles of the straight lines it joins up with
+        let x_line = x_band.start as f32 + thickness / 2.0;
+        let y_line = y_band.start as f32 + thickness / 2.0;
+        let x_room = if dx > 0.0 {
+            width as f32 - x_line
+        } else {
+            x_line
+        };
+        let y_room = if dy > 0.0 {
+            height as f32 - y_line
+        } else {
+            y_line
+        };
+        let radius = x_room.min(y_room);
+        let (cx, cy) = (x_line + dx * radius, y_line + dy * radius);
+        for y in 0..height {
+            for x in 0..width {
+                let (px, py) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+                //Only the quarter facing the corner
+                if px * dx <= 0.0 && py * dy <= 0.0 {
+                    let distance = (px * px + py * py).sqrt();
+                    self.plot(x, y, thickness / 2.0 + 0.5 - (distance - radius).abs());
+                }
+            }
+        }
+        //Whichever way the cell is longer, a straight bit carries on to the edge
+        let x_straight = if dx > 0.0 {
+            cx as usize..width
+        } else {
+            0..cx.ceil() as usize
+        };
+        let y_straight = if dy > 0.0 {
+            cy as usize..height
+        } else {
+            0..cy.ceil() as usize
+        };
+        self.rect(x_straight, y_band.clone(), 0xFF);
+        self.rect(x_band, y_straight, 0xFF);
+    }
+    /// Corner to corner, rising from bottom left to top right or falling from top left to bottom right.
+    fn diagonal(&mut self, rising: bool) {
+        let (width, height) = (self.width as f32, self.height as f32);
+        let length = width.hypot(height);
+        let thickness = self.light as f32;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                let distance = if rising {
+                    (px * height + py * width - width * height).abs() / length
+                } else {
+                    (px * height - py * width).abs() / length
+                };
+                self.plot(x, y, thickness / 2.0 + 0.5 - distance);
+            }
+        }
+    }
+    /// U+2580 to U+259F. Halves and quarters split the cell in the same place, so they tile with each other.
+    fn block(&mut self, c: char) {
+        let (width, height) = (self.width, self.height);
+        let eighths_x = |n: usize| (width * n + 4) / 8;
+        let eighths_y = |n: usize| (height * n + 4) / 8;
+        let x_split = eighths_x(4);
+        let y_split = height - eighths_y(4);
+        match c {
+            '\u{2580}' => self.rect(0..width, 0..y_split, 0xFF),
+            '\u{2581}'..='\u{2588}' => {
+                let n = c as usize - 0x2580;
+                self.rect(0..width, height - eighths_y(n)..height, 0xFF);
+            }
+            '\u{2589}'..='\u{258F}' => {
+                let n = 0x2590 - c as usize;
+                self.rect(0..eighths_x(n), 0..height, 0xFF);
+            }
+            '\u{2590}' => self.rect(x_split..width, 0..height, 0xFF),
+            //Shades are see-through rather than dithered, so they're even however they're scaled
+            '\u{2591}'..='\u{2593}' => {
+                let n = c as usize - 0x2590;
+                self.pixels.fill((0x40 * n) as u8);
+            }
+            '\u{2594}' => self.rect(0..width, 0..eighths_y(1), 0xFF),
+            '\u{2595}' => self.rect(width - eighths_x(1)..width, 0..height, 0xFF),
+            '\u{2596}'..='\u{259F}' => {
+                //Upper left, upper right, lower left, lower right
+                const QUADRANTS: [u8; 10] = [4, 8, 1, 13, 9, 7, 11, 2, 6, 14];
+                let quadrants = QUADRANTS[c as usize - 0x2596];
+                let corners = [
+                    (0..x_split, 0..y_split),
+                    (x_split..width, 0..y_split),
+                    (0..x_split, y_split..height),
+                    (x_split..width, y_split..height),
+                ];
+                for (i, (x, y)) in corners.into_iter().enumerate() {
+                    if quadrants & (1 << i) != 0 {
+                        self.rect(x, y, 0xFF);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    /// A 2 by 4 grid of dots, one bit each.
+    fn braille(&mut self, dots: u8) {
+        //Dots 1 to 3 go down the left and 4 to 6 down the right, then 7 and 8 are the bottom row
+        const POSITIONS: [(usize, usize); 8] = [
+            (0, 0),
+            (0, 1),
+            (0, 2),
+            (1, 0),
+            (1, 1),
+            (1, 2),
+            (0, 3),
+            (1, 3),
+        ];
+        let (width, height) = (self.width, self.height);
+        let size = ((width / 2).min(height / 4).div_ceil(2)).max(1);
+        for (bit, (col, row)) in POSITIONS.into_iter().enumerate() {
+            if dots & (1 << bit) == 0 {
+                continue;
+            }
+            let (x0, x1) = (col * width / 2, (col + 1) * width / 2);
+            let (y0, y1) = (row * height / 4, (row + 1) * height / 4);
+            let x = x0 + (x1 - x0).saturating_sub(size) / 2;
+            let y = y0 + (y1 - y0).saturating_sub(size) / 2;
+            self.rect(x..x + size, y..y + size, 0xFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rows of `#` for full coverage, `.` for none and `+` for anything in between.
+    fn picture(pixels: &[u8], width: usize) -> Vec<String> {
+        pixels
+            .chunks_exact(width)
+            .map(|row| {
+                row.iter()
+                    .map(|&p| match p {
+                        0 => '.',
+                        0xFF => '#',
+                        _ => '+',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lines_go_through_the_middle() {
+        let horizontal = picture(&draw('─', 8, 16).unwrap(), 8);
+        for (y, row) in horizontal.iter().enumerate() {
+            assert_eq!(row, if y == 7 { "########" } else { "........" });
+        }
+        let cross = picture(&draw('┼', 8, 16).unwrap(), 8);
+        assert_eq!(cross[7], horizontal[7]);
+        assert!(cross.iter().all(|row| &row[3..4] == "#"));
+        let corner = picture(&draw('┌', 8, 16).unwrap(), 8);
+        assert_eq!(corner[0], "........");
+        assert_eq!(corner[7], "...#####");
+        assert_eq!(corner[15], "...#....");
+    }
+
+    /// Which rows have anything drawn in them.
+    fn drawn_rows(c: char, width: usize, height: usize) -> Vec<usize> {
+        let pixels = draw(c, width, height).unwrap();
+        (0..height)
+            .filter(|y| pixels[y * width..(y + 1) * width].iter().any(|&p| p != 0))
+            .collect()
+    }
+
+    #[test]
+    fn heavy_and_double_lines() {
+        assert_eq!(drawn_rows('─', 16, 32), [15, 16]);
+        assert_eq!(drawn_rows('━', 16, 32), [14, 15, 16, 17]);
+        //Two light lines with a light line's gap between
+        assert_eq!(drawn_rows('═', 16, 32), [13, 14, 17, 18]);
+        assert_eq!(drawn_rows('═', 12, 24), [10, 12]);
+    }
+
+    #[test]
+    fn blocks() {
+        assert!(draw('█', 8, 16).unwrap().iter().all(|&p| p == 0xFF));
+        let lower = picture(&draw('▄', 8, 16).unwrap(), 8);
+        assert!(lower[..8].iter().all(|row| row == "........"));
+        assert!(lower[8..].iter().all(|row| row == "########"));
+        let left = picture(&draw('▌', 8, 16).unwrap(), 8);
+        assert!(left.iter().all(|row| row == "####...."));
+    }
+
+    #[test]
+    fn braille_dots() {
+        let one = draw('⠁', 8, 16).unwrap();
+        let (top_left, rest): (Vec<_>, Vec<_>) = one
+            .chunks_exact(8)
+            .enumerate()
+            .flat_map(|(y, row)| row.iter().enumerate().map(move |(x, &p)| (x, y, p)))
+            .partition(|&(x, y, _)| x < 4 && y < 4);
+        assert!(top_left.iter().any(|&(_, _, p)| p != 0));
+        assert!(rest.iter().all(|&(_, _, p)| p == 0));
+        let all = draw('⣿', 8, 16).unwrap();
+        assert!(
+            all.iter().filter(|&&p| p != 0).count() > one.iter().filter(|&&p| p != 0).count() * 7
+        );
+    }
+
+    #[test]
+    fn dashes_leave_gaps() {
+        let triple = picture(&draw('┄', 12, 24).unwrap(), 12);
+        assert_eq!(triple[11], "###.###.###.");
+        let double = picture(&draw('╌', 12, 24).unwrap(), 12);
+        assert_eq!(double[11], ".####..####.");
+        let quadruple = picture(&draw('┊', 12, 24).unwrap(), 12);
+        let column: String = quadruple.iter().map(|row| &row[5..6]).collect();
+        assert_eq!(column, ".####..####..####..####.");
+    }
+
+    #[test]
+    fn anything_fits_in_tiny_cells() {
+        for (width, height) in [(1, 1), (1, 2), (2, 1), (3, 5), (5, 3), (4, 9)] {
+            for c in ('\u{2500}'..='\u{259F}').chain('\u{2800}'..='\u{28FF}') {
+                let pixels = draw(c, width, height).unwrap();
+                assert_eq!(pixels.len(), width * height, "{c} at {width}x{height}");
+            }
+        }
+    }
+
+    #[test]
+    fn only_box_drawing_blocks_and_braille() {
+        assert!(draw('a', 8, 16).is_none());
+        assert!(!covers('\u{24FF}'));
+        assert!(covers('\u{2580}'));
+    }
+}
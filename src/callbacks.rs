@@ -0,0 +1,144 @@
+use vt100::{Callbacks, Screen};
+
+use crate::{
+    colors::{ColorState, format_color_spec, parse_color_spec},
+    palette::Palette,
+};
+
+/// State the parser hands back to us for sequences that don't touch the grid.
+///
+/// The counts only ever go up (and wrap), compare them against the last value you saw.
+pub struct TermCallbacks {
+    pub audible_bell_count: usize,
+    pub visual_bell_count: usize,
+    /// Set by OSC 0 and OSC 2
+    pub title: String,
+    /// Set by OSC 0 and OSC 1
+    pub icon_name: String,
+    /// Bytes waiting to be sent back to the other end, answers to queries and the like.
+    pub responses: Vec<u8>,
+    /// Changed by OSC 10, 11 and 12.
+    pub colors: ColorState,
+    /// What OSC 110, 111 and 112 go back to.
+    pub default_colors: ColorState,
+    /// 0xRRGGBB, changed by OSC 4.
+    pub palette: Palette,
+    /// What OSC 104 goes back to.
+    pub default_palette: Palette,
+    /// The OSC being handled ended with BEL instead of ST, so answers to it should too.
+    ///
+    /// vt100 doesn't pass this on, whoever feeds the parser has to set it.
+    pub osc_bell: bool,
+}
+
+impl Default for TermCallbacks {
+    fn default() -> Self {
+        TermCallbacks {
+            audible_bell_count: 0,
+            visual_bell_count: 0,
+            title: String::new(),
+            icon_name: String::new(),
+            responses: Vec::new(),
+            colors: ColorState::default(),
+            default_colors: ColorState::default(),
+            palette: Palette::XTERM,
+            default_palette: Palette::XTERM,
+            osc_bell: false,
+        }
+    }
+}
+
+impl TermCallbacks {
+    /// Queues `answer` to an OSC, ended the same way the OSC was.
+    fn osc_answer(&mut self, answer: &str) {
+        self.responses.extend(answer.as_bytes());
+        let terminator: &[u8] = if self.osc_bell { b"\x07" } else { b"\x1B\\" };
+        self.responses.extend(terminator);
+    }
+}
+
+impl Callbacks for TermCallbacks {
+    fn audible_bell(&mut self, _: &mut Screen) {
+        self.audible_bell_count = self.audible_bell_count.wrapping_add(1);
+    }
+    fn visual_bell(&mut self, _: &mut Screen) {
+        self.visual_bell_count = self.visual_bell_count.wrapping_add(1);
+    }
+    fn set_window_icon_name(&mut self, _: &mut Screen, icon_name: &[u8]) {
+        self.icon_name = String::from_utf8_lossy(icon_name).into_owned();
+    }
+    fn set_window_title(&mut self, _: &mut Screen, title: &[u8]) {
+        self.title = String::from_utf8_lossy(title).into_owned();
+    }
+    fn unhandled_osc(&mut self, screen: &mut Screen, params: &[&[u8]]) {
+        match params {
+            //vt100 splits the whole OSC on `;`, so a title with a `;` in it ends up here
+            [ps @ (b"0" | b"1" | b"2"), rest @ ..] if !rest.is_empty() => {
+                let joined = rest.join(&b';');
+                if ps != b"2" {
+                    self.set_window_icon_name(screen, &joined);
+                }
+                if ps != b"1" {
+                    self.set_window_title(screen, &joined);
+                }
+            }
+            //Each extra param moves on to the next colour, so `OSC 10;?;? ST` asks for the foreground and background
+            [ps @ (b"10" | b"11" | b"12"), rest @ ..] => {
+                let first = osc_number(ps).expect("Matched a number");
+                for (ps, spec) in (first..).zip(rest) {
+                    let Some(color) = self.colors.dynamic_mut(ps) else {
+                        break;
+                    };
+                    if *spec == b"?" {
+                        let answer = format!("\x1B]{ps};{}", format_color_spec(*color));
+                        self.osc_answer(&answer);
+                    } else if let Some(new) = parse_color_spec(spec) {
+                        *color = new;
+                    }
+                }
+            }
+            [ps @ (b"110" | b"111" | b"112"), ..] => {
+                let ps = osc_number(ps).expect("Matched a number") - 100;
+                let default = self.default_colors.dynamic_mut(ps).copied();
+                if let (Some(color), Some(default)) = (self.colors.dynamic_mut(ps), default) {
+                    *color = default;
+                }
+            }
+            //Pairs of index and colour, any number of them
+            [b"4", rest @ ..] => {
+                for pair in rest.chunks_exact(2) {
+                    let Some(index) = palette_index(pair[0]) else {
+                        continue;
+                    };
+                    if pair[1] == b"?" {
+                        let color = format_color_spec(self.palette.get(index));
+                        self.osc_answer(&format!("\x1B]4;{index};{color}"));
+                    } else if let Some(new) = parse_color_spec(pair[1]) {
+                        self.palette.set(index, new);
+                    }
+                }
+            }
+            //No indices means all of them
+            [b"104", rest @ ..] => {
+                let rest: Vec<&[u8]> = rest.iter().copied().filter(|i| !i.is_empty()).collect();
+                if rest.is_empty() {
+                    self.palette = self.default_palette;
+                }
+                for index in rest {
+                    if let Some(index) = palette_index(index) {
+                        self.palette.set(index, self.default_palette.get(index));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn osc_number(ps: &[u8]) -> Option<u32> {
+    std::str::from_utf8(ps).ok()?.parse().ok()
+}
+
+fn palette_index(param: &[u8]) -> Option<u8> {
+    osc_number(param)?.try_into().ok()
+}
@@ -12,7 +12,12 @@ use vita_gl_helpers::{
     uniform_table,
 };
 
-use crate::font_rasterizer::{RasterizedFont, rasterize_font};
+use crate::{
+    depth::ColorDepth,
+    font::ATLAS_PADDING,
+    font_rasterizer::{RasterizedFont, rasterize_font},
+    palette::{Palette, rgb_to_gpu},
+};
 
 pub const QUAD_INDICES: &[u16] = &[0, 1, 3, 2];
 
@@ -29,6 +34,9 @@ attribute_table!(TtyAttributeTable,
 
 uniform_table!(TtyUniformTable,
     char_dim: Uniform2fv => "charDim",
+    slot_dim: Uniform2fv => "slotDim",
+    padding_dim: Uniform2fv => "paddingDim",
+    atlas_columns: Uniform1fv => "atlasColumns",
     // transform: UniformMatrix3x2fv => "transform",
     the_texture: Uniform1iv => "the_texture"
 );
@@ -42,10 +50,33 @@ pub enum TermColor {
 impl TermColor {
     pub const BLACK: TermColor = TermColor::Pal(0);
     pub const WHITE: TermColor = TermColor::Pal(15);
-    pub fn select(self, pal: &[u32; 256]) -> u32 {
+    /// The bottom 24 bits in the order the shader wants, the top byte is left free for the glyph.
+    pub fn select(self, pal: &Palette) -> u32 {
         match self {
-            TermColor::Pal(i) => pal[i as usize],
-            TermColor::True(c) => c & 0xFFFFFF,
+            TermColor::Pal(i) => pal.gpu(i) & 0xFFFFFF,
+            TermColor::True(c) => rgb_to_gpu(c) & 0xFFFFFF,
+        }
+    }
+    /// The nearest colour `depth` allows, keeping palette colours as palette indices where it can.
+    pub fn quantize(self, pal: &Palette, depth: ColorDepth) -> TermColor {
+        match (self, depth) {
+            (_, ColorDepth::TrueColor) | (TermColor::Pal(_), ColorDepth::Colors256) => self,
+            (TermColor::Pal(i), ColorDepth::Colors16) if i < 16 => self,
+            (TermColor::Pal(i), ColorDepth::Colors16) => {
+                TermColor::Pal(pal.nearest_perceptual_in(pal.get(i), 0..=15))
+            }
+            (TermColor::True(c), ColorDepth::Colors256) => {
+                TermColor::Pal(pal.nearest_perceptual_in(c, 0..=255))
+            }
+            (TermColor::True(c), ColorDepth::Colors16) => {
+                TermColor::Pal(pal.nearest_perceptual_in(c, 0..=15))
+            }
+            (TermColor::Pal(i), ColorDepth::Mono { .. }) => {
+                TermColor::True(depth.quantize_index(i, pal))
+            }
+            (TermColor::True(c), ColorDepth::Mono { .. }) => {
+                TermColor::True(depth.quantize(c, pal))
+            }
         }
     }
 }
@@ -74,7 +105,9 @@ pub struct CharMap {
     /// Program used to draw with
     pub program: Program,
     pub rasterized_font: RasterizedFont,
-    pub pal_256: Box<[u32; 256]>,
+    pub pal_256: Palette,
+    /// Colours given to `put_char_*` get snapped to this.
+    pub depth: ColorDepth,
     pub transform: [glam::Vec3; 2],
     // pub u_big_ass_uniform_location: i32,
     // pub u_other_big_ass_uniform_location: i32,
@@ -108,7 +141,8 @@ impl CharMap {
             .font
             .get_glyph_index(' ')
             .expect("The font didn't have space, WTF?");
-        let space_lower_fill = (((space_index & 0xFF) << 24) as u32) | self.pal_256[0];
+        let space_lower_fill =
+            (((space_index & 0xFF) << 24) as u32) | TermColor::BLACK.select(&self.pal_256);
         let space_upper = (space_index >> 8) as u8; //Usually 0, but who knows, maybe someone's going to pass a really messed up PSF into this function.
         (space_lower_fill, space_upper)
     }
@@ -117,7 +151,7 @@ impl CharMap {
         screen_width: usize,
         screen_height: usize,
         // pal_16: Box<[u32; 16]>,
-        pal_256: Box<[u32; 256]>,
+        pal_256: Palette,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let rasterized_font = rasterize_font(&font);
         let mut this = CharMap {
@@ -134,6 +168,7 @@ impl CharMap {
             screen_bg: Vec::new(),
             screen_upper: Vec::new(),
             default_bg: TermColor::Pal(0),
+            depth: ColorDepth::TrueColor,
             vbos: [Default::default(); 2],
             vertex_shader: Shader::from(0),
             fragment_shader: load_shader(include_str!("tty.frag"), gl::FRAGMENT_SHADER)?,
@@ -156,7 +191,7 @@ impl CharMap {
         term_height: usize,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let (fill_lower, fill_upper) = self.get_space();
-        let fill_bg = self.pal_256[0];
+        let fill_bg = TermColor::BLACK.select(&self.pal_256);
         match term_width.cmp(&self.screen_width) {
             Ordering::Less => {
                 //We need to truncate each row, meaning we should make new vecs
@@ -198,7 +233,7 @@ impl CharMap {
             gl::Enable(gl::TEXTURE_2D);
             gl::ActiveTexture(gl::TEXTURE0);
         }
-        self.rasterized_font.textures[0].bind(gl::TEXTURE_2D);
+        self.rasterized_font.texture.bind(gl::TEXTURE_2D);
         let chrcount = (self.screen_width * self.screen_height) as i32;
         self.program.use_me();
         self.attributes.enable_all();
@@ -215,6 +250,16 @@ impl CharMap {
         self.uniforms
             .char_dim
             .set(bytemuck::cast(self.rasterized_font.char_dim));
+        //Glyphs were drawn into the atlas in order, so glyph n is in slot n
+        let layout = self.rasterized_font.cache.layout();
+        let texel_dim = self.rasterized_font.texel_dim;
+        self.uniforms.slot_dim.set(bytemuck::cast(
+            glam::vec2(layout.slot_width as f32, layout.slot_height as f32) * texel_dim,
+        ));
+        self.uniforms
+            .padding_dim
+            .set(bytemuck::cast(texel_dim * ATLAS_PADDING as f32));
+        self.uniforms.atlas_columns.set(layout.columns as f32);
         self.uniforms.the_texture.set(0);
         ElementsU16 {
             indices: QUAD_INDICES,
@@ -250,20 +295,21 @@ impl CharMap {
     //     self.put_lower_upper_bg(row, col, lower, upper, bg);
     // }
     pub fn put_char_256(&mut self, c: char, fg: usize, bg: usize, row: usize, col: usize) {
-        let (lower, upper) = self.lower_upper(c);
-        let lower = lower | self.pal_256[fg];
-        let bg = self.pal_256[bg];
-        self.put_lower_upper_bg(row, col, lower, upper, bg);
+        self.put_char_tc(
+            c,
+            TermColor::Pal(fg as u8),
+            TermColor::Pal(bg as u8),
+            row,
+            col,
+        );
     }
     pub fn put_char_true(&mut self, c: char, fg: u32, bg: u32, row: usize, col: usize) {
-        let (lower, upper) = self.lower_upper(c);
-        let lower = lower | fg;
-        self.put_lower_upper_bg(row, col, lower, upper, bg);
+        self.put_char_tc(c, TermColor::True(fg), TermColor::True(bg), row, col);
     }
     pub fn put_char_tc(&mut self, c: char, fg: TermColor, bg: TermColor, row: usize, col: usize) {
         let (lower, upper) = self.lower_upper(c);
-        let lower = lower | fg.select(&self.pal_256);
-        let bg = bg.select(&self.pal_256);
+        let lower = lower | fg.quantize(&self.pal_256, self.depth).select(&self.pal_256);
+        let bg = bg.quantize(&self.pal_256, self.depth).select(&self.pal_256);
         self.put_lower_upper_bg(row, col, lower, upper, bg);
     }
     pub fn scroll_up(&mut self, n_lines: usize) {
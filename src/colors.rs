@@ -0,0 +1,77 @@
+/// The colours that aren't in the palette. Everything is 0xRRGGBB.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ColorState {
+    /// Cells using the default foreground, set by OSC 10.
+    pub foreground: u32,
+    /// Cells using the default background, set by OSC 11.
+    pub background: u32,
+    /// Set by OSC 12. Text under the cursor is drawn in the background colour.
+    pub cursor: u32,
+    /// Fills the screen around the terminal grid.
+    pub margin: u32,
+}
+
+impl Default for ColorState {
+    fn default() -> Self {
+        ColorState {
+            foreground: 0xFFFFFF,
+            background: 0x000000,
+            cursor: 0xFFFFFF,
+            margin: 0xFFFFFF,
+        }
+    }
+}
+
+impl ColorState {
+    /// The colour a dynamic colour OSC (10, 11 or 12) talks about.
+    pub fn dynamic_mut(&mut self, ps: u32) -> Option<&mut u32> {
+        match ps {
+            10 => Some(&mut self.foreground),
+            11 => Some(&mut self.background),
+            12 => Some(&mut self.cursor),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the colour specs xterm understands, apart from names: `rgb:R/G/B` with 1 to 4 hex digits per channel, and `#RGB` with 1 to 4 digits per channel.
+pub fn parse_color_spec(spec: &[u8]) -> Option<u32> {
+    let spec = std::str::from_utf8(spec).ok()?;
+    let channels: Vec<u32> = if let Some(rest) = spec.strip_prefix("rgb:") {
+        let parts: Vec<&str> = rest.split('/').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+        parts
+            .into_iter()
+            .map(|p| scale_hex(p, p.len()))
+            .collect::<Option<_>>()?
+    } else if let Some(rest) = spec.strip_prefix('#') {
+        let digits = rest.len() / 3;
+        if rest.len() % 3 != 0 || !rest.is_ascii() {
+            return None;
+        }
+        (0..3)
+            .map(|i| scale_hex(&rest[i * digits..(i + 1) * digits], digits))
+            .collect::<Option<_>>()?
+    } else {
+        return None;
+    };
+    Some((channels[0] << 16) | (channels[1] << 8) | channels[2])
+}
+
+/// Turns 1 to 4 hex digits into a byte, treating them as a fraction of the largest value that many digits can hold.
+fn scale_hex(digits: &str, len: usize) -> Option<u32> {
+    if !(1..=4).contains(&len) || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let value = u32::from_str_radix(digits, 16).ok()?;
+    let max = (1u32 << (4 * len)) - 1;
+    Some((value * 255 + max / 2) / max)
+}
+
+/// The `rgb:RRRR/GGGG/BBBB` form xterm answers queries with.
+pub fn format_color_spec(color: u32) -> String {
+    let [_, r, g, b] = color.to_be_bytes();
+    format!("rgb:{r:02x}{r:02x}/{g:02x}{g:02x}/{b:02x}{b:02x}")
+}
@@ -0,0 +1,112 @@
+use crate::palette::{channels, rgb, srgb_to_linear};
+
+/// How bright a colour looks, from 0.0 for black to 1.0 for white. This is the WCAG definition.
+pub fn relative_luminance(color: u32) -> f32 {
+    let [r, g, b] = channels(color).map(srgb_to_linear);
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// From 1.0 (the same) to 21.0 (black on white). Doesn't care which way round the colours are.
+pub fn contrast_ratio(a: u32, b: u32) -> f32 {
+    let (la, lb) = (relative_luminance(a), relative_luminance(b));
+    (la.max(lb) + 0.05) / (la.min(lb) + 0.05)
+}
+
+/// Moves `from` a fraction `t` of the way to `to`.
+fn mix(from: u32, to: u32, t: f32) -> u32 {
+    let [fr, fg, fb] = channels(from);
+    let [tr, tg, tb] = channels(to);
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    rgb(lerp(fr, tr), lerp(fg, tg), lerp(fb, tb))
+}
+
+/// Lightens or darkens `fg` just enough to reach `min_ratio` against `bg`, keeping its hue.
+///
+/// Goes away from the background first (lighter on dark backgrounds, darker on light ones), and only tries the other way if that can't get there.
+/// If neither can, you get whichever of black or white stands out more.
+pub fn ensure_contrast(fg: u32, bg: u32, min_ratio: f32) -> u32 {
+    if contrast_ratio(fg, bg) >= min_ratio {
+        return fg;
+    }
+    let (white, black) = (0xFFFFFF, 0x000000);
+    let targets = if contrast_ratio(white, bg) >= contrast_ratio(black, bg) {
+        [white, black]
+    } else {
+        [black, white]
+    };
+    for target in targets {
+        if contrast_ratio(target, bg) < min_ratio {
+            continue;
+        }
+        //Once a step towards the target is enough, any bigger step is too, so find the smallest one
+        let (mut lo, mut hi) = (0.0f32, 1.0f32);
+        for _ in 0..12 {
+            let mid = (lo + hi) / 2.0;
+            if contrast_ratio(mix(fg, target, mid), bg) >= min_ratio {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+        return mix(fg, target, hi);
+    }
+    targets[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.01, "{a} isn't {b}");
+    }
+
+    #[test]
+    fn wcag_ratios() {
+        assert_near(contrast_ratio(0x000000, 0xFFFFFF), 21.0);
+        assert_near(contrast_ratio(0xFFFFFF, 0x000000), 21.0);
+        assert_near(contrast_ratio(0x777777, 0x777777), 1.0);
+        assert_near(contrast_ratio(0x0000FF, 0x000000), 2.44);
+        assert_near(contrast_ratio(0xFFFF00, 0xFFFFFF), 1.07);
+        assert_near(contrast_ratio(0x767676, 0xFFFFFF), 4.54);
+    }
+
+    #[test]
+    fn blue_on_black_gets_lighter() {
+        let fg = ensure_contrast(0x0000FF, 0x000000, 4.5);
+        assert!(contrast_ratio(fg, 0x000000) >= 4.5);
+        //Still blue, and no lighter than it has to be
+        let [r, g, b] = channels(fg);
+        assert!(b == 0xFF && r == g && r < 0x80, "{fg:06x}");
+        assert!(contrast_ratio(mix(0x0000FF, fg, 0.9), 0x000000) < 4.5);
+    }
+
+    #[test]
+    fn yellow_on_white_gets_darker() {
+        let fg = ensure_contrast(0xFFFF00, 0xFFFFFF, 4.5);
+        assert!(contrast_ratio(fg, 0xFFFFFF) >= 4.5);
+        let [r, g, b] = channels(fg);
+        assert!(r == g && b == 0 && r < 0x80, "{fg:06x}");
+    }
+
+    #[test]
+    fn enough_contrast_is_left_alone() {
+        assert_eq!(ensure_contrast(0xFFFF00, 0x000000, 4.5), 0xFFFF00);
+    }
+
+    #[test]
+    fn a_mid_grey_background_goes_whichever_way_gets_there() {
+        //White can't get 4:1 on this, black can, even though the text starts out lighter
+        let bg = 0x888888;
+        assert!(contrast_ratio(0xFFFFFF, bg) < 4.0);
+        let fg = ensure_contrast(0xAAAAAA, bg, 4.0);
+        assert!(contrast_ratio(fg, bg) >= 4.0, "{fg:06x}");
+        assert!(fg < 0x888888);
+    }
+
+    #[test]
+    fn out_of_reach_gives_black_or_white() {
+        assert_eq!(ensure_contrast(0x808080, 0x202020, 21.0), 0xFFFFFF);
+        assert_eq!(ensure_contrast(0x808080, 0xE0E0E0, 21.0), 0x000000);
+    }
+}
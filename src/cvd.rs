@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+
+use crate::palette::{channels, linear_to_srgb, rgb, srgb_to_linear};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Deficiency {
+    /// No red cones.
+    Protanopia,
+    /// No green cones, the most common one.
+    Deuteranopia,
+    /// No blue cones.
+    Tritanopia,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CvdMode {
+    #[default]
+    Off,
+    /// Shows roughly what someone with the deficiency would see, for checking themes.
+    Simulate(Deficiency),
+    /// Moves the differences someone with the deficiency can't see into ones they can, so red and green in diffs stay apart.
+    Correct(Deficiency),
+}
+
+type Matrix = [[f32; 3]; 3];
+
+/// Machado, Oliveira and Fernandes (2009), at full severity. These work on linear RGB.
+const PROTANOPIA: Matrix = [
+    [0.152286, 1.052583, -0.204868],
+    [0.114503, 0.786281, 0.099216],
+    [-0.003882, -0.048116, 1.051998],
+];
+const DEUTERANOPIA: Matrix = [
+    [0.367322, 0.860646, -0.227968],
+    [0.280085, 0.672501, 0.047413],
+    [-0.011820, 0.042940, 0.968881],
+];
+const TRITANOPIA: Matrix = [
+    [1.255528, -0.076749, -0.178779],
+    [-0.078411, 0.930809, 0.147602],
+    [0.004733, 0.691367, 0.303900],
+];
+
+/// Where the lost difference gets put back: red/green loss goes into green and blue, blue/yellow loss into red and green.
+const SHIFT_RED_GREEN: Matrix = [[0.0, 0.0, 0.0], [0.7, 1.0, 0.0], [0.7, 0.0, 1.0]];
+const SHIFT_BLUE: Matrix = [[1.0, 0.0, 0.7], [0.0, 1.0, 0.7], [0.0, 0.0, 0.0]];
+
+fn mul(m: &Matrix, v: [f32; 3]) -> [f32; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+impl Deficiency {
+    fn simulation(self) -> &'static Matrix {
+        match self {
+            Deficiency::Protanopia => &PROTANOPIA,
+            Deficiency::Deuteranopia => &DEUTERANOPIA,
+            Deficiency::Tritanopia => &TRITANOPIA,
+        }
+    }
+    fn shift(self) -> &'static Matrix {
+        match self {
+            Deficiency::Protanopia | Deficiency::Deuteranopia => &SHIFT_RED_GREEN,
+            Deficiency::Tritanopia => &SHIFT_BLUE,
+        }
+    }
+}
+
+impl CvdMode {
+    /// Transforms one 0xRRGGBB colour.
+    pub fn apply(self, color: u32) -> u32 {
+        let (deficiency, correct) = match self {
+            CvdMode::Off => return color,
+            CvdMode::Simulate(d) => (d, false),
+            CvdMode::Correct(d) => (d, true),
+        };
+        let linear = channels(color).map(srgb_to_linear);
+        let simulated = mul(deficiency.simulation(), linear);
+        let out = if correct {
+            //Daltonize: whatever the simulation lost gets added back where it can be seen
+            let lost = [0, 1, 2].map(|i| linear[i] - simulated[i]);
+            let shifted = mul(deficiency.shift(), lost);
+            [0, 1, 2].map(|i| linear[i] + shifted[i])
+        } else {
+            simulated
+        };
+        let [r, g, b] = out.map(linear_to_srgb);
+        rgb(r, g, b)
+    }
+    /// Transforms every pixel of an RGBA image, leaving alpha alone.
+    pub fn apply_rgba(self, rgba: &[u8]) -> Vec<u8> {
+        //Sixel and most pixel art only use a few colours
+        let mut cache: HashMap<[u8; 3], [u8; 3]> = HashMap::new();
+        let mut out = rgba.to_vec();
+        for pixel in out.chunks_exact_mut(4) {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]];
+            let new = *cache
+                .entry([r, g, b])
+                .or_insert_with(|| channels(self.apply(rgb(r, g, b))));
+            pixel[..3].copy_from_slice(&new);
+        }
+        out
+    }
+    /// Parses the names the control port takes: `off`, or `simulate` or `correct` followed by `protanopia`, `deuteranopia` or `tritanopia`.
+    pub fn parse(text: &str) -> Option<CvdMode> {
+        let mut words = text.split_whitespace();
+        let mode = words.next()?;
+        if mode == "off" {
+            return Some(CvdMode::Off);
+        }
+        let deficiency = match words.next()? {
+            "protanopia" => Deficiency::Protanopia,
+            "deuteranopia" => Deficiency::Deuteranopia,
+            "tritanopia" => Deficiency::Tritanopia,
+            _ => return None,
+        };
+        match mode {
+            "simulate" => Some(CvdMode::Simulate(deficiency)),
+            "correct" => Some(CvdMode::Correct(deficiency)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Deficiency; 3] = [
+        Deficiency::Protanopia,
+        Deficiency::Deuteranopia,
+        Deficiency::Tritanopia,
+    ];
+
+    fn assert_near(a: u32, b: u32) {
+        let close = channels(a)
+            .into_iter()
+            .zip(channels(b))
+            .all(|(a, b)| a.abs_diff(b) <= 1);
+        assert!(close, "{a:06x} isn't {b:06x}");
+    }
+
+    fn modes() -> impl Iterator<Item = CvdMode> {
+        ALL.into_iter()
+            .flat_map(|d| [CvdMode::Simulate(d), CvdMode::Correct(d)])
+    }
+
+    #[test]
+    fn off_changes_nothing() {
+        for color in [0x000000, 0xFF0000, 0x12AB34, 0xFFFFFF] {
+            assert_eq!(CvdMode::Off.apply(color), color);
+        }
+        let rgba = [1, 2, 3, 4, 250, 128, 0, 255];
+        assert_eq!(CvdMode::Off.apply_rgba(&rgba), rgba);
+    }
+
+    #[test]
+    fn greys_stay_put() {
+        //Every row of the simulation adds up to one, so nothing without colour has anything to lose
+        for deficiency in ALL {
+            for row in deficiency.simulation() {
+                assert!((row.iter().sum::<f32>() - 1.0).abs() < 0.001);
+            }
+        }
+        for mode in modes() {
+            for grey in [0x000000, 0x808080, 0xFFFFFF] {
+                assert_near(mode.apply(grey), grey);
+            }
+        }
+    }
+
+    #[test]
+    fn red_as_protanopes_see_it() {
+        //The first column of the Machado matrix, [0.152, 0.115, -0.004], back in sRGB
+        assert_near(
+            CvdMode::Simulate(Deficiency::Protanopia).apply(0xFF0000),
+            0x6D5F00,
+        );
+    }
+
+    #[test]
+    fn correcting_moves_what_is_lost_where_it_can_be_seen() {
+        //Red and green blindness lose red, which goes into blue, leaving red itself alone
+        for deficiency in [Deficiency::Protanopia, Deficiency::Deuteranopia] {
+            let [r, g, b] = channels(CvdMode::Correct(deficiency).apply(0xCC3333));
+            assert_eq!(r, 0xCC);
+            assert!(b > 0x60 && b > g, "{deficiency:?}: {r:02x}{g:02x}{b:02x}");
+        }
+        //Blue blindness loses blue, which goes into red and green, leaving blue alone
+        let [r, g, b] = channels(CvdMode::Correct(Deficiency::Tritanopia).apply(0x3333CC));
+        assert_eq!(b, 0xCC);
+        assert!(r > 0x33 || g > 0x33, "{r:02x}{g:02x}{b:02x}");
+    }
+
+    #[test]
+    fn alpha_is_left_alone() {
+        let mode = CvdMode::Simulate(Deficiency::Deuteranopia);
+        let out = mode.apply_rgba(&[0xFF, 0x00, 0x00, 0x40]);
+        assert_eq!(out[3], 0x40);
+        assert_eq!(rgb(out[0], out[1], out[2]), mode.apply(0xFF0000));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(CvdMode::parse("off"), Some(CvdMode::Off));
+        assert_eq!(
+            CvdMode::parse("correct deuteranopia"),
+            Some(CvdMode::Correct(Deficiency::Deuteranopia))
+        );
+        assert_eq!(CvdMode::parse("simulate"), None);
+        assert_eq!(CvdMode::parse("squint tritanopia"), None);
+    }
+}
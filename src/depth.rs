@@ -0,0 +1,97 @@
+use crate::palette::{Palette, perceptual_distance};
+
+/// How many colours the terminal pretends it can show. Every colour is snapped to the nearest one it's allowed, by how different they look.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColorDepth {
+    #[default]
+    TrueColor,
+    /// The whole palette.
+    Colors256,
+    /// The first 16 entries of the palette.
+    Colors16,
+    /// Everything becomes one of two colours, both 0xRRGGBB. Green on black for that green screen look.
+    Mono { foreground: u32, background: u32 },
+}
+
+impl ColorDepth {
+    pub const GREEN_SCREEN: ColorDepth = ColorDepth::Mono {
+        foreground: 0x33FF33,
+        background: 0x000000,
+    };
+
+    /// Snaps a 0xRRGGBB colour to this depth.
+    pub fn quantize(self, rgb: u32, pal: &Palette) -> u32 {
+        match self {
+            ColorDepth::TrueColor => rgb,
+            ColorDepth::Colors256 => pal.get(pal.nearest_perceptual_in(rgb, 0..=255)),
+            ColorDepth::Colors16 => pal.get(pal.nearest_perceptual_in(rgb, 0..=15)),
+            ColorDepth::Mono {
+                foreground,
+                background,
+            } => {
+                if perceptual_distance(rgb, foreground) < perceptual_distance(rgb, background) {
+                    foreground
+                } else {
+                    background
+                }
+            }
+        }
+    }
+    /// Snaps the text colour `fg` on `bg`, now that `bg` has been snapped to `bg_snapped`.
+    ///
+    /// In monochrome it's whichever of the two colours isn't the background's, so dark text on a dark background doesn't vanish,
+    /// unless the text was hidden by being the same colour as its background to begin with.
+    /// Any other depth just snaps `fg`.
+    pub fn quantize_text(self, fg: u32, bg: u32, bg_snapped: u32, pal: &Palette) -> u32 {
+        match self {
+            ColorDepth::Mono { .. } if fg == bg => bg_snapped,
+            ColorDepth::Mono {
+                foreground,
+                background,
+            } => {
+                if bg_snapped == background {
+                    foreground
+                } else {
+                    background
+                }
+            }
+            _ => self.quantize(fg, pal),
+        }
+    }
+    /// Like [`ColorDepth::quantize`], for palette entries, which don't need snapping if they're already allowed.
+    pub fn quantize_index(self, index: u8, pal: &Palette) -> u32 {
+        match self {
+            ColorDepth::TrueColor | ColorDepth::Colors256 => pal.get(index),
+            ColorDepth::Colors16 if index < 16 => pal.get(index),
+            _ => self.quantize(pal.get(index), pal),
+        }
+    }
+    /// Parses the names the control port takes: `true`, `256`, `16`, `mono`, or `mono` followed by a foreground and background like `#33ff33 #000000`.
+    pub fn parse(text: &str) -> Option<ColorDepth> {
+        let mut words = text.split_whitespace();
+        let depth = match words.next()? {
+            "true" | "truecolor" | "24bit" => ColorDepth::TrueColor,
+            "256" => ColorDepth::Colors256,
+            "16" => ColorDepth::Colors16,
+            "mono" | "2" => match (words.next(), words.next()) {
+                (None, _) => ColorDepth::GREEN_SCREEN,
+                (Some(fg), Some(bg)) => ColorDepth::Mono {
+                    foreground: parse_hex(fg)?,
+                    background: parse_hex(bg)?,
+                },
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(depth)
+    }
+}
+
+/// `#rrggbb`, or without the `#`, to 0xRRGGBB.
+pub fn parse_hex(text: &str) -> Option<u32> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if hex.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
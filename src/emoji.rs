@@ -0,0 +1,281 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use crate::{
+    cvd::CvdMode,
+    depth::ColorDepth,
+    font::AtlasLayout,
+    image_layer::{Image, decode_png},
+    palette::{self, Palette},
+    tile_encoder::EncodeOptions,
+};
+
+/// Every emoji in `emoji.png`, left to right.
+const EMOJI: [char; 26] = [
+    '\u{1F600}',
+    '\u{1F602}',
+    '\u{1F642}',
+    '\u{1F609}',
+    '\u{1F622}',
+    '\u{1F44D}',
+    '\u{1F440}',
+    '\u{2764}',
+    '\u{1F525}',
+    '\u{2728}',
+    '\u{1F41B}',
+    '\u{1F4DD}',
+    '\u{1F680}',
+    '\u{2705}',
+    '\u{274C}',
+    '\u{26A0}',
+    '\u{1F389}',
+    '\u{1F527}',
+    '\u{1F4A1}',
+    '\u{2B50}',
+    '\u{26A1}',
+    '\u{1F512}',
+    '\u{1F4E6}',
+    '\u{1F534}',
+    '\u{1F7E2}',
+    '\u{1F7E1}',
+];
+/// Emoji in `emoji.png` are this many pixels square.
+const EMOJI_SIZE: usize = 16;
+
+/// The colour emoji vitatty comes with: the ones that turn up most in commit messages and chat, as pixel art.
+pub struct EmojiSet {
+    sheet: Image,
+}
+
+impl EmojiSet {
+    /// Decoded the first time anything asks for it.
+    pub fn bundled() -> &'static EmojiSet {
+        static BUNDLED: OnceLock<EmojiSet> = OnceLock::new();
+        BUNDLED.get_or_init(|| {
+            let sheet = decode_png(include_bytes!("emoji.png"), usize::MAX)
+                .expect("The bundled emoji.png is broken");
+            assert_eq!(
+                (sheet.width, sheet.height),
+                (EMOJI.len() * EMOJI_SIZE, EMOJI_SIZE),
+                "emoji.png doesn't match the list of emoji"
+            );
+            EmojiSet { sheet }
+        })
+    }
+    pub fn count(&self) -> usize {
+        EMOJI.len()
+    }
+    pub fn index(&self, c: char) -> Option<usize> {
+        EMOJI.iter().position(|&e| e == c)
+    }
+    /// Emoji `index` scaled to fit in the middle of a `width` by `height` box, as RGBA.
+    ///
+    /// Each pixel is the average of the emoji pixels under it, weighted by how opaque they are, so shrinking doesn't leave dark fringes.
+    pub fn draw(&self, index: usize, width: usize, height: usize) -> Vec<u8> {
+        let mut pixels = vec![0; width * height * 4];
+        let side = width.min(height);
+        if side == 0 {
+            return pixels;
+        }
+        let (left, top) = ((width - side) / 2, (height - side) / 2);
+        //Source pixels from `start * EMOJI_SIZE / side` up to the next target pixel's start, at least one of them
+        let span = |i: usize| {
+            let start = i * EMOJI_SIZE / side;
+            start..((i + 1) * EMOJI_SIZE / side).max(start + 1)
+        };
+        for y in 0..side {
+            for x in 0..side {
+                let mut sum = [0u32; 4];
+                let mut count = 0;
+                for sy in span(y) {
+                    for sx in span(x) {
+                        let at = (sy * self.sheet.width + index * EMOJI_SIZE + sx) * 4;
+                        let pixel = &self.sheet.rgba[at..at + 4];
+                        let alpha = pixel[3] as u32;
+                        for (total, &channel) in sum.iter_mut().zip(&pixel[..3]) {
+                            *total += channel as u32 * alpha;
+                        }
+                        sum[3] += alpha;
+                        count += 1;
+                    }
+                }
+                let [r, g, b, a] = sum;
+                if a > 0 {
+                    let at = ((top + y) * width + left + x) * 4;
+                    let [r, g, b] = [r, g, b].map(|c| (c / a) as u8);
+                    pixels[at..at + 4].copy_from_slice(&[r, g, b, (a / count) as u8]);
+                }
+            }
+        }
+        pixels
+    }
+}
+
+/// Where every emoji in a set goes in the colour atlas, each drawn twice: two cells wide for wide cells, and one cell wide for narrow ones.
+///
+/// There's only a handful of them, so they're all drawn up front instead of going through a [`GlyphCache`](crate::glyph_cache::GlyphCache).
+pub struct EmojiAtlas {
+    pub layout: AtlasLayout,
+    cell_width: usize,
+    cell_height: usize,
+}
+
+impl EmojiAtlas {
+    pub fn new(set: &EmojiSet, (cell_width, cell_height): (usize, usize)) -> EmojiAtlas {
+        EmojiAtlas {
+            layout: AtlasLayout::new(set.count() * 2, (cell_width * 2, cell_height)),
+            cell_width,
+            cell_height,
+        }
+    }
+    /// The top left pixel of emoji `index` in the atlas, or `None` if the cells are so big it didn't fit.
+    pub fn origin(&self, index: usize, wide: bool) -> Option<[u16; 2]> {
+        self.layout.origin(index * 2 + !wide as usize)
+    }
+    /// The whole atlas as RGBA, [`AtlasLayout::size`] big.
+    pub fn pixels(&self, set: &EmojiSet) -> Vec<u8> {
+        let (width, height) = self.layout.size();
+        let mut pixels = vec![0; width * height * 4];
+        for index in 0..set.count() {
+            for wide in [true, false] {
+                let Some([x, y]) = self.origin(index, wide) else {
+                    continue;
+                };
+                let emoji_width = self.cell_width * (1 + wide as usize);
+                let emoji = set.draw(index, emoji_width, self.cell_height);
+                for (row, line) in emoji.chunks_exact(emoji_width * 4).enumerate() {
+                    let at = ((y as usize + row) * width + x as usize) * 4;
+                    pixels[at..at + line.len()].copy_from_slice(line);
+                }
+            }
+        }
+        pixels
+    }
+}
+
+/// What's done to the emoji's own colours so they match the rest of the screen, the same as [`EncodeOptions`] does to cells.
+///
+/// Monochrome isn't done here: there the emoji are drawn as shapes in their cell's text colour instead, which only the shader can do.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct EmojiColors {
+    reverse_video: bool,
+    cvd: CvdMode,
+    /// Only when it's snapping to the palette, so changing the palette doesn't redo the emoji otherwise.
+    palette: Option<(ColorDepth, Palette)>,
+}
+
+impl EmojiColors {
+    pub fn new(options: &EncodeOptions, pal: &Palette) -> EmojiColors {
+        let palette = match options.depth {
+            ColorDepth::Colors256 | ColorDepth::Colors16 => Some((options.depth, *pal)),
+            ColorDepth::TrueColor | ColorDepth::Mono { .. } => None,
+        };
+        EmojiColors {
+            reverse_video: options.reverse_video,
+            cvd: options.cvd,
+            palette,
+        }
+    }
+    /// Recolours RGBA pixels: inverted for reverse video, then colour blindness, then the depth, like cells are.
+    ///
+    /// Alpha is left alone, as are pixels that can't be seen.
+    pub fn apply(&self, rgba: &[u8]) -> Vec<u8> {
+        let mut cache: HashMap<[u8; 3], [u8; 3]> = HashMap::new();
+        let mut out = rgba.to_vec();
+        for pixel in out.chunks_exact_mut(4).filter(|pixel| pixel[3] != 0) {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]];
+            let new = *cache.entry([r, g, b]).or_insert_with(|| {
+                let rgb = palette::rgb(r, g, b);
+                let rgb = if self.reverse_video {
+                    !rgb & 0xFFFFFF
+                } else {
+                    rgb
+                };
+                let rgb = self.cvd.apply(rgb);
+                let rgb = match self.palette {
+                    Some((depth, pal)) => depth.quantize(rgb, &pal),
+                    None => rgb,
+                };
+                palette::channels(rgb)
+            });
+            pixel[..3].copy_from_slice(&new);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIXELS: [u8; 12] = [
+        0xFF, 0x20, 0x20, 0xFF, 0x10, 0x10, 0x10, 0x80, 0x12, 0x34, 0x56, 0,
+    ];
+
+    #[test]
+    fn by_default_nothing_changes() {
+        let colors = EmojiColors::new(&EncodeOptions::default(), &Palette::XTERM);
+        assert_eq!(colors.apply(&PIXELS), PIXELS);
+    }
+
+    #[test]
+    fn reverse_video_inverts_what_can_be_seen() {
+        let options = EncodeOptions {
+            reverse_video: true,
+            ..EncodeOptions::default()
+        };
+        let colors = EmojiColors::new(&options, &Palette::XTERM);
+        assert_eq!(
+            colors.apply(&PIXELS),
+            [
+                0x00, 0xDF, 0xDF, 0xFF, 0xEF, 0xEF, 0xEF, 0x80, 0x12, 0x34, 0x56, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn colour_blindness_is_applied() {
+        let cvd = CvdMode::parse("simulate protanopia").unwrap();
+        let options = EncodeOptions {
+            cvd,
+            ..EncodeOptions::default()
+        };
+        let out = EmojiColors::new(&options, &Palette::XTERM).apply(&PIXELS);
+        assert_eq!(out[..4], cvd.apply_rgba(&PIXELS[..4]));
+        assert_ne!(out[..3], PIXELS[..3]);
+    }
+
+    #[test]
+    fn sixteen_colours_snap_to_the_palette() {
+        let pal = Palette::XTERM;
+        let options = EncodeOptions {
+            depth: ColorDepth::Colors16,
+            ..EncodeOptions::default()
+        };
+        let out = EmojiColors::new(&options, &pal).apply(&PIXELS);
+        for pixel in out[..8].chunks_exact(4) {
+            let rgb = palette::rgb(pixel[0], pixel[1], pixel[2]);
+            assert!((0..16).any(|i| pal.get(i) == rgb), "{rgb:06X}");
+        }
+        assert_eq!(out[3], 0xFF);
+        assert_eq!(out[7], 0x80);
+    }
+
+    #[test]
+    fn palette_changes_only_matter_when_snapping_to_it() {
+        let mut other = Palette::XTERM;
+        other.set(1, 0x123456);
+        let plain = EncodeOptions::default();
+        assert_eq!(
+            EmojiColors::new(&plain, &Palette::XTERM),
+            EmojiColors::new(&plain, &other)
+        );
+        let snapped = EncodeOptions {
+            depth: ColorDepth::Colors256,
+            ..EncodeOptions::default()
+        };
+        assert_ne!(
+            EmojiColors::new(&snapped, &Palette::XTERM),
+            EmojiColors::new(&snapped, &other)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::rc::Rc;
+
+use psf2_font::Psf2Font;
+
+use crate::{bdf::BdfFont, outline_font::OutlineFont, psf1::Psf1Font, psf2};
+
+/// Loaded at startup instead of Terminus, if it's there.
+pub const FONT_PATH: &str = "ux0:data/vitatty/font";
+/// The other faces, loaded at startup if they're there. They have to be the same size as the regular face.
+pub const FACE_PATHS: [(Face, &str); 3] = [
+    (Face::Bold, "ux0:data/vitatty/font_bold"),
+    (Face::Italic, "ux0:data/vitatty/font_italic"),
+    (Face::BoldItalic, "ux0:data/vitatty/font_bold_italic"),
+];
+/// Pixel height for TrueType and OpenType fonts when nobody said otherwise. Bitmap fonts are always their own size.
+pub const DEFAULT_OUTLINE_SIZE: f32 = 16.0;
+/// Biggest texture the Vita's GPU takes, in either direction.
+pub const MAX_TEXTURE_SIZE: usize = 4096;
+/// Empty pixels around every glyph in the atlas, so smoothing a stretched glyph doesn't pick up its neighbours.
+pub const ATLAS_PADDING: usize = 1;
+/// Big enough for anyone, and small enough that a decent number still fit in the atlas.
+pub const MAX_GLYPH_SIZE: usize = 256;
+
+/// A glyph, ready to go in the atlas.
+pub enum Glyph<'a> {
+    /// Packed 1 bit per pixel, most significant bit on the left, each row padded out to a whole byte, rows top to bottom. That's the PSF layout.
+    Bitmap(&'a [u8]),
+    /// One byte of coverage per pixel, `0x00` to `0xFF`, a whole cell's worth, rows top to bottom.
+    Coverage(Vec<u8>),
+}
+
+/// Where glyphs go in the atlas. They're all the same size, so they go in a grid of slots, left to right then top to bottom,
+/// each glyph with [`ATLAS_PADDING`] of empty space on every side.
+#[derive(Clone, Copy, Debug)]
+pub struct AtlasLayout {
+    /// Slots per row.
+    pub columns: usize,
+    /// How many slots fit.
+    pub capacity: usize,
+    /// A glyph plus the padding between it and the next one, in pixels.
+    pub slot_width: usize,
+    pub slot_height: usize,
+}
+
+impl AtlasLayout {
+    pub fn new(slots: usize, (width, height): (usize, usize)) -> AtlasLayout {
+        let (slot_width, slot_height) = (width + ATLAS_PADDING, height + ATLAS_PADDING);
+        //The padding above and left of the first slot
+        let room = MAX_TEXTURE_SIZE - ATLAS_PADDING;
+        let columns = (room / slot_width).min(slots).max(1);
+        let capacity = (columns * (room / slot_height)).min(slots);
+        AtlasLayout {
+            columns,
+            capacity,
+            slot_width,
+            slot_height,
+        }
+    }
+    /// The top left pixel of the glyph in a slot.
+    pub fn origin(&self, slot: usize) -> Option<[u16; 2]> {
+        (slot < self.capacity).then(|| {
+            [
+                (slot % self.columns * self.slot_width + ATLAS_PADDING) as u16,
+                (slot / self.columns * self.slot_height + ATLAS_PADDING) as u16,
+            ]
+        })
+    }
+    /// `(width, height)` in pixels of the slots actually used, padding and all.
+    pub fn size(&self) -> (usize, usize) {
+        let rows = self.capacity.div_ceil(self.columns);
+        (
+            self.columns * self.slot_width + ATLAS_PADDING,
+            rows * self.slot_height + ATLAS_PADDING,
+        )
+    }
+}
+
+/// What the rasterizer and the tile encoder need from a font, whatever file it came from.
+pub trait Font {
+    /// `(width, height)` of every glyph, in pixels.
+    fn dimensions(&self) -> (usize, usize);
+    fn glyph_count(&self) -> usize;
+    /// Which glyph to draw for `c`, if the font has one.
+    fn glyph_index(&self, c: char) -> Option<usize>;
+    fn glyph(&self, index: usize) -> Option<Glyph<'_>>;
+    /// The same font drawn `factor` times bigger, for fonts that can be drawn at any size.
+    /// Bitmap fonts can't, so they give `None` and get stretched on the GPU instead.
+    fn scaled(&self, _factor: f32) -> Option<Result<Box<dyn Font + Send>, String>> {
+        None
+    }
+    /// The outline font underneath, for shaping. Bitmap fonts don't have ligatures to shape with.
+    fn outline(&self) -> Option<&OutlineFont> {
+        None
+    }
+}
+
+/// Which of a font's faces to draw a cell with.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Face {
+    Regular = 0,
+    Bold = 1,
+    Italic = 2,
+    BoldItalic = 3,
+}
+
+impl Face {
+    pub const ALL: [Face; 4] = [Face::Regular, Face::Bold, Face::Italic, Face::BoldItalic];
+    pub fn from_style(bold: bool, italic: bool) -> Face {
+        match (bold, italic) {
+            (false, false) => Face::Regular,
+            (true, false) => Face::Bold,
+            (false, true) => Face::Italic,
+            (true, true) => Face::BoldItalic,
+        }
+    }
+    pub fn is_italic(self) -> bool {
+        matches!(self, Face::Italic | Face::BoldItalic)
+    }
+    /// `regular`, `bold`, `italic` or `bold-italic`.
+    pub fn parse(text: &str) -> Option<Face> {
+        match text {
+            "regular" => Some(Face::Regular),
+            "bold" => Some(Face::Bold),
+            "italic" => Some(Face::Italic),
+            "bold-italic" => Some(Face::BoldItalic),
+            _ => None,
+        }
+    }
+    /// What to use instead when this face isn't loaded, best first. Slant matters more than weight, since fake italics are uglier than missing bold.
+    fn fallbacks(self) -> &'static [Face] {
+        match self {
+            Face::Regular => &[],
+            Face::Bold => &[Face::Regular],
+            Face::Italic => &[Face::Regular],
+            Face::BoldItalic => &[Face::Italic, Face::Bold, Face::Regular],
+        }
+    }
+}
+
+/// Up to four faces of a font, all with the same cell size. Only the regular face has to be there.
+///
+/// Cloning is cheap, the faces are shared, so changes can be tried out on a copy.
+#[derive(Clone)]
+pub struct FontFaces {
+    faces: [Option<Rc<dyn Font + Send>>; 4],
+}
+
+impl FontFaces {
+    pub fn new(regular: Box<dyn Font + Send>) -> FontFaces {
+        FontFaces {
+            faces: [Some(regular.into()), None, None, None],
+        }
+    }
+    pub fn regular(&self) -> &(dyn Font + Send) {
+        self.faces[Face::Regular as usize]
+            .as_deref()
+            .expect("The regular face is always there")
+    }
+    pub fn get(&self, face: Face) -> Option<&(dyn Font + Send)> {
+        self.faces[face as usize].as_deref()
+    }
+    /// Adds or replaces one of the other faces. A new regular face can be a different size, but then the other faces are dropped.
+    pub fn set(&mut self, face: Face, font: Box<dyn Font + Send>) -> Result<(), String> {
+        if face == Face::Regular {
+            if font.dimensions() != self.regular().dimensions() {
+                *self = FontFaces::new(font);
+            } else {
+                self.faces[0] = Some(font.into());
+            }
+            return Ok(());
+        }
+        let (width, height) = font.dimensions();
+        let (regular_width, regular_height) = self.regular().dimensions();
+        if (width, height) != (regular_width, regular_height) {
+            return Err(format!(
+                "{face:?} face is {width}x{height}, but the regular face is {regular_width}x{regular_height}"
+            ));
+        }
+        self.faces[face as usize] = Some(font.into());
+        Ok(())
+    }
+    /// The face that actually gets drawn for `face`: itself if it's loaded, or the closest one that is.
+    pub fn resolve(&self, face: Face) -> Face {
+        std::iter::once(face)
+            .chain(face.fallbacks().iter().copied())
+            .find(|&f| self.faces[f as usize].is_some())
+            .unwrap_or(Face::Regular)
+    }
+    /// Every face drawn `factor` times bigger, or `None` for bitmap fonts. See [`Font::scaled`].
+    ///
+    /// Faces that can't be scaled, or come out a different size to the regular face, are left out.
+    pub fn scaled(&self, factor: f32) -> Option<Result<FontFaces, String>> {
+        let regular = match self.regular().scaled(factor)? {
+            Ok(regular) => regular,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut scaled = FontFaces::new(regular);
+        for face in [Face::Bold, Face::Italic, Face::BoldItalic] {
+            if let Some(Some(Ok(font))) = self.get(face).map(|f| f.scaled(factor)) {
+                let _ = scaled.set(face, font);
+            }
+        }
+        Some(Ok(scaled))
+    }
+}
+
+impl Font for Psf2Font {
+    fn dimensions(&self) -> (usize, usize) {
+        let (width, height) = Psf2Font::dimensions(self);
+        (width as usize, height as usize)
+    }
+    fn glyph_count(&self) -> usize {
+        Psf2Font::glyph_count(self)
+    }
+    fn glyph_index(&self, c: char) -> Option<usize> {
+        self.get_glyph_index(c)
+    }
+    fn glyph(&self, index: usize) -> Option<Glyph<'_>> {
+        self.get_glyph_by_index(index).map(Glyph::Bitmap)
+    }
+}
+
+/// Works out what kind of font `data` is and parses it. `size` only matters for outline fonts.
+pub fn load(data: Vec<u8>, size: f32) -> Result<Box<dyn Font + Send>, String> {
+    let font: Box<dyn Font + Send> = match data.get(..4) {
+        Some([0x36, 0x04, ..]) => Box::new(Psf1Font::parse(&data)?),
+        Some([0x72, 0xB5, 0x4A, 0x86]) => Box::new(psf2::Psf2Font::parse(&data)?),
+        Some(b"STAR") => {
+            let text = String::from_utf8(data).map_err(|_| "BDF font isn't valid UTF-8")?;
+            Box::new(BdfFont::parse(&text)?)
+        }
+        Some([0x00, 0x01, 0x00, 0x00] | b"true" | b"OTTO" | b"ttcf") => {
+            Box::new(OutlineFont::parse(data, size)?)
+        }
+        _ => return Err("Not a PSF, BDF, TrueType or OpenType font".to_string()),
+    };
+    let (width, height) = font.dimensions();
+    if width > MAX_GLYPH_SIZE || height > MAX_GLYPH_SIZE {
+        return Err(format!(
+            "Glyphs are {width}x{height}, bigger than {MAX_GLYPH_SIZE}x{MAX_GLYPH_SIZE}"
+        ));
+    }
+    if font.glyph_count() == 0 {
+        return Err("Font has no glyphs".to_string());
+    }
+    Ok(font)
+}
@@ -1,54 +1,76 @@
-use psf2_font::Psf2Font;
+use crate::{
+    box_drawing,
+    emoji::{EmojiAtlas, EmojiColors, EmojiSet},
+    font::{AtlasLayout, Font, Glyph},
+    glyph_cache::{AtlasTarget, GlyphCache},
+    outline_font::OutlineFont,
+    shaping::{self, Shaper},
+    symbols,
+};
 use vita_gl_helpers::texture::{GenDelTexturesExt, Texture};
 
+/// Most glyphs the atlas holds at once. Big fonts have tens of thousands, but a screen only shows a few hundred.
+const CACHE_SLOTS: usize = 4096;
+/// Glyph numbers from here up are characters we draw ourselves, well past the end of any real font.
+const DRAWN_GLYPHS: usize = 1 << 24;
+/// And from here up, pieces of ligatures.
+const LIGATURE_GLYPHS: usize = 1 << 25;
+
 pub struct RasterizedFont {
-    pub textures: Vec<Texture>,
+    /// Every glyph that's cached, in one texture, so the whole foreground is one draw.
+    pub texture: Texture,
     pub texture_width: usize,
     pub texture_height: usize,
     pub char_dim: glam::Vec2,
+    /// Size of a single pixel in UV coordinates, for turning glyph positions into UVs.
+    pub texel_dim: glam::Vec2,
+    /// Size of a single glyph in pixels
+    pub char_width: usize,
+    pub char_height: usize,
+    pub cache: GlyphCache,
 }
 
 impl Drop for RasterizedFont {
     fn drop(&mut self) {
-        self.textures.delete_textures();
+        [self.texture].delete_textures();
     }
 }
 
-pub fn rasterize_font(font: &Psf2Font) -> RasterizedFont {
-    let (char_width, char_height) = font.dimensions();
-    let texture_width = (char_width * 16).next_power_of_two() as usize;
-    let texture_height = (char_height * 16).next_power_of_two() as usize;
-    let char_dim = {
-        let c_dim = glam::vec2(char_width as f32, char_height as f32);
+impl RasterizedFont {
+    /// An empty atlas, glyphs are drawn into it as they turn up on screen.
+    pub fn new(font: &(impl Font + ?Sized)) -> RasterizedFont {
+        Self::with_slots(font, font.glyph_count())
+    }
+    /// Slots to give `font` so everything drawn with it fits at once: its own glyphs, the ones [`WithDrawnGlyphs`] adds,
+    /// and pieces of ligatures if it's an outline font.
+    pub fn slots_for(font: &(impl Font + ?Sized)) -> usize {
+        let pieces = match font.outline() {
+            Some(_) => shaping::MAX_PIECES,
+            None => 0,
+        };
+        font.glyph_count() + box_drawing::COVERED + symbols::COVERED + pieces
+    }
+    /// Like [`RasterizedFont::new`], but with room for `slots` glyphs (up to a limit) however many the font has.
+    /// Atlases made with the same slots and glyph size are the same size.
+    pub fn with_slots(font: &(impl Font + ?Sized), slots: usize) -> RasterizedFont {
+        let (char_width, char_height) = font.dimensions();
+        let charcount = font.glyph_count();
+        let layout = AtlasLayout::new(slots.min(CACHE_SLOTS), (char_width, char_height));
+        let (atlas_width, atlas_height) = layout.size();
+        let texture_width = atlas_width.next_power_of_two();
+        let texture_height = atlas_height.next_power_of_two();
         let t_dim = glam::vec2(texture_width as f32, texture_height as f32);
-        c_dim / t_dim
-    };
-    let charcount = font.glyph_count();
-    let n_textures_to_create = charcount.div_ceil(256);
-    let mut textures = vec![Texture::default(); n_textures_to_create];
-    textures.gen_textures();
-    //Keep this here to reuse the allocation. No need to clear as it will be overwritten.
-    let mut tex_data = vec![0u8; texture_width * texture_height];
-    println!("We need to do {charcount} chars");
-    for (tex_i, block) in ChunkIterator(charcount).into_iter().enumerate() {
-        println!("ti {tex_i} block {block}");
-        let tex_gl = textures[tex_i];
-
-        for chr_index in 0..block {
-            let glyph_index = (tex_i * 256) + chr_index;
-            let glyph = font
-                .get_glyph_by_index(glyph_index)
-                .expect("Somehow, got a char out of bounds");
-            rasterize_char(
-                &mut tex_data,
-                texture_width as usize,
-                chr_index,
-                glyph,
-                char_width as usize,
-            );
-        }
-        // dump_texture(&tex_data, self.texture_width as usize);
-        tex_gl.bind_then(gl::TEXTURE_2D, |b| {
+        let char_dim = glam::vec2(char_width as f32, char_height as f32) / t_dim;
+        let texel_dim = glam::Vec2::ONE / t_dim;
+        let mut textures = [Texture::default()];
+        textures.gen_textures();
+        let [texture] = textures;
+        let tex_data = vec![0u8; texture_width * texture_height];
+        println!(
+            "Font has {charcount} chars, room for {} in a {texture_width}x{texture_height} atlas",
+            layout.capacity
+        );
+        texture.bind_then(gl::TEXTURE_2D, |b| {
             b.image_2d(
                 0,
                 0x1909,
@@ -61,63 +83,232 @@ pub fn rasterize_font(font: &Psf2Font) -> RasterizedFont {
             b.parameter_i(gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
             b.parameter_i(gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
         });
+        let mut atlas = TextureAtlas {
+            texture,
+            char_width,
+            char_height,
+        };
+        RasterizedFont {
+            texture,
+            texture_width,
+            texture_height,
+            char_dim,
+            texel_dim,
+            char_width,
+            char_height,
+            cache: GlyphCache::new(layout, font, &mut atlas),
+        }
+    }
+    /// Smooth (bilinear) filtering, for when glyphs are drawn at a size that isn't a whole number of texels. Otherwise they're kept crisp.
+    pub fn set_smooth(&self, smooth: bool) {
+        let filter = if smooth { gl::LINEAR } else { gl::NEAREST };
+        self.texture.bind_then(gl::TEXTURE_2D, |b| {
+            b.parameter_i(gl::TEXTURE_MIN_FILTER, filter as i32);
+            b.parameter_i(gl::TEXTURE_MAG_FILTER, filter as i32);
+        });
     }
-    RasterizedFont {
-        textures,
-        texture_width,
-        texture_height,
-        char_dim,
+    /// Throws out the cached pieces of ligatures, once [`Shaper`] has numbered them again.
+    pub fn forget_ligatures(&mut self) {
+        self.cache.forget(LIGATURE_GLYPHS..);
+    }
+    /// Splits off the texture so it can be drawn into while the cache is borrowed.
+    pub fn split(&mut self) -> (&mut GlyphCache, TextureAtlas) {
+        let atlas = TextureAtlas {
+            texture: self.texture,
+            char_width: self.char_width,
+            char_height: self.char_height,
+        };
+        (&mut self.cache, atlas)
     }
 }
 
-struct ChunkIterator(usize);
-
-impl Iterator for ChunkIterator {
-    type Item = usize;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.0 {
-            0 => None,
-            n if n < 256 => {
-                self.0 = 0;
-                Some(n)
-            }
-            _ => {
-                self.0 -= 256;
-                Some(256)
-            }
+/// Colour emoji, in an RGBA texture of their own.
+pub struct RasterizedEmoji {
+    pub texture: Texture,
+    /// Size of a single pixel in UV coordinates.
+    pub texel_dim: glam::Vec2,
+    pub atlas: EmojiAtlas,
+    /// The atlas as drawn, before [`EmojiColors`], so it can be recoloured without drawing it again.
+    pixels: Vec<u8>,
+    /// What the texture was last recoloured with.
+    colors: EmojiColors,
+    /// The atlas rounded up to powers of two.
+    texture_size: (usize, usize),
+}
+
+impl Drop for RasterizedEmoji {
+    fn drop(&mut self) {
+        [self.texture].delete_textures();
+    }
+}
+
+impl RasterizedEmoji {
+    /// Every emoji in `set`, drawn for cells `cell_size` pixels big.
+    pub fn new(set: &EmojiSet, cell_size: (usize, usize)) -> RasterizedEmoji {
+        let atlas = EmojiAtlas::new(set, cell_size);
+        let (atlas_width, atlas_height) = atlas.layout.size();
+        let texture_width = atlas_width.next_power_of_two();
+        let texture_height = atlas_height.next_power_of_two();
+        let mut textures = [Texture::default()];
+        textures.gen_textures();
+        let [texture] = textures;
+        texture.bind_then(gl::TEXTURE_2D, |b| {
+            b.parameter_i(gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            b.parameter_i(gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+        });
+        let emoji = RasterizedEmoji {
+            texture,
+            texel_dim: glam::Vec2::ONE / glam::vec2(texture_width as f32, texture_height as f32),
+            pixels: atlas.pixels(set),
+            atlas,
+            colors: EmojiColors::default(),
+            texture_size: (texture_width, texture_height),
+        };
+        emoji.upload(&emoji.pixels);
+        emoji
+    }
+    /// Redoes the texture if `colors` changed since last time.
+    pub fn recolor(&mut self, colors: EmojiColors) {
+        if colors != self.colors {
+            self.upload(&colors.apply(&self.pixels));
+            self.colors = colors;
         }
     }
+    /// `pixels` are laid out at the atlas size, then padded out to the texture size.
+    fn upload(&self, pixels: &[u8]) {
+        let (atlas_width, _) = self.atlas.layout.size();
+        let (texture_width, texture_height) = self.texture_size;
+        let mut tex_data = vec![0u8; texture_width * texture_height * 4];
+        for (row, line) in pixels.chunks_exact(atlas_width * 4).enumerate() {
+            let at = row * texture_width * 4;
+            tex_data[at..at + line.len()].copy_from_slice(line);
+        }
+        self.texture.bind_then(gl::TEXTURE_2D, |b| {
+            b.image_2d(
+                0,
+                gl::RGBA as _,
+                texture_width as i32,
+                texture_height as i32,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                tex_data.as_ptr() as _,
+            );
+        });
+    }
+    /// See [`RasterizedFont::set_smooth`].
+    pub fn set_smooth(&self, smooth: bool) {
+        let filter = if smooth { gl::LINEAR } else { gl::NEAREST };
+        self.texture.bind_then(gl::TEXTURE_2D, |b| {
+            b.parameter_i(gl::TEXTURE_MIN_FILTER, filter as i32);
+            b.parameter_i(gl::TEXTURE_MAG_FILTER, filter as i32);
+        });
+    }
 }
 
-fn rasterize_char(
-    target_array: &mut [u8],
-    target_row_len: usize,
-    target_uv: usize,
-    data: &[u8],
+/// Draws straight into the atlas texture.
+pub struct TextureAtlas {
+    texture: Texture,
     char_width: usize,
-) {
-    let mut ptr =
-        ((target_uv >> 4) * target_row_len * data.len()) + ((target_uv & 0xF) * char_width);
-    let stride = target_row_len - char_width;
-    let mut char_width_iterator = 0;
-    for &datum in data.into_iter() {
-        let datum = datum.reverse_bits();
-        for bit_n in 0..8 {
-            let the_bool = ((datum >> bit_n) & 1) != 0;
-            let the_byte = if the_bool { 0xFF } else { 0x00 };
-            target_array[ptr] = the_byte;
-            ptr += 1;
-            char_width_iterator += 1;
-            if char_width_iterator == char_width {
-                ptr += stride;
-                char_width_iterator = 0;
-                break;
-            }
+    char_height: usize,
+}
+
+impl AtlasTarget for TextureAtlas {
+    fn put(&mut self, origin: [u16; 2], pixels: &[u8]) {
+        let [x, y] = origin.map(|x| x as i32);
+        self.texture.bind(gl::TEXTURE_2D);
+        unsafe {
+            //Rows of a glyph aren't padded out to 4 bytes
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::TexSubImage2D(
+                gl::TEXTURE_2D,
+                0,
+                x,
+                y,
+                self.char_width as i32,
+                self.char_height as i32,
+                0x1909u32,
+                gl::UNSIGNED_BYTE,
+                pixels.as_ptr() as _,
+            );
         }
     }
 }
 
+/// `font`, but with box drawing, block elements and braille drawn to fit the cell exactly instead of taken from the font,
+/// so lines join up across cells and shading tiles without seams. See [`box_drawing`].
+///
+/// Powerline separators and common Nerd Font icons are drawn too, but only if the font doesn't have its own. See [`symbols`].
+pub struct WithDrawnGlyphs<'a, F: Font + ?Sized>(pub &'a F);
+
+impl<F: Font + ?Sized> Font for WithDrawnGlyphs<'_, F> {
+    fn dimensions(&self) -> (usize, usize) {
+        self.0.dimensions()
+    }
+    fn glyph_count(&self) -> usize {
+        self.0.glyph_count()
+    }
+    fn glyph_index(&self, c: char) -> Option<usize> {
+        if box_drawing::covers(c) {
+            return Some(DRAWN_GLYPHS + c as usize);
+        }
+        self.0
+            .glyph_index(c)
+            .or_else(|| symbols::covers(c).then_some(DRAWN_GLYPHS + c as usize))
+    }
+    fn glyph(&self, index: usize) -> Option<Glyph<'_>> {
+        let Some(c) = index.checked_sub(DRAWN_GLYPHS) else {
+            return self.0.glyph(index);
+        };
+        let (width, height) = self.dimensions();
+        let c = char::from_u32(c as u32)?;
+        box_drawing::draw(c, width, height)
+            .or_else(|| symbols::draw(c, width, height))
+            .map(Glyph::Coverage)
+    }
+}
+
+/// The pieces `shaper` cut ligatures into, drawn with `font`. Pieces are asked for by number, see [`LigaturePieces::glyph_number`].
+pub struct LigaturePieces<'a> {
+    pub shaper: &'a Shaper,
+    pub font: &'a OutlineFont,
+}
+
+impl LigaturePieces<'_> {
+    /// Which glyph to look up for piece `piece`, clear of the font's own glyphs and the ones we draw.
+    pub fn glyph_number(piece: usize) -> usize {
+        LIGATURE_GLYPHS + piece
+    }
+}
+
+impl Font for LigaturePieces<'_> {
+    fn dimensions(&self) -> (usize, usize) {
+        self.font.dimensions()
+    }
+    fn glyph_count(&self) -> usize {
+        self.font.glyph_count()
+    }
+    fn glyph_index(&self, _c: char) -> Option<usize> {
+        None
+    }
+    fn glyph(&self, index: usize) -> Option<Glyph<'_>> {
+        let piece = index.checked_sub(LIGATURE_GLYPHS)?;
+        self.shaper
+            .draw_piece(self.font, piece)
+            .map(Glyph::Coverage)
+    }
+}
+
+/// Fills the atlas with as many glyphs as fit up front, for things that don't go through the cache.
+pub fn rasterize_font(font: &(impl Font + ?Sized)) -> RasterizedFont {
+    let mut rasterized_font = RasterizedFont::new(font);
+    let (cache, mut atlas) = rasterized_font.split();
+    cache.begin_frame();
+    for glyph_index in 0..cache.layout().capacity {
+        cache.lookup(glyph_index, font, &mut atlas);
+    }
+    rasterized_font
+}
+
 #[allow(dead_code)]
 fn dump_texture(tex: &[u8], width: usize) {
     for line in tex.chunks(width) {
@@ -127,3 +318,64 @@ fn dump_texture(tex: &[u8], width: usize) {
         println!("");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glyph_cache::CpuAtlas;
+
+    /// A 256 glyph bitmap font, like most PSF1 console fonts.
+    struct Console;
+
+    impl Font for Console {
+        fn dimensions(&self) -> (usize, usize) {
+            (8, 16)
+        }
+        fn glyph_count(&self) -> usize {
+            256
+        }
+        fn glyph_index(&self, c: char) -> Option<usize> {
+            Some(c as usize).filter(|&i| i < 256)
+        }
+        fn glyph(&self, index: usize) -> Option<Glyph<'_>> {
+            Some(Glyph::Coverage(vec![index as u8; 8 * 16]))
+        }
+    }
+
+    #[test]
+    fn drawn_glyphs_are_counted_right() {
+        let covered = |covers: fn(char) -> bool| {
+            (0..=char::MAX as u32)
+                .filter_map(char::from_u32)
+                .filter(|&c| covers(c))
+                .count()
+        };
+        assert_eq!(covered(box_drawing::covers), box_drawing::COVERED);
+        assert_eq!(covered(symbols::covers), symbols::COVERED);
+    }
+
+    #[test]
+    fn a_small_font_has_room_for_everything_drawn() {
+        //A braille graph, its box drawn border, and the whole font besides, all on screen at once
+        let font = WithDrawnGlyphs(&Console);
+        let layout = AtlasLayout::new(RasterizedFont::slots_for(&Console), (8, 16));
+        let mut atlas = CpuAtlas::new(&layout);
+        let mut cache = GlyphCache::new(layout, &font, &mut atlas);
+        cache.begin_frame();
+        let zero = cache.lookup(0, &font, &mut atlas);
+        let chars = ('\u{0}'..='\u{FF}')
+            .chain('\u{2800}'..='\u{28FF}')
+            .chain('\u{2500}'..='\u{257F}');
+        let mut origins = std::collections::HashSet::new();
+        for c in chars {
+            let glyph = font.glyph_index(c).unwrap();
+            let origin = cache.lookup(glyph, &font, &mut atlas);
+            assert!(
+                glyph == 0 || origin != zero,
+                "{c:?} was drawn as glyph zero"
+            );
+            origins.insert(origin);
+        }
+        assert_eq!(origins.len(), 256 + 256 + 128);
+    }
+}
@@ -0,0 +1,556 @@
+use std::{collections::HashMap, ops::RangeBounds};
+
+use crate::font::{ATLAS_PADDING, AtlasLayout, Font, Glyph};
+
+/// Somewhere glyphs can be drawn into, one cell at a time. The GPU atlas is one, [`CpuAtlas`] is another for checking the cache without a GPU.
+pub trait AtlasTarget {
+    /// `pixels` is a whole cell of coverage, rows top to bottom, with its top left corner to go at pixel `origin`.
+    fn put(&mut self, origin: [u16; 2], pixels: &[u8]);
+}
+
+/// An atlas in plain memory, one byte per pixel.
+pub struct CpuAtlas {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+    char_width: usize,
+}
+
+impl CpuAtlas {
+    pub fn new(layout: &AtlasLayout) -> CpuAtlas {
+        let (width, height) = layout.size();
+        CpuAtlas {
+            width,
+            height,
+            pixels: vec![0; width * height],
+            char_width: layout.slot_width - ATLAS_PADDING,
+        }
+    }
+}
+
+impl AtlasTarget for CpuAtlas {
+    fn put(&mut self, origin: [u16; 2], pixels: &[u8]) {
+        let [x, y] = origin.map(|x| x as usize);
+        let origin = (y * self.width) + x;
+        for (y, row) in pixels.chunks_exact(self.char_width).enumerate() {
+            let start = origin + y * self.width;
+            self.pixels[start..start + self.char_width].copy_from_slice(row);
+        }
+    }
+}
+
+/// How well the cache is doing, since it was made.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl std::ops::Add for CacheStats {
+    type Output = CacheStats;
+    fn add(self, other: CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            evictions: self.evictions + other.evictions,
+        }
+    }
+}
+
+impl CacheStats {
+    /// Out of 1.0, or 1.0 if nothing has been looked up yet.
+    pub fn hit_rate(&self) -> f32 {
+        match self.hits + self.misses {
+            0 => 1.0,
+            total => self.hits as f32 / total as f32,
+        }
+    }
+}
+
+/// Keeps the glyphs that are actually on screen in the atlas, drawing each one the first time it's needed.
+///
+/// When the atlas is full, the glyph that's gone longest without being drawn makes way.
+/// Glyphs drawn this frame are never evicted, so if a single screen needs more glyphs than there are slots, the rest show as glyph zero.
+/// That's why glyph zero is drawn into the first slot up front, and never leaves.
+pub struct GlyphCache {
+    layout: AtlasLayout,
+    char_width: usize,
+    char_height: usize,
+    /// Slot each cached glyph is in.
+    slots: HashMap<usize, usize>,
+    /// Glyph in each slot, and the frame it was last used in.
+    slot_glyphs: Vec<Option<(usize, u64)>>,
+    frame: u64,
+    /// Slots below this have been filled at some point.
+    next_free: usize,
+    pub stats: CacheStats,
+    scratch: Vec<u8>,
+}
+
+impl GlyphCache {
+    /// Draws glyph zero of `font` into `atlas` straight away.
+    pub fn new(
+        layout: AtlasLayout,
+        font: &(impl Font + ?Sized),
+        atlas: &mut impl AtlasTarget,
+    ) -> GlyphCache {
+        let (char_width, char_height) = font.dimensions();
+        let mut cache = GlyphCache {
+            layout,
+            char_width,
+            char_height,
+            slots: HashMap::new(),
+            slot_glyphs: vec![None; layout.capacity],
+            frame: 0,
+            next_free: 0,
+            stats: CacheStats::default(),
+            scratch: vec![0; char_width * char_height],
+        };
+        cache.lookup(0, font, atlas);
+        cache.stats = CacheStats::default();
+        cache
+    }
+    pub fn layout(&self) -> &AtlasLayout {
+        &self.layout
+    }
+    /// Call before looking up a frame's worth of glyphs.
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+    }
+    /// The top left pixel of `glyph` in the atlas, drawing it there first if it isn't already.
+    pub fn lookup(
+        &mut self,
+        glyph: usize,
+        font: &(impl Font + ?Sized),
+        atlas: &mut impl AtlasTarget,
+    ) -> [u16; 2] {
+        if let Some(&slot) = self.slots.get(&glyph) {
+            self.stats.hits += 1;
+            self.slot_glyphs[slot] = Some((glyph, self.frame));
+            return self.position(slot);
+        }
+        self.stats.misses += 1;
+        let Some(slot) = self.free_slot() else {
+            //Glyph zero, better than nothing
+            return self.position(0);
+        };
+        if let Some((evicted, _)) = self.slot_glyphs[slot] {
+            self.slots.remove(&evicted);
+            self.stats.evictions += 1;
+        }
+        self.scratch.fill(0);
+        if let Some(data) = font.glyph(glyph) {
+            glyph_pixels(data, &mut self.scratch, self.char_width, self.char_height);
+        }
+        atlas.put(self.position(slot), &self.scratch);
+        self.slots.insert(glyph, slot);
+        self.slot_glyphs[slot] = Some((glyph, self.frame));
+        self.position(slot)
+    }
+    /// Throws out every cached glyph numbered in `glyphs`, for when those numbers are about to mean something else.
+    /// Their slots are the first to be reused.
+    pub fn forget(&mut self, glyphs: impl RangeBounds<usize>) {
+        for entry in &mut self.slot_glyphs[1..] {
+            if let Some((glyph, _)) = *entry
+                && glyphs.contains(&glyph)
+            {
+                self.slots.remove(&glyph);
+                *entry = None;
+            }
+        }
+    }
+    /// An empty slot, or else the least recently used one that isn't on screen this frame.
+    fn free_slot(&mut self) -> Option<usize> {
+        if self.next_free < self.slot_glyphs.len() {
+            self.next_free += 1;
+            return Some(self.next_free - 1);
+        }
+        //A scan is fine, misses are rare once the screen has settled
+        self.slot_glyphs
+            .iter()
+            .enumerate()
+            //Glyph zero's slot
+            .skip(1)
+            //Forgotten slots come first, being `None`
+            .map(|(slot, entry)| (slot, entry.map(|(_, used)| used)))
+            .filter(|&(_, used)| used != Some(self.frame))
+            .min_by_key(|&(_, used)| used)
+            .map(|(slot, _)| slot)
+    }
+    fn position(&self, slot: usize) -> [u16; 2] {
+        self.layout
+            .origin(slot)
+            .expect("Somehow, got a slot out of the atlas")
+    }
+}
+
+/// Turns a glyph into a cell's worth of coverage, one byte per pixel.
+pub fn glyph_pixels(glyph: Glyph, target: &mut [u8], char_width: usize, char_height: usize) {
+    match glyph {
+        Glyph::Bitmap(data) => rasterize_char(target, data, char_width),
+        Glyph::Coverage(coverage) => {
+            let len = (char_width * char_height).min(coverage.len());
+            target[..len].copy_from_slice(&coverage[..len]);
+        }
+    }
+}
+
+/// Rows are `char_width.div_ceil(8)` bytes each, however wide the font is, with any spare bits on the right ignored.
+fn rasterize_char(target_array: &mut [u8], data: &[u8], char_width: usize) {
+    let row_len = char_width.div_ceil(8);
+    for (row, target_row) in data
+        .chunks_exact(row_len)
+        .zip(target_array.chunks_exact_mut(char_width))
+    {
+        for (x, pixel) in target_row.iter_mut().enumerate() {
+            let the_bool = row[x / 8] & (0x80 >> (x % 8)) != 0;
+            *pixel = if the_bool { 0xFF } else { 0x00 };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every glyph is a 2 by 2 block of its own number.
+    struct Numbers;
+
+    impl Font for Numbers {
+        fn dimensions(&self) -> (usize, usize) {
+            (2, 2)
+        }
+        fn glyph_count(&self) -> usize {
+            256
+        }
+        fn glyph_index(&self, c: char) -> Option<usize> {
+            Some(c as usize)
+        }
+        fn glyph(&self, index: usize) -> Option<Glyph<'_>> {
+            Some(Glyph::Coverage(vec![index as u8; 4]))
+        }
+    }
+
+    /// A cache with room for glyph zero and `slots - 1` others.
+    fn cache(slots: usize) -> (GlyphCache, CpuAtlas) {
+        let layout = AtlasLayout::new(slots, (2, 2));
+        let mut atlas = CpuAtlas::new(&layout);
+        let cache = GlyphCache::new(layout, &Numbers, &mut atlas);
+        (cache, atlas)
+    }
+
+    fn drawn_at(atlas: &CpuAtlas, [x, y]: [u16; 2]) -> u8 {
+        atlas.pixels[y as usize * atlas.width + x as usize]
+    }
+
+    #[test]
+    fn a_miss_then_a_hit() {
+        let (mut cache, mut atlas) = cache(4);
+        cache.begin_frame();
+        let origin = cache.lookup(5, &Numbers, &mut atlas);
+        assert_eq!(drawn_at(&atlas, origin), 5);
+        assert_eq!((cache.stats.hits, cache.stats.misses), (0, 1));
+        assert_eq!(cache.lookup(5, &Numbers, &mut atlas), origin);
+        assert_eq!((cache.stats.hits, cache.stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn the_least_recently_used_glyph_goes_first() {
+        let (mut cache, mut atlas) = cache(3);
+        cache.begin_frame();
+        let one = cache.lookup(1, &Numbers, &mut atlas);
+        let two = cache.lookup(2, &Numbers, &mut atlas);
+        cache.begin_frame();
+        cache.lookup(1, &Numbers, &mut atlas);
+        cache.begin_frame();
+        assert_eq!(cache.lookup(3, &Numbers, &mut atlas), two);
+        assert_eq!(drawn_at(&atlas, two), 3);
+        assert_eq!(cache.stats.evictions, 1);
+        assert_eq!(cache.lookup(1, &Numbers, &mut atlas), one);
+        assert_eq!(cache.stats.hits, 2);
+    }
+
+    #[test]
+    fn glyphs_on_screen_are_never_evicted() {
+        let (mut cache, mut atlas) = cache(3);
+        cache.begin_frame();
+        let one = cache.lookup(1, &Numbers, &mut atlas);
+        let two = cache.lookup(2, &Numbers, &mut atlas);
+        //No room, so it's drawn as glyph zero
+        let three = cache.lookup(3, &Numbers, &mut atlas);
+        assert_eq!(drawn_at(&atlas, three), 0);
+        assert_eq!(three, AtlasLayout::new(3, (2, 2)).origin(0).unwrap());
+        assert_eq!(cache.stats.evictions, 0);
+        assert_eq!(drawn_at(&atlas, one), 1);
+        assert_eq!(drawn_at(&atlas, two), 2);
+    }
+
+    #[test]
+    fn glyph_zero_is_never_evicted() {
+        let (mut cache, mut atlas) = cache(2);
+        let zero = AtlasLayout::new(2, (2, 2)).origin(0).unwrap();
+        //Zero has gone longest without being used, but it stays
+        for glyph in 1..10 {
+            cache.begin_frame();
+            cache.lookup(glyph, &Numbers, &mut atlas);
+        }
+        assert_eq!(cache.stats.evictions, 8);
+        assert_eq!(cache.lookup(0, &Numbers, &mut atlas), zero);
+        assert_eq!(cache.stats.misses, 9);
+        //Even while the only other slot is on screen
+        assert_eq!(cache.lookup(10, &Numbers, &mut atlas), zero);
+        assert_eq!(drawn_at(&atlas, zero), 0);
+    }
+
+    #[test]
+    fn forgotten_glyphs_make_way_first() {
+        let (mut cache, mut atlas) = cache(4);
+        cache.begin_frame();
+        let one = cache.lookup(1, &Numbers, &mut atlas);
+        let two = cache.lookup(200, &Numbers, &mut atlas);
+        let three = cache.lookup(201, &Numbers, &mut atlas);
+        cache.begin_frame();
+        cache.forget(200..);
+        //Glyph one has gone longest without being used, but the forgotten slots are free
+        assert_eq!(cache.lookup(2, &Numbers, &mut atlas), two);
+        assert_eq!(cache.lookup(3, &Numbers, &mut atlas), three);
+        assert_eq!(cache.stats.evictions, 0);
+        //And forgotten glyphs are drawn again when they come back
+        cache.begin_frame();
+        assert_eq!(cache.lookup(200, &Numbers, &mut atlas), one);
+        assert_eq!(drawn_at(&atlas, one), 200);
+        assert_eq!(cache.stats.hits, 0);
+    }
+
+    #[test]
+    fn hit_rate() {
+        assert_eq!(CacheStats::default().hit_rate(), 1.0);
+        let stats = CacheStats {
+            hits: 3,
+            misses: 1,
+            evictions: 0,
+        };
+        assert_eq!(stats.hit_rate(), 0.75);
+        assert_eq!((stats + stats).hit_rate(), 0.75);
+    }
+
+    /// A PSF2 font of `count` glyphs, each a different jumble of bits, with the spare bits at the end of each row set to catch anything that draws them.
+    fn psf2(width: usize, height: usize, count: usize) -> Vec<u8> {
+        let row_len = width.div_ceil(8);
+        let glyph_len = row_len * height;
+        let mut data = vec![0x72, 0xB5, 0x4A, 0x86];
+        for field in [0, 32, 0, count, glyph_len, height, width] {
+            data.extend((field as u32).to_le_bytes());
+        }
+        let mut seed = (width * height) as u32;
+        let spare = match width % 8 {
+            0 => 0,
+            used => 0xFFu8 >> used,
+        };
+        for _ in 0..count * height {
+            for byte in 0..row_len {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let bits = (seed >> 16) as u8;
+                data.push(if byte == row_len - 1 {
+                    bits | spare
+                } else {
+                    bits
+                });
+            }
+        }
+        data
+    }
+
+    /// Draws every glyph of a `width` by `height` font through the cache, and checks the atlas against `src/testdata`.
+    ///
+    /// Run with `BLESS=1` to write the expected atlases again.
+    fn golden(width: usize, height: usize) {
+        let font = crate::psf2::Psf2Font::parse(&psf2(width, height, 12)).unwrap();
+        let layout = AtlasLayout::new(font.glyph_count(), font.dimensions());
+        let mut atlas = CpuAtlas::new(&layout);
+        let mut cache = GlyphCache::new(layout, &font, &mut atlas);
+        cache.begin_frame();
+        //Backwards, so slots don't just follow glyph numbers
+        for glyph in (0..12).rev() {
+            cache.lookup(glyph, &font, &mut atlas);
+        }
+        let mut pgm = format!("P5\n{} {}\n255\n", atlas.width, atlas.height).into_bytes();
+        pgm.extend(&atlas.pixels);
+        let path = format!(
+            "{}/src/testdata/atlas_{width}x{height}.pgm",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        if std::env::var_os("BLESS").is_some() {
+            std::fs::write(&path, &pgm).unwrap();
+        }
+        let expected = std::fs::read(&path).unwrap();
+        assert!(pgm == expected, "{path} doesn't match");
+    }
+
+    /// Draws glyph 1 of a two glyph `width` by `height` font made from `rows`, and checks it came out as `expected` in the second slot,
+    /// with nothing anywhere else. Unlike the golden atlases, these can be checked by eye.
+    fn known_glyph(width: usize, height: usize, rows: &[u8], expected: &[&str]) {
+        let row_len = width.div_ceil(8);
+        let mut data = vec![0x72, 0xB5, 0x4A, 0x86];
+        for field in [0, 32, 0, 2, row_len * height, height, width] {
+            data.extend((field as u32).to_le_bytes());
+        }
+        data.extend(vec![0; row_len * height]);
+        data.extend(rows);
+        let font = crate::psf2::Psf2Font::parse(&data).unwrap();
+        let layout = AtlasLayout::new(2, (width, height));
+        let mut atlas = CpuAtlas::new(&layout);
+        let mut cache = GlyphCache::new(layout, &font, &mut atlas);
+        cache.begin_frame();
+        //Past glyph zero's slot and the padding either side of it
+        let origin = [width as u16 + 2, 1];
+        assert_eq!(cache.lookup(1, &font, &mut atlas), origin);
+        let [x, y] = origin.map(|x| x as usize);
+        let mut drawn = vec![];
+        for row in atlas.pixels.chunks_exact(atlas.width).skip(y).take(height) {
+            let row: String = row[x..x + width]
+                .iter()
+                .map(|&p| match p {
+                    0x00 => '.',
+                    0xFF => '#',
+                    _ => '?',
+                })
+                .collect();
+            drawn.push(row);
+        }
+        assert_eq!(drawn, expected);
+        let set = atlas.pixels.iter().filter(|&&p| p != 0).count();
+        let expected_set: usize = expected.iter().map(|row| row.matches('#').count()).sum();
+        assert_eq!(set, expected_set, "Something was drawn outside the glyph");
+    }
+
+    #[test]
+    fn known_glyph_8x16() {
+        let rows = [
+            0x00, 0x18, 0x24, 0x42, 0x42, 0x7E, 0x42, 0x42, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        #[rustfmt::skip]
+        let expected = [
+            "........",
+            "...##...",
+            "..#..#..",
+            ".#....#.",
+            ".#....#.",
+            ".######.",
+            ".#....#.",
+            ".#....#.",
+            ".#....#.",
+            "........",
+            "........",
+            "........",
+            "........",
+            "........",
+            "........",
+            "........",
+        ];
+        known_glyph(8, 16, &rows, &expected);
+    }
+
+    #[test]
+    fn known_glyph_12x24() {
+        //A frame, with the four spare bits at the end of every row set, which mustn't show
+        let mut rows = vec![];
+        rows.extend([0xFF, 0xFF]);
+        for _ in 1..23 {
+            rows.extend([0x80, 0x1F]);
+        }
+        rows.extend([0xFF, 0xFF]);
+        #[rustfmt::skip]
+        let expected = [
+            "############",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "#..........#",
+            "############",
+        ];
+        known_glyph(12, 24, &rows, &expected);
+    }
+
+    #[test]
+    fn known_glyph_16x32() {
+        //A diagonal, one pixel right every two rows, crossing from the first byte of each row into the second
+        let mut rows = vec![];
+        for y in 0..32 {
+            let x = y / 2;
+            let bit = 0x8000u16 >> x;
+            rows.extend(bit.to_be_bytes());
+        }
+        #[rustfmt::skip]
+        let expected = [
+            "#...............",
+            "#...............",
+            ".#..............",
+            ".#..............",
+            "..#.............",
+            "..#.............",
+            "...#............",
+            "...#............",
+            "....#...........",
+            "....#...........",
+            ".....#..........",
+            ".....#..........",
+            "......#.........",
+            "......#.........",
+            ".......#........",
+            ".......#........",
+            "........#.......",
+            "........#.......",
+            ".........#......",
+            ".........#......",
+            "..........#.....",
+            "..........#.....",
+            "...........#....",
+            "...........#....",
+            "............#...",
+            "............#...",
+            ".............#..",
+            ".............#..",
+            "..............#.",
+            "..............#.",
+            "...............#",
+            "...............#",
+        ];
+        known_glyph(16, 32, &rows, &expected);
+    }
+
+    #[test]
+    fn golden_8x16() {
+        golden(8, 16);
+    }
+
+    #[test]
+    fn golden_12x24() {
+        golden(12, 24);
+    }
+
+    #[test]
+    fn golden_16x32() {
+        golden(16, 32);
+    }
+}
@@ -0,0 +1,304 @@
+use std::{ops::Range, rc::Rc};
+
+/// A decoded image, 4 bytes per pixel, RGBA, rows top to bottom.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgba: Vec<u8>,
+}
+
+/// Why a PNG couldn't be decoded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PngError {
+    /// Not a PNG, or too big.
+    Header,
+    /// The pixel data is broken or cut short.
+    Data,
+    /// Still palettized after expanding, which shouldn't happen.
+    Indexed,
+}
+
+/// Any PNG, as 8 bit RGBA, refusing to decode more than `limit` bytes.
+pub fn decode_png(raw: &[u8], limit: usize) -> Result<Image, PngError> {
+    let mut decoder = png::Decoder::new_with_limits(raw, png::Limits { bytes: limit });
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|_| PngError::Header)?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|_| PngError::Data)?;
+    let buf = &buf[..info.buffer_size()];
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => buf.to_vec(),
+        png::ColorType::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 0xFF])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&g| [g, g, g, 0xFF]).collect(),
+        png::ColorType::Indexed => return Err(PngError::Indexed),
+    };
+    Ok(Image {
+        width: info.width as usize,
+        height: info.height as usize,
+        rgba,
+    })
+}
+
+/// An image stuck to the cell grid. Everything is in cells, not pixels.
+pub struct Placement {
+    /// Placements sharing an image share a key, and so share a texture.
+    pub image_key: u64,
+    pub image: Rc<Image>,
+    /// Goes negative as the placement scrolls off the top.
+    pub row: isize,
+    pub col: usize,
+    pub width: f32,
+    pub height: f32,
+    /// Nudges the image away from the top left of its cell, in cells.
+    pub offset: [f32; 2],
+    /// The part of the image to show, as `[x, y, width, height]` in `0.0..=1.0`.
+    pub uv_rect: [f32; 4],
+    /// Negative goes under the text, zero and up goes over it.
+    pub z: i32,
+    /// Image id and placement id, for placements made with the kitty graphics protocol.
+    pub kitty_id: Option<(u32, u32)>,
+    /// Which screen this was placed on, so the alternate screen doesn't show the main screen's images or vice versa.
+    pub alternate: bool,
+}
+
+/// The part of a placement that's inside the grid, ready to be turned into a quad.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ClippedQuad {
+    pub pos: [f32; 2],
+    pub size: [f32; 2],
+    pub uv_pos: [f32; 2],
+    pub uv_size: [f32; 2],
+}
+
+impl Placement {
+    pub fn clip(&self, rows: usize, cols: usize) -> Option<ClippedQuad> {
+        let x0 = self.col as f32 + self.offset[0];
+        let y0 = self.row as f32 + self.offset[1];
+        let (x1, y1) = (x0 + self.width, y0 + self.height);
+        let (cx0, cy0) = (x0.max(0.0), y0.max(0.0));
+        let (cx1, cy1) = (x1.min(cols as f32), y1.min(rows as f32));
+        if cx0 >= cx1 || cy0 >= cy1 {
+            return None;
+        }
+        let [u, v, uw, vh] = self.uv_rect;
+        Some(ClippedQuad {
+            pos: [cx0, cy0],
+            size: [cx1 - cx0, cy1 - cy0],
+            uv_pos: [
+                u + uw * (cx0 - x0) / self.width,
+                v + vh * (cy0 - y0) / self.height,
+            ],
+            uv_size: [
+                uw * (cx1 - cx0) / self.width,
+                vh * (cy1 - cy0) / self.height,
+            ],
+        })
+    }
+}
+
+/// Keeps track of where images are on the grid, and moves them when the text scrolls.
+///
+/// Knows nothing about GL, `ImageRender` does the drawing.
+pub struct ImageLayer {
+    placements: Vec<Placement>,
+    next_key: u64,
+    cell_width: usize,
+    cell_height: usize,
+    alternate: bool,
+}
+
+impl ImageLayer {
+    /// The cell size (in pixels) decides how many cells an image covers.
+    pub fn new(cell_width: usize, cell_height: usize) -> ImageLayer {
+        ImageLayer {
+            placements: Vec::new(),
+            next_key: 0,
+            cell_width,
+            cell_height,
+            alternate: false,
+        }
+    }
+    pub fn cell_size(&self) -> (usize, usize) {
+        (self.cell_width, self.cell_height)
+    }
+    /// Images keep their size in pixels, so everything already placed grows or shrinks in cells.
+    pub fn set_cell_size(&mut self, cell_width: usize, cell_height: usize) {
+        let x_scale = self.cell_width as f32 / cell_width as f32;
+        let y_scale = self.cell_height as f32 / cell_height as f32;
+        for p in &mut self.placements {
+            p.width *= x_scale;
+            p.height *= y_scale;
+            p.offset[0] *= x_scale;
+            p.offset[1] *= y_scale;
+        }
+        self.cell_width = cell_width;
+        self.cell_height = cell_height;
+    }
+    pub fn new_image_key(&mut self) -> u64 {
+        self.next_key += 1;
+        self.next_key
+    }
+    /// Places an image at its natural size, under the text, with its top left corner at `row`, `col`. Returns how many rows it covers.
+    pub fn place(&mut self, image: Rc<Image>, image_key: u64, row: u16, col: u16) -> usize {
+        let width = image.width as f32 / self.cell_width as f32;
+        let height = image.height as f32 / self.cell_height as f32;
+        self.add(Placement {
+            image_key,
+            image,
+            row: row as isize,
+            col: col as usize,
+            width,
+            height,
+            offset: [0.0; 2],
+            uv_rect: [0.0, 0.0, 1.0, 1.0],
+            z: -1,
+            kitty_id: None,
+            alternate: self.alternate,
+        });
+        height.ceil() as usize
+    }
+    /// Adds a placement to the current screen.
+    pub fn add(&mut self, placement: Placement) {
+        self.placements.push(Placement {
+            alternate: self.alternate,
+            ..placement
+        });
+    }
+    /// Like [`Vec::retain`], but only looks at the current screen.
+    pub fn retain(&mut self, mut f: impl FnMut(&Placement) -> bool) {
+        let alternate = self.alternate;
+        self.placements.retain(|p| p.alternate != alternate || f(p));
+    }
+    /// Whether anything on either screen still shows an image.
+    pub fn has_placements(&self, image_key: u64) -> bool {
+        self.placements.iter().any(|p| p.image_key == image_key)
+    }
+    /// Forgets every placement of an image, on both screens, for when its data is thrown away.
+    pub fn remove_image(&mut self, image_key: u64) {
+        self.placements.retain(|p| p.image_key != image_key);
+    }
+    /// Moves everything on the current screen with its top in `rows` up by `n` rows, forgetting anything that leaves them.
+    ///
+    /// When `rows` starts at the top of the screen, images go once they're all the way off it, the same as the text does.
+    /// Otherwise they go as soon as their top row leaves, as the text above the margin doesn't move.
+    pub fn scroll_up(&mut self, rows: Range<usize>, n: usize) {
+        let alternate = self.alternate;
+        self.placements.retain_mut(|p| {
+            if p.alternate != alternate || !rows.contains(&(p.row.max(0) as usize)) {
+                return true;
+            }
+            p.row -= n as isize;
+            match rows.start {
+                0 => p.row as f32 + p.height > 0.0,
+                top => p.row >= top as isize,
+            }
+        });
+    }
+    /// Moves everything on the current screen with its top in `rows` down by `n` rows, forgetting anything that leaves them.
+    pub fn scroll_down(&mut self, rows: Range<usize>, n: usize) {
+        let alternate = self.alternate;
+        self.placements.retain_mut(|p| {
+            if p.alternate != alternate || !rows.contains(&(p.row.max(0) as usize)) {
+                return true;
+            }
+            p.row += n as isize;
+            p.row < rows.end as isize
+        });
+    }
+    /// Forgets everything on the current screen.
+    pub fn clear(&mut self) {
+        let alternate = self.alternate;
+        self.placements.retain(|p| p.alternate != alternate);
+    }
+    /// Call whenever the parser might have switched screens.
+    pub fn set_alternate(&mut self, alternate: bool) {
+        if self.alternate && !alternate {
+            //The alternate screen's contents are gone once we leave it
+            self.clear();
+        }
+        self.alternate = alternate;
+    }
+    pub fn visible(&self) -> impl Iterator<Item = &Placement> {
+        self.placements
+            .iter()
+            .filter(move |p| p.alternate == self.alternate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cells are 8 by 16, so this is 2 cells wide and 2.5 tall.
+    fn layer_with(rows: &[u16]) -> ImageLayer {
+        let mut layer = ImageLayer::new(8, 16);
+        for &row in rows {
+            let image = Rc::new(Image {
+                width: 16,
+                height: 40,
+                rgba: vec![0; 16 * 40 * 4],
+            });
+            let key = layer.new_image_key();
+            assert_eq!(layer.place(image, key, row, 0), 3);
+        }
+        layer
+    }
+
+    fn rows(layer: &ImageLayer) -> Vec<isize> {
+        layer.visible().map(|p| p.row).collect()
+    }
+
+    #[test]
+    fn scrolling_the_whole_screen_keeps_images_until_they_are_all_gone() {
+        let mut layer = layer_with(&[1]);
+        layer.scroll_up(0..24, 2);
+        assert_eq!(rows(&layer), [-1]);
+        layer.scroll_up(0..24, 2);
+        assert_eq!(rows(&layer), []);
+    }
+
+    #[test]
+    fn scrolling_a_region_only_moves_images_in_it() {
+        let mut layer = layer_with(&[2, 10]);
+        layer.scroll_up(5..20, 1);
+        assert_eq!(rows(&layer), [2, 9]);
+        //Gone as soon as its top leaves the region
+        layer.scroll_up(5..20, 5);
+        assert_eq!(rows(&layer), [2]);
+    }
+
+    #[test]
+    fn scrolling_down_drops_images_off_the_bottom() {
+        let mut layer = layer_with(&[0, 21]);
+        layer.scroll_down(0..24, 3);
+        assert_eq!(rows(&layer), [3]);
+    }
+
+    #[test]
+    fn the_alternate_screen_has_its_own_images() {
+        let mut layer = layer_with(&[0]);
+        layer.set_alternate(true);
+        assert_eq!(rows(&layer), []);
+        layer.scroll_up(0..24, 1);
+        layer.set_alternate(false);
+        assert_eq!(rows(&layer), [0]);
+    }
+
+    #[test]
+    fn clipping_off_the_top_moves_the_uvs() {
+        let mut layer = layer_with(&[0]);
+        layer.scroll_up(0..24, 1);
+        let quad = layer.visible().next().unwrap().clip(24, 80).unwrap();
+        assert_eq!(quad.pos, [0.0, 0.0]);
+        assert_eq!(quad.size, [2.0, 1.5]);
+        assert_eq!(quad.uv_pos, [0.0, 0.4]);
+        assert_eq!(quad.uv_size, [1.0, 0.6]);
+    }
+}
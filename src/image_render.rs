@@ -0,0 +1,170 @@
+use std::collections::{HashMap, HashSet};
+
+use vita_gl_helpers::{
+    draw::{Elements, ElementsU16, Mode},
+    program::{Program, link_program},
+    shader::{Shader, load_shader},
+    texture::{GenDelTexturesExt, Texture},
+    uniform_table,
+};
+
+use crate::{
+    cvd::CvdMode,
+    image_layer::{Image, ImageLayer},
+};
+
+uniform_table!(ImgUniformTable,
+  transform : UniformMatrix3fv => "transform",
+  rect_pos : Uniform2fv => "rect_pos",
+  rect_size : Uniform2fv => "rect_size",
+  uv_pos : Uniform2fv => "uv_pos",
+  uv_size : Uniform2fv => "uv_size",
+  the_texture : Uniform1iv => "the_texture"
+);
+
+/// Draws the placements in an [`ImageLayer`] as textured quads, in the same coordinate space as the tile grid.
+///
+/// Textures are uploaded the first time an image is seen, and deleted once nothing on screen uses them.
+/// The colour blindness mode is done to the pixels on the way up, like it is to the text.
+pub struct ImageRender {
+    program: Program,
+    vs: Shader,
+    fs: Shader,
+    unif_table: ImgUniformTable,
+    textures: HashMap<u64, Texture>,
+    /// What the textures were uploaded with.
+    cvd: CvdMode,
+}
+
+impl ImageRender {
+    pub fn new() -> Result<ImageRender, Box<dyn std::error::Error>> {
+        let vs = load_shader(include_str!("neo_tty_img.vert"), gl::VERTEX_SHADER)?;
+        let fs = load_shader(include_str!("neo_tty_img.frag"), gl::FRAGMENT_SHADER)?;
+        let program = link_program(vs, fs)?;
+        let unif_table = program.get_uniform_table()?;
+        Ok(ImageRender {
+            program,
+            vs,
+            fs,
+            unif_table,
+            textures: HashMap::new(),
+            cvd: CvdMode::Off,
+        })
+    }
+    fn upload(image: &Image, cvd: CvdMode) -> Texture {
+        let rgba = match cvd {
+            CvdMode::Off => None,
+            mode => Some(mode.apply_rgba(&image.rgba)),
+        };
+        let pixels = rgba.as_ref().unwrap_or(&image.rgba);
+        let mut textures = vec![Texture::default()];
+        textures.gen_textures();
+        let tex_gl = textures[0];
+        tex_gl.bind_then(gl::TEXTURE_2D, |b| {
+            b.image_2d(
+                0,
+                gl::RGBA as _,
+                image.width as i32,
+                image.height as i32,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                pixels.as_ptr() as _,
+            );
+            b.parameter_i(gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            b.parameter_i(gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            b.parameter_i(gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            b.parameter_i(gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+        });
+        tex_gl
+    }
+    /// Uploads textures for anything newly on screen and deletes the ones nothing uses any more. Call once a frame, before [`ImageRender::draw`].
+    ///
+    /// Changing `cvd` uploads everything again.
+    pub fn prepare(&mut self, layer: &ImageLayer, rows: usize, cols: usize, cvd: CvdMode) {
+        if cvd != self.cvd {
+            let mut textures: Vec<Texture> = self.textures.drain().map(|(_, t)| t).collect();
+            if !textures.is_empty() {
+                textures.delete_textures();
+            }
+            self.cvd = cvd;
+        }
+        let mut in_use = HashSet::new();
+        for p in layer.visible().filter(|p| p.clip(rows, cols).is_some()) {
+            in_use.insert(p.image_key);
+            self.textures
+                .entry(p.image_key)
+                .or_insert_with(|| Self::upload(&p.image, cvd));
+        }
+        let mut stale: Vec<Texture> = vec![];
+        self.textures.retain(|key, tex| {
+            let keep = in_use.contains(key);
+            if !keep {
+                stale.push(*tex);
+            }
+            keep
+        });
+        if !stale.is_empty() {
+            stale.delete_textures();
+        }
+    }
+    /// Draws either the placements that go under the text, or the ones that go over it.
+    ///
+    /// `row_offset` is how many grid rows sit above the terminal (the status bar).
+    pub fn draw(
+        &self,
+        layer: &ImageLayer,
+        rows: usize,
+        cols: usize,
+        row_offset: usize,
+        transform: [f32; 9],
+        under_text: bool,
+    ) {
+        let mut quads: Vec<_> = layer
+            .visible()
+            .filter(|p| (p.z < 0) == under_text && self.textures.contains_key(&p.image_key))
+            .filter_map(|p| Some((p, p.clip(rows, cols)?)))
+            .collect();
+        quads.sort_by_key(|(p, _)| p.z);
+        if quads.is_empty() {
+            return;
+        }
+        self.program.use_me();
+        self.unif_table.transform.set(transform, false);
+        self.unif_table.the_texture.set(0);
+        unsafe {
+            gl::Enable(gl::TEXTURE_2D);
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+        }
+        for (p, quad) in quads {
+            self.textures[&p.image_key].bind(gl::TEXTURE_2D);
+            self.unif_table
+                .rect_pos
+                .set([quad.pos[0], quad.pos[1] + row_offset as f32]);
+            self.unif_table.rect_size.set(quad.size);
+            self.unif_table.uv_pos.set(quad.uv_pos);
+            self.unif_table.uv_size.set(quad.uv_size);
+            ElementsU16 {
+                indices: &[0, 1, 3, 2],
+            }
+            .draw(Mode::Quads);
+        }
+        unsafe {
+            gl::Disable(gl::BLEND);
+            gl::Disable(gl::TEXTURE_2D);
+        }
+    }
+}
+
+impl Drop for ImageRender {
+    fn drop(&mut self) {
+        let mut textures: Vec<Texture> = self.textures.drain().map(|(_, t)| t).collect();
+        textures.delete_textures();
+        unsafe {
+            self.program.delete();
+            self.fs.delete();
+            self.vs.delete();
+        }
+    }
+}
@@ -0,0 +1,601 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::image_layer::{Image, ImageLayer, Placement, PngError, decode_png};
+
+/// How much decoded image data (RGBA bytes) we keep around by default before throwing out the least recently used images.
+pub const DEFAULT_BUDGET: usize = 32 * 1024 * 1024;
+
+/// Anything wider or taller than this gets refused.
+const MAX_DIMENSION: u32 = 4096;
+
+/// Ids we hand out to images that were sent without one, kept well away from the ones programs pick.
+const FIRST_AUTO_ID: u32 = 0x8000_0000;
+
+/// The keys of a `G` command that we understand. Missing keys are zero, like the spec says.
+#[derive(Clone, Default)]
+struct Control {
+    action: u8,
+    format: u32,
+    medium: u8,
+    width: u32,
+    height: u32,
+    id: u32,
+    number: u32,
+    placement_id: u32,
+    more: bool,
+    compression: u8,
+    quiet: u32,
+    src_x: u32,
+    src_y: u32,
+    src_w: u32,
+    src_h: u32,
+    cols: u32,
+    rows: u32,
+    offset_x: u32,
+    offset_y: u32,
+    z: i32,
+    no_cursor_move: bool,
+    delete: u8,
+}
+
+fn parse_control(keys: &[u8]) -> Control {
+    let mut c = Control::default();
+    for pair in keys.split(|&b| b == b',') {
+        let [key, b'=', value @ ..] = pair else {
+            continue;
+        };
+        let ch = value.first().copied().unwrap_or(0);
+        let num = || {
+            std::str::from_utf8(value)
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or(0)
+        };
+        let unum = || num().clamp(0, u32::MAX as i64) as u32;
+        match key {
+            b'a' => c.action = ch,
+            b'f' => c.format = unum(),
+            b't' => c.medium = ch,
+            b's' => c.width = unum(),
+            b'v' => c.height = unum(),
+            b'i' => c.id = unum(),
+            b'I' => c.number = unum(),
+            b'p' => c.placement_id = unum(),
+            b'm' => c.more = unum() == 1,
+            b'o' => c.compression = ch,
+            b'q' => c.quiet = unum(),
+            b'x' => c.src_x = unum(),
+            b'y' => c.src_y = unum(),
+            b'w' => c.src_w = unum(),
+            b'h' => c.src_h = unum(),
+            b'c' => c.cols = unum(),
+            b'r' => c.rows = unum(),
+            b'X' => c.offset_x = unum(),
+            b'Y' => c.offset_y = unum(),
+            b'z' => c.z = num().clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+            b'C' => c.no_cursor_move = unum() == 1,
+            b'd' => c.delete = ch,
+            _ => {}
+        }
+    }
+    if c.action == 0 {
+        c.action = b't';
+    }
+    if c.format == 0 {
+        c.format = 32;
+    }
+    if c.medium == 0 {
+        c.medium = b'd';
+    }
+    c
+}
+
+fn base64_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let sextet = |b: u8| match b {
+        b'A'..=b'Z' => Some(b - b'A'),
+        b'a'..=b'z' => Some(b - b'a' + 26),
+        b'0'..=b'9' => Some(b - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let mut out = Vec::with_capacity(data.len() / 4 * 3);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &b in data {
+        if b == b'=' || b.is_ascii_whitespace() {
+            continue;
+        }
+        acc = (acc << 6) | sextet(b)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// The kitty error string for a PNG that wouldn't decode, ready to send back.
+fn png_error(error: PngError) -> &'static str {
+    match error {
+        PngError::Header => "EBADPNG:could not read PNG header",
+        PngError::Data => "EBADPNG:could not decode PNG",
+        PngError::Indexed => "EBADPNG:unexpected indexed PNG",
+    }
+}
+
+fn decode_pixels(c: &Control, raw: Vec<u8>, limit: usize) -> Result<Image, &'static str> {
+    let image = match c.format {
+        100 => decode_png(&raw, limit).map_err(png_error)?,
+        24 | 32 => {
+            let bpp = (c.format / 8) as usize;
+            if c.width == 0 || c.height == 0 {
+                return Err("EINVAL:width and height are required for raw pixel data");
+            }
+            //Before working out the size, which could wrap on a 32 bit usize otherwise
+            if c.width > MAX_DIMENSION || c.height > MAX_DIMENSION {
+                return Err("EFBIG:image is too big");
+            }
+            if raw.len() != c.width as usize * c.height as usize * bpp {
+                return Err("EINVAL:pixel data is the wrong size");
+            }
+            let rgba = if bpp == 4 {
+                raw
+            } else {
+                raw.chunks_exact(3)
+                    .flat_map(|p| [p[0], p[1], p[2], 0xFF])
+                    .collect()
+            };
+            Image {
+                width: c.width as usize,
+                height: c.height as usize,
+                rgba,
+            }
+        }
+        _ => return Err("EINVAL:unsupported format"),
+    };
+    if image.width as u32 > MAX_DIMENSION || image.height as u32 > MAX_DIMENSION {
+        return Err("EFBIG:image is too big");
+    }
+    Ok(image)
+}
+
+/// A chunked transmission that's still coming in.
+enum Transfer {
+    /// The first chunk's keys, and all the base64 so far.
+    Receiving(Control, Vec<u8>),
+    /// It got too big, the rest of the chunks are thrown away and the first chunk's keys are kept to answer with at the end.
+    Discarding(Control),
+}
+
+struct StoredImage {
+    image: Rc<Image>,
+    key: u64,
+    last_used: u64,
+}
+
+/// What the caller has to do after a command has been handled.
+#[derive(Default)]
+pub struct KittyOutcome {
+    /// Bytes to send back to the other end.
+    pub response: Option<Vec<u8>>,
+    /// How many rows the new placement covers, and how many columns, so the cursor can be moved past it.
+    pub cursor_move: Option<(usize, usize)>,
+}
+
+/// Keeps the images sent with the kitty graphics protocol, and turns its commands into placements in an [`ImageLayer`].
+///
+/// Only direct transmission (`t=d`) is supported, we have no filesystem or shared memory in common with the other end.
+pub struct KittyGraphics {
+    images: HashMap<u32, StoredImage>,
+    pending: Option<Transfer>,
+    budget: usize,
+    used: usize,
+    clock: u64,
+    next_auto_id: u32,
+}
+
+impl KittyGraphics {
+    /// `budget` is how many bytes of decoded images to keep at most.
+    pub fn new(budget: usize) -> KittyGraphics {
+        KittyGraphics {
+            images: HashMap::new(),
+            pending: None,
+            budget,
+            used: 0,
+            clock: 0,
+            next_auto_id: FIRST_AUTO_ID,
+        }
+    }
+    /// How many bytes of decoded images are being kept right now.
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+    /// Handles the body of an APC string. Anything that isn't a `G` command is ignored.
+    pub fn handle(
+        &mut self,
+        payload: &[u8],
+        layer: &mut ImageLayer,
+        cursor: (u16, u16),
+    ) -> KittyOutcome {
+        let Some(payload) = payload.strip_prefix(b"G") else {
+            return KittyOutcome::default();
+        };
+        let (keys, data) = match payload.iter().position(|&b| b == b';') {
+            Some(i) => (&payload[..i], &payload[i + 1..]),
+            None => (payload, &[][..]),
+        };
+        let control = parse_control(keys);
+        let (control, data) = match self.pending.take() {
+            Some(Transfer::Receiving(first, mut buf)) => {
+                if buf.len() + data.len() > self.budget * 2 {
+                    return self.discard(first, control.more);
+                }
+                buf.extend_from_slice(data);
+                if control.more {
+                    self.pending = Some(Transfer::Receiving(first, buf));
+                    return KittyOutcome::default();
+                }
+                (first, buf)
+            }
+            Some(Transfer::Discarding(first)) => return self.discard(first, control.more),
+            None if control.more && matches!(control.action, b't' | b'T' | b'q') => {
+                self.pending = Some(Transfer::Receiving(control, data.to_vec()));
+                return KittyOutcome::default();
+            }
+            None => (control, data.to_vec()),
+        };
+        let result = match control.action {
+            b't' | b'T' | b'q' => self.transmit(&control, &data, layer, cursor),
+            b'p' => self.put(&control, control.id, layer, cursor),
+            b'd' => {
+                self.delete(&control, layer, cursor);
+                //Deletes never get a reply
+                return KittyOutcome::default();
+            }
+            _ => Err("EINVAL:unsupported action"),
+        };
+        match result {
+            Ok(cursor_move) => KittyOutcome {
+                response: response(&control, "OK"),
+                cursor_move,
+            },
+            Err(e) => KittyOutcome {
+                response: response(&control, e),
+                cursor_move: None,
+            },
+        }
+    }
+    /// Throws away chunks of a transmission that got too big, answering once the last one comes in.
+    fn discard(&mut self, first: Control, more: bool) -> KittyOutcome {
+        if more {
+            self.pending = Some(Transfer::Discarding(first));
+            return KittyOutcome::default();
+        }
+        KittyOutcome {
+            response: response(&first, "EFBIG:transmission is too big"),
+            cursor_move: None,
+        }
+    }
+    fn transmit(
+        &mut self,
+        c: &Control,
+        data: &[u8],
+        layer: &mut ImageLayer,
+        cursor: (u16, u16),
+    ) -> Result<Option<(usize, usize)>, &'static str> {
+        if c.medium != b'd' {
+            return Err("EINVAL:only direct transmission is supported");
+        }
+        let raw = base64_decode(data).ok_or("EINVAL:bad base64")?;
+        let raw = match c.compression {
+            0 => raw,
+            b'z' => miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&raw, self.budget)
+                .map_err(|_| "EINVAL:bad zlib data")?,
+            _ => return Err("EINVAL:unsupported compression"),
+        };
+        let image = decode_pixels(c, raw, self.budget)?;
+        if c.action == b'q' {
+            return Ok(None);
+        }
+        if image.rgba.len() > self.budget {
+            return Err("ENOSPC:image is bigger than the memory budget");
+        }
+        let id = if c.id != 0 {
+            c.id
+        } else {
+            self.next_auto_id = self.next_auto_id.checked_add(1).unwrap_or(FIRST_AUTO_ID);
+            self.next_auto_id
+        };
+        self.store(id, image, layer);
+        if c.action == b'T' {
+            self.put(c, id, layer, cursor)
+        } else {
+            Ok(None)
+        }
+    }
+    fn store(&mut self, id: u32, image: Image, layer: &mut ImageLayer) {
+        //Sending an image with an id we already have replaces it, and its placements go with it
+        self.forget(id, layer);
+        let size = image.rgba.len();
+        while self.used + size > self.budget {
+            let Some(&oldest) = self
+                .images
+                .iter()
+                .min_by_key(|(_, stored)| stored.last_used)
+                .map(|(id, _)| id)
+            else {
+                break;
+            };
+            self.forget(oldest, layer);
+        }
+        self.clock += 1;
+        self.used += size;
+        self.images.insert(
+            id,
+            StoredImage {
+                image: Rc::new(image),
+                key: layer.new_image_key(),
+                last_used: self.clock,
+            },
+        );
+    }
+    fn forget(&mut self, id: u32, layer: &mut ImageLayer) {
+        if let Some(stored) = self.images.remove(&id) {
+            self.used -= stored.image.rgba.len();
+            layer.remove_image(stored.key);
+        }
+    }
+    fn put(
+        &mut self,
+        c: &Control,
+        id: u32,
+        layer: &mut ImageLayer,
+        cursor: (u16, u16),
+    ) -> Result<Option<(usize, usize)>, &'static str> {
+        self.clock += 1;
+        let stored = self.images.get_mut(&id).ok_or("ENOENT:no such image")?;
+        stored.last_used = self.clock;
+        let image = stored.image.clone();
+        let image_key = stored.key;
+        let (iw, ih) = (image.width as u32, image.height as u32);
+        let src_x = c.src_x.min(iw);
+        let src_y = c.src_y.min(ih);
+        let src_w = if c.src_w == 0 {
+            iw - src_x
+        } else {
+            c.src_w.min(iw - src_x)
+        };
+        let src_h = if c.src_h == 0 {
+            ih - src_y
+        } else {
+            c.src_h.min(ih - src_y)
+        };
+        if src_w == 0 || src_h == 0 {
+            return Err("EINVAL:source rectangle is empty");
+        }
+        let (cw, ch) = layer.cell_size();
+        let (cw, ch) = (cw as f32, ch as f32);
+        let natural = (src_w as f32 / cw, src_h as f32 / ch);
+        //If only one of c and r is given, the other keeps the aspect ratio
+        let (width, height) = match (c.cols, c.rows) {
+            (0, 0) => natural,
+            (cols, 0) => (cols as f32, cols as f32 * natural.1 / natural.0),
+            (0, rows) => (rows as f32 * natural.0 / natural.1, rows as f32),
+            (cols, rows) => (cols as f32, rows as f32),
+        };
+        if c.placement_id != 0 {
+            layer.retain(|p| p.kitty_id != Some((id, c.placement_id)));
+        }
+        let offset = [c.offset_x as f32 / cw, c.offset_y as f32 / ch];
+        let (row, col) = cursor;
+        layer.add(Placement {
+            image_key,
+            image,
+            row: row as isize,
+            col: col as usize,
+            width,
+            height,
+            offset,
+            uv_rect: [
+                src_x as f32 / iw as f32,
+                src_y as f32 / ih as f32,
+                src_w as f32 / iw as f32,
+                src_h as f32 / ih as f32,
+            ],
+            z: c.z,
+            kitty_id: Some((id, c.placement_id)),
+            alternate: false,
+        });
+        Ok((!c.no_cursor_move).then(|| {
+            (
+                (offset[1] + height).ceil() as usize,
+                (offset[0] + width).ceil() as usize,
+            )
+        }))
+    }
+    fn delete(&mut self, c: &Control, layer: &mut ImageLayer, cursor: (u16, u16)) {
+        let (row, col) = (cursor.0 as f32, cursor.1 as f32);
+        match c.delete {
+            0 | b'a' | b'A' => layer.retain(|p| p.kitty_id.is_none()),
+            b'i' | b'I' => layer.retain(|p| match p.kitty_id {
+                Some((id, pid)) => id != c.id || (c.placement_id != 0 && pid != c.placement_id),
+                None => true,
+            }),
+            b'c' | b'C' => layer.retain(|p| {
+                let (x, y) = (p.col as f32 + p.offset[0], p.row as f32 + p.offset[1]);
+                p.kitty_id.is_none()
+                    || !(x <= col && col < x + p.width && y <= row && row < y + p.height)
+            }),
+            b'z' | b'Z' => layer.retain(|p| p.kitty_id.is_none() || p.z != c.z),
+            _ => {}
+        }
+        //Upper case also frees the data of any image that no longer has a placement
+        if c.delete.is_ascii_uppercase() {
+            let unused: Vec<u32> = self
+                .images
+                .iter()
+                .filter(|(_, stored)| !layer.has_placements(stored.key))
+                .map(|(&id, _)| id)
+                .collect();
+            for id in unused {
+                self.forget(id, layer);
+            }
+        }
+    }
+}
+
+fn response(c: &Control, message: &str) -> Option<Vec<u8>> {
+    //Programs that didn't give an id aren't expecting an answer
+    if c.id == 0 && c.number == 0 {
+        return None;
+    }
+    let ok = message == "OK";
+    if (ok && c.quiet >= 1) || c.quiet >= 2 {
+        return None;
+    }
+    let mut keys = vec![];
+    if c.id != 0 {
+        keys.push(format!("i={}", c.id));
+    }
+    if c.number != 0 {
+        keys.push(format!("I={}", c.number));
+    }
+    if c.placement_id != 0 {
+        keys.push(format!("p={}", c.placement_id));
+    }
+    Some(format!("\x1B_G{};{}\x1B\\", keys.join(","), message).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One opaque black pixel, as base64.
+    const PIXEL: &str = "AAAA/w==";
+
+    fn send(kitty: &mut KittyGraphics, layer: &mut ImageLayer, command: &str) -> Option<String> {
+        kitty
+            .handle(command.as_bytes(), layer, (0, 0))
+            .response
+            .map(|r| String::from_utf8(r).unwrap())
+    }
+
+    #[test]
+    fn base64_with_and_without_padding() {
+        assert_eq!(base64_decode(b"TWFu").unwrap(), b"Man");
+        assert_eq!(base64_decode(b"TWE=").unwrap(), b"Ma");
+        assert_eq!(base64_decode(b"TQ==").unwrap(), b"M");
+        assert_eq!(base64_decode(b"TW\nFu").unwrap(), b"Man");
+        assert_eq!(base64_decode(b"+/+/").unwrap(), [0xFB, 0xFF, 0xBF]);
+        assert!(base64_decode(b"TW*u").is_none());
+    }
+
+    #[test]
+    fn chunks_are_put_together() {
+        let mut kitty = KittyGraphics::new(DEFAULT_BUDGET);
+        let mut layer = ImageLayer::new(8, 16);
+        assert_eq!(
+            send(&mut kitty, &mut layer, "Ga=t,s=2,v=1,i=5,m=1;AAAA"),
+            None
+        );
+        assert_eq!(send(&mut kitty, &mut layer, "Gm=1;/wAA"), None);
+        assert_eq!(
+            send(&mut kitty, &mut layer, "Gm=0;AP8="),
+            Some("\x1B_Gi=5;OK\x1B\\".into())
+        );
+        assert_eq!(kitty.memory_used(), 8);
+    }
+
+    #[test]
+    fn a_transmission_that_is_too_big_is_answered_once() {
+        let mut kitty = KittyGraphics::new(4);
+        let mut layer = ImageLayer::new(8, 16);
+        assert_eq!(
+            send(&mut kitty, &mut layer, "Ga=t,s=2,v=1,i=5,m=1;AAAA"),
+            None
+        );
+        assert_eq!(send(&mut kitty, &mut layer, "Gm=1;AAAAAAAA"), None);
+        assert_eq!(send(&mut kitty, &mut layer, "Gm=1;AAAA"), None);
+        assert_eq!(
+            send(&mut kitty, &mut layer, "Gm=0;AAAA"),
+            Some("\x1B_Gi=5;EFBIG:transmission is too big\x1B\\".into())
+        );
+        assert_eq!(kitty.memory_used(), 0);
+        //The next command starts afresh
+        assert_eq!(
+            send(&mut kitty, &mut layer, &format!("Ga=t,s=1,v=1,i=6;{PIXEL}")),
+            Some("\x1B_Gi=6;OK\x1B\\".into())
+        );
+    }
+
+    #[test]
+    fn quiet_keeps_errors_to_itself() {
+        let mut kitty = KittyGraphics::new(4);
+        let mut layer = ImageLayer::new(8, 16);
+        assert_eq!(
+            send(&mut kitty, &mut layer, "Ga=t,s=2,v=1,i=5,q=2,m=1;AAAA"),
+            None
+        );
+        assert_eq!(send(&mut kitty, &mut layer, "Gm=1;AAAAAAAA"), None);
+        assert_eq!(send(&mut kitty, &mut layer, "Gm=0;AAAA"), None);
+        assert_eq!(
+            send(&mut kitty, &mut layer, "Ga=p,i=9,q=1"),
+            Some("\x1B_Gi=9;ENOENT:no such image\x1B\\".into())
+        );
+    }
+
+    #[test]
+    fn the_least_recently_used_image_goes_first() {
+        //Room for two pixels
+        let mut kitty = KittyGraphics::new(8);
+        let mut layer = ImageLayer::new(8, 16);
+        for id in 1..=2 {
+            send(
+                &mut kitty,
+                &mut layer,
+                &format!("Ga=t,s=1,v=1,i={id};{PIXEL}"),
+            );
+        }
+        //Putting 1 makes 2 the oldest
+        send(&mut kitty, &mut layer, "Ga=p,i=1");
+        send(&mut kitty, &mut layer, &format!("Ga=t,s=1,v=1,i=3;{PIXEL}"));
+        assert_eq!(kitty.memory_used(), 8);
+        let mut ids: Vec<u32> = kitty.images.keys().copied().collect();
+        ids.sort();
+        assert_eq!(ids, [1, 3]);
+        //Image 1's placement is still there
+        assert!(layer.has_placements(kitty.images[&1].key));
+    }
+
+    #[test]
+    fn raw_sizes_are_checked_before_they_can_wrap() {
+        let mut kitty = KittyGraphics::new(DEFAULT_BUDGET);
+        let mut layer = ImageLayer::new(8, 16);
+        //65536 * 16384 * 4 is 2^32, which wraps to an empty payload on the Vita
+        assert_eq!(
+            send(&mut kitty, &mut layer, "Ga=t,f=32,s=65536,v=16384,i=7;"),
+            Some("\x1B_Gi=7;EFBIG:image is too big\x1B\\".into())
+        );
+        assert_eq!(
+            send(
+                &mut kitty,
+                &mut layer,
+                &format!("Ga=t,f=32,s=1,v=4097,i=7;{PIXEL}")
+            ),
+            Some("\x1B_Gi=7;EFBIG:image is too big\x1B\\".into())
+        );
+        assert_eq!(kitty.memory_used(), 0);
+    }
+
+    #[test]
+    fn images_bigger_than_the_budget_are_refused() {
+        let mut kitty = KittyGraphics::new(4);
+        let mut layer = ImageLayer::new(8, 16);
+        assert_eq!(
+            send(&mut kitty, &mut layer, "Ga=t,s=2,v=1,i=1;AAAA/wAAAP8="),
+            Some("\x1B_Gi=1;ENOSPC:image is bigger than the memory budget\x1B\\".into())
+        );
+    }
+}
@@ -1,19 +1,57 @@
 #![feature(allocator_api)]
 
+pub mod bdf;
+pub mod bell;
+pub mod box_drawing;
+pub mod callbacks;
 pub mod char_manager;
+pub mod colors;
+pub mod contrast;
+pub mod cvd;
 pub mod default_pal;
+pub mod depth;
+pub mod emoji;
+pub mod font;
 pub mod font_rasterizer;
+pub mod glyph_cache;
+pub mod image_layer;
+pub mod image_render;
+pub mod kitty;
 pub mod neo_charmgr;
+pub mod outline_font;
+pub mod palette;
+pub mod psf1;
+pub mod psf2;
+pub mod scroll_tracker;
+pub mod session;
+pub mod shaping;
+pub mod sixel;
+pub mod status_bar;
+pub mod stream_filter;
+pub mod symbols;
+pub mod term_backend;
 pub mod texture_debug;
+pub mod theme;
+pub mod tile_encoder;
 pub mod vgl_allocator;
+pub mod zoom;
 
 use vita_gl_helpers::{errors::eprintln_errors, initialise_default, swap_buffers};
 
 use crate::{
+    bell::{Bell, BellCommand, BellConfig},
     char_manager::CharMap,
-    default_pal::{PAL_16, PAL_256},
+    cvd::CvdMode,
+    default_pal::PAL_16,
+    depth::ColorDepth,
+    font::{DEFAULT_OUTLINE_SIZE, FACE_PATHS, FONT_PATH, Face, Font, FontFaces},
+    glyph_cache::CacheStats,
     neo_charmgr::NeoCharRender,
+    session::Session,
     texture_debug::TexDebug,
+    theme::{THEME_PATH, Theme},
+    tile_encoder::EncodeOptions,
+    zoom::{ZoomCommand, fit_screen, screen_transform},
 };
 
 use std::io::{Read, Write};
@@ -21,43 +59,172 @@ use std::io::{Read, Write};
 pub const VERTICES: &'static [f32] = &[-0.7, 0.7, 0., 0.7, 0.7, 0., -0.7, -0.7, 0., 0.7, -0.7, 0.];
 pub const UVS: &'static [f32] = &[0., 0., 1., 0., 0., 1., 1., 1.];
 
+/// Faces waiting to be swapped in on the next frame, from the memory card at startup or sent over the network.
+type PendingFonts = Vec<(Face, Box<dyn Font + Send>)>;
+
 fn main_but_errors() -> Result<std::convert::Infallible, Box<dyn std::error::Error>> {
     println!("---- RUN START ----");
     unsafe {
         gl::Enable(gl::TEXTURE_2D);
         gl::ActiveTexture(gl::TEXTURE0);
     }
-    let terminus = psf2_font::load_terminus().expect("WAT");
-    let mut neo_charmgr = NeoCharRender::new(&terminus, 40, 128, 0).expect("No NeoCharRender? sad");
-    // neo_charmgr.parser.screen_mut().
+    let terminus = psf2_font::load_terminus()
+        .map_err(|e| format!("Couldn't load the built in font: {e:?}"))?;
+    let mut fonts = FontFaces::new(Box::new(terminus));
+    let mut neo_charmgr = NeoCharRender::new(&fonts, 41, 129, true).expect("No NeoCharRender? sad");
+    //What's actually drawn, if zooming made bigger or smaller copies of `fonts`
+    let mut scaled_fonts: Option<FontFaces> = None;
+    //Nobody has zoomed yet, so the grid is wherever the transform puts it
+    let mut zoom: Option<f32> = None;
+    //Swapped in on the first frame, the same as ones sent over the network. Regular first, so the others can be checked against it
+    let font_arc_mutex: std::sync::Arc<std::sync::Mutex<PendingFonts>> = Default::default();
+    for (face, path) in std::iter::once((Face::Regular, FONT_PATH)).chain(FACE_PATHS) {
+        match std::fs::read(path) {
+            Ok(data) => match font::load(data, DEFAULT_OUTLINE_SIZE) {
+                Ok(loaded) => font_arc_mutex.lock().expect("WAT").push((face, loaded)),
+                Err(e) => println!("Couldn't load {path}: {e}"),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => println!("Couldn't read {path}: {e}"),
+        }
+    }
+    let (rows, cols) = neo_charmgr.size();
+    let mut session = Session::new(rows as u16, cols as u16, 0, neo_charmgr.cell_size());
+    match std::fs::read_to_string(THEME_PATH) {
+        Ok(text) => match Theme::parse(&text) {
+            Ok(theme) => theme.apply(session.parser.callbacks_mut()),
+            Err(e) => println!("Couldn't load {THEME_PATH}: {e}"),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => println!("Couldn't read {THEME_PATH}: {e}"),
+    }
     for i in 0..16 {
         let ri = 15 - i;
-        writeln!(
-            neo_charmgr.parser,
-            "\x1B[48;5;{ri}m\x1B[38;5;{i}mHello World!\x1B[0m\r"
-        )
-        .unwrap();
+        session.process(format!("\x1B[48;5;{ri}m\x1B[38;5;{i}mHello World!\x1B[0m\r\n").as_bytes());
     }
-    neo_charmgr.parser.flush().unwrap();
     let texdebug = TexDebug::new();
     let transform_arc_mutex = std::sync::Arc::new(std::sync::Mutex::new([
         0.0155f32, 0.0, -1.0, 0.0, -0.05, 1.0, 0.0, 0.0, 1.0,
     ]));
     let transform_arc_mutex_clone = transform_arc_mutex.clone();
-    std::thread::spawn(transform_arc_mutex_server(transform_arc_mutex_clone));
-    let string_arc_mutex: std::sync::Arc<std::sync::Mutex<String>> = Default::default();
-    std::thread::spawn(string_server(string_arc_mutex.clone()));
+    let title_arc_mutex: std::sync::Arc<std::sync::Mutex<(String, String)>> = Default::default();
+    let theme_arc_mutex: std::sync::Arc<std::sync::Mutex<Option<Theme>>> = Default::default();
+    let options_arc_mutex: std::sync::Arc<std::sync::Mutex<EncodeOptions>> = Default::default();
+    let glyph_stats_arc_mutex: std::sync::Arc<std::sync::Mutex<CacheStats>> = Default::default();
+    let zoom_arc_mutex: std::sync::Arc<std::sync::Mutex<Option<ZoomCommand>>> = Default::default();
+    let bell_arc_mutex =
+        std::sync::Arc::new(std::sync::Mutex::new(Bell::new(BellConfig::default())));
+    std::thread::spawn(transform_arc_mutex_server(
+        transform_arc_mutex_clone,
+        title_arc_mutex.clone(),
+        theme_arc_mutex.clone(),
+        options_arc_mutex.clone(),
+        font_arc_mutex.clone(),
+        glyph_stats_arc_mutex.clone(),
+        zoom_arc_mutex.clone(),
+        bell_arc_mutex.clone(),
+    ));
+    let string_arc_mutex: std::sync::Arc<std::sync::Mutex<Vec<u8>>> = Default::default();
+    let outbox_arc_mutex: std::sync::Arc<std::sync::Mutex<Vec<u8>>> = Default::default();
+    std::thread::spawn(string_server(
+        string_arc_mutex.clone(),
+        outbox_arc_mutex.clone(),
+    ));
     unsafe {
         loop {
             let the_transform = *transform_arc_mutex.lock().expect("WAT");
             {
                 let mut the_string = string_arc_mutex.lock().expect("WAT");
-                write!(neo_charmgr.parser, "{}", the_string).unwrap();
+                session.process(&the_string);
                 the_string.clear();
             }
-            gl::ClearColor(1.0, 1.0, 1.0, 1.0);
+            {
+                let responses = &mut session.parser.callbacks_mut().responses;
+                if !responses.is_empty() {
+                    outbox_arc_mutex
+                        .lock()
+                        .expect("WAT")
+                        .extend(responses.drain(..));
+                }
+            }
+            {
+                let callbacks = session.parser.callbacks();
+                let mut the_title = title_arc_mutex.lock().expect("WAT");
+                if the_title.0 != callbacks.title || the_title.1 != callbacks.icon_name {
+                    *the_title = (callbacks.title.clone(), callbacks.icon_name.clone());
+                }
+            }
+            if let Some(theme) = theme_arc_mutex.lock().expect("WAT").take() {
+                theme.apply(session.parser.callbacks_mut());
+            }
+            let new_fonts = std::mem::take(&mut *font_arc_mutex.lock().expect("WAT"));
+            if !new_fonts.is_empty() {
+                //Only kept if the renderer can be rebuilt with them
+                let mut candidate = fonts.clone();
+                for (face, new_font) in new_fonts {
+                    if let Err(e) = candidate.set(face, new_font) {
+                        println!("Couldn't use the {face:?} face: {e}");
+                    }
+                }
+                let changed = match zoom {
+                    Some(zoom) => apply_zoom(
+                        &mut neo_charmgr,
+                        &mut session,
+                        &candidate,
+                        &mut scaled_fonts,
+                        zoom,
+                    )
+                    .map(|transform| *transform_arc_mutex.lock().expect("WAT") = transform),
+                    None => change_font(&mut neo_charmgr, &mut session, &candidate).map(|scale| {
+                        //Keep the grid covering the same part of the screen
+                        let mut transform = transform_arc_mutex.lock().expect("WAT");
+                        for (i, t) in transform.iter_mut().take(6).enumerate() {
+                            *t *= scale[i % 3];
+                        }
+                    }),
+                };
+                match changed {
+                    Ok(()) => fonts = candidate,
+                    Err(e) => println!("Couldn't switch font: {e}"),
+                }
+                //Start the frame again with the new transform
+                continue;
+            }
+            if let Some(command) = zoom_arc_mutex.lock().expect("WAT").take() {
+                let new_zoom = command.apply(zoom.unwrap_or(1.0));
+                match apply_zoom(
+                    &mut neo_charmgr,
+                    &mut session,
+                    &fonts,
+                    &mut scaled_fonts,
+                    new_zoom,
+                ) {
+                    Ok(transform) => {
+                        zoom = Some(new_zoom);
+                        *transform_arc_mutex.lock().expect("WAT") = transform;
+                    }
+                    Err(e) => println!("Couldn't zoom to {new_zoom}: {e}"),
+                }
+                continue;
+            }
+            let now = std::time::Instant::now();
+            let (screen_flash, border_flash) = {
+                let mut bell = bell_arc_mutex.lock().expect("WAT");
+                bell.update(session.parser.callbacks(), now);
+                (bell.screen_flash(now), bell.border_flash(now))
+            };
+            neo_charmgr.options = EncodeOptions {
+                reverse_video: screen_flash,
+                ..*options_arc_mutex.lock().expect("WAT")
+            };
+            let margin = border_flash.unwrap_or(session.parser.callbacks().colors.margin);
+            let margin = neo_charmgr.options.cvd.apply(margin);
+            let [_, r, g, b] = margin.to_be_bytes().map(|x| x as f32 / 255.0);
+            gl::ClearColor(r, g, b, 1.0);
             gl::Clear(gl::COLOR_BUFFER_BIT);
-            neo_charmgr.draw(&terminus, &PAL_256, the_transform);
+            let drawn_fonts = scaled_fonts.as_ref().unwrap_or(&fonts);
+            neo_charmgr.draw(&session.parser, &session.images, drawn_fonts, the_transform);
+            *glyph_stats_arc_mutex.lock().expect("WAT") = neo_charmgr.glyph_stats();
             // texdebug.draw(char_manager.textures[0]);
             eprintln_errors();
             swap_buffers();
@@ -72,19 +239,261 @@ fn main() {
     println!("---- RUN END ----");
 }
 
+/// How many rows and columns of `cell_size` cover the same pixels as the grid `render` has now.
+fn fit_grid(render: &NeoCharRender, cell_size: (usize, usize)) -> (u16, u16) {
+    let (rows, cols) = render.size();
+    let (old_width, old_height) = render.cell_size();
+    //Counting the status bar, which is a row like any other
+    let grid_rows = (rows + 1) * old_height / cell_size.1;
+    let cols = cols * old_width / cell_size.0;
+    (
+        grid_rows.clamp(2, 256) as u16 - 1,
+        cols.clamp(1, 256) as u16,
+    )
+}
+
+/// Rebuilds the renderer for new fonts and resizes the session to match.
+/// Returns how much to scale the transform's x and y columns by (with 1.0 for the translation) so the new grid lands where the old one was.
+fn change_font(
+    neo_charmgr: &mut NeoCharRender,
+    session: &mut Session,
+    fonts: &FontFaces,
+) -> Result<[f32; 3], Box<dyn std::error::Error>> {
+    let (old_rows, old_cols) = neo_charmgr.size();
+    let cell_size = fonts.regular().dimensions();
+    let (rows, cols) = fit_grid(neo_charmgr, cell_size);
+    let render = NeoCharRender::new(fonts, rows, cols, true)?;
+    let options = neo_charmgr.options;
+    *neo_charmgr = render;
+    neo_charmgr.options = options;
+    session.resize(rows, cols, cell_size);
+    Ok([
+        old_cols as f32 / cols as f32,
+        (old_rows + 1) as f32 / (rows + 1) as f32,
+        1.0,
+    ])
+}
+
+/// Rebuilds the renderer so everything is `zoom` times the font's own size, with as many rows and columns as fit on the screen.
+/// Outline fonts are drawn again at the new size, bitmap fonts are stretched. Returns the transform to draw with.
+fn apply_zoom(
+    neo_charmgr: &mut NeoCharRender,
+    session: &mut Session,
+    fonts: &FontFaces,
+    scaled_fonts: &mut Option<FontFaces>,
+    zoom: f32,
+) -> Result<[f32; 9], Box<dyn std::error::Error>> {
+    let scaled = fonts.scaled(zoom).transpose()?;
+    let (drawn, stretch) = match &scaled {
+        Some(scaled) => (scaled, 1.0),
+        None => (fonts, zoom),
+    };
+    let (width, height) = drawn.regular().dimensions();
+    let cell_size = (width as f32 * stretch, height as f32 * stretch);
+    let (rows, cols) = fit_screen(cell_size, true);
+    let mut render = NeoCharRender::new(drawn, rows, cols, true)?;
+    //Glyphs only need smoothing when a texel doesn't land on a whole number of pixels
+    render.set_smooth(stretch.fract() != 0.0);
+    render.options = neo_charmgr.options;
+    *neo_charmgr = render;
+    session.resize(rows, cols, (width, height));
+    *scaled_fonts = scaled;
+    Ok(screen_transform(cell_size))
+}
+
+//One for each thing the control port shares with the render loop
+#[allow(clippy::too_many_arguments)]
 fn transform_arc_mutex_server(
     transform_arc_mutex: std::sync::Arc<std::sync::Mutex<[f32; 9]>>,
+    title_arc_mutex: std::sync::Arc<std::sync::Mutex<(String, String)>>,
+    theme_arc_mutex: std::sync::Arc<std::sync::Mutex<Option<Theme>>>,
+    options_arc_mutex: std::sync::Arc<std::sync::Mutex<EncodeOptions>>,
+    font_arc_mutex: std::sync::Arc<std::sync::Mutex<PendingFonts>>,
+    glyph_stats_arc_mutex: std::sync::Arc<std::sync::Mutex<CacheStats>>,
+    zoom_arc_mutex: std::sync::Arc<std::sync::Mutex<Option<ZoomCommand>>>,
+    bell_arc_mutex: std::sync::Arc<std::sync::Mutex<Bell>>,
 ) -> impl FnMut() {
     move || {
         let listener = std::net::TcpListener::bind("0.0.0.0:9039").expect("NO BIND TO 9039? SCAM.");
         loop {
             match listener.accept() {
                 Ok((mut stream, _)) => {
-                    let mut the_string = String::new();
-                    let Ok(_) = stream.read_to_string(&mut the_string) else {
+                    let mut the_bytes = Vec::new();
+                    let Ok(_) = stream.read_to_end(&mut the_bytes) else {
+                        continue;
+                    };
+                    //`font`, `font 20`, `font bold` or `font bold 20` on its own line, then the whole font file, which is binary so it can't go through the string handling
+                    if let Some(newline) = the_bytes.iter().position(|&b| b == b'\n')
+                        && let Ok(command) = std::str::from_utf8(&the_bytes[..newline])
+                        && let Some(args) = command.trim().strip_prefix("font")
+                        && (args.is_empty() || args.starts_with(' '))
+                    {
+                        let mut args = args.split_whitespace().peekable();
+                        let face = args
+                            .next_if(|arg| Face::parse(arg).is_some())
+                            .and_then(Face::parse)
+                            .unwrap_or(Face::Regular);
+                        let size = match args.next() {
+                            None => Ok(DEFAULT_OUTLINE_SIZE),
+                            Some(size) => {
+                                size.parse::<f32>().map_err(|e| format!("BAD SIZE: {e:?}"))
+                            }
+                        };
+                        let font_data = the_bytes[newline + 1..].to_vec();
+                        match size.and_then(|size| {
+                            font::load(font_data, size).map_err(|e| format!("BAD FONT: {e}"))
+                        }) {
+                            Ok(font) => {
+                                font_arc_mutex
+                                    .lock()
+                                    .expect("CAN'T LOCK? SCAM")
+                                    .push((face, font));
+                                writeln!(stream, "OK").unwrap();
+                            }
+                            Err(e) => writeln!(stream, "{e}").unwrap(),
+                        }
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    let Ok(the_string) = String::from_utf8(the_bytes) else {
+                        writeln!(stream, "BAD COMMAND: not UTF-8").unwrap();
+                        stream.flush().unwrap();
                         continue;
                     };
                     println!("Received {the_string}");
+                    if the_string.trim() == "title" {
+                        let (title, icon_name) =
+                            title_arc_mutex.lock().expect("CAN'T LOCK? SCAM").clone();
+                        writeln!(stream, "TITLE {title}\nICON {icon_name}").unwrap();
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    if the_string.trim() == "glyphs" {
+                        let stats = *glyph_stats_arc_mutex.lock().expect("CAN'T LOCK? SCAM");
+                        writeln!(
+                            stream,
+                            "HITS {}\nMISSES {}\nEVICTIONS {}\nHIT RATE {:.1}%",
+                            stats.hits,
+                            stats.misses,
+                            stats.evictions,
+                            stats.hit_rate() * 100.0
+                        )
+                        .unwrap();
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    //`theme` on its own line, then the whole theme file
+                    if let Some(("theme", theme_text)) = the_string.split_once('\n') {
+                        match Theme::parse(theme_text) {
+                            Ok(theme) => {
+                                *theme_arc_mutex.lock().expect("CAN'T LOCK? SCAM") = Some(theme);
+                                writeln!(stream, "OK").unwrap();
+                            }
+                            Err(e) => writeln!(stream, "BAD THEME: {e}").unwrap(),
+                        }
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    //`contrast 4.5` or `contrast off`
+                    if let Some(ratio) = the_string.trim().strip_prefix("contrast ") {
+                        let ratio = match ratio.trim() {
+                            "off" => Ok(None),
+                            ratio => ratio.parse::<f32>().map(Some),
+                        };
+                        match ratio {
+                            Ok(ratio) => {
+                                options_arc_mutex
+                                    .lock()
+                                    .expect("CAN'T LOCK? SCAM")
+                                    .min_contrast = ratio;
+                                writeln!(stream, "OK").unwrap();
+                            }
+                            Err(e) => writeln!(stream, "BAD RATIO: {e:?}").unwrap(),
+                        }
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    //`cvd correct deuteranopia`, `cvd simulate tritanopia`, `cvd off` and so on
+                    if let Some(mode) = the_string.trim().strip_prefix("cvd ") {
+                        match CvdMode::parse(mode) {
+                            Some(mode) => {
+                                options_arc_mutex.lock().expect("CAN'T LOCK? SCAM").cvd = mode;
+                                writeln!(stream, "OK").unwrap();
+                            }
+                            None => writeln!(stream, "BAD CVD MODE {mode}").unwrap(),
+                        }
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    //`zoom in`, `zoom out` or `zoom 1.5`
+                    if let Some(zoom) = the_string.trim().strip_prefix("zoom ") {
+                        match ZoomCommand::parse(zoom) {
+                            Some(command) => {
+                                *zoom_arc_mutex.lock().expect("CAN'T LOCK? SCAM") = Some(command);
+                                writeln!(stream, "OK").unwrap();
+                            }
+                            None => writeln!(stream, "BAD ZOOM {zoom}").unwrap(),
+                        }
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    //`bell` on its own asks whether a bell rang while the terminal was in the background
+                    if the_string.trim() == "bell" {
+                        let pending = bell_arc_mutex
+                            .lock()
+                            .expect("CAN'T LOCK? SCAM")
+                            .pending_notification;
+                        writeln!(stream, "PENDING {}", if pending { "yes" } else { "no" }).unwrap();
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    //`bell screen`, `bell border #ff8000`, `bell off`, `bell flash 100`, `bell interval 500`, `bell notify off`, `bell background`, `bell foreground`
+                    if let Some(command) = the_string.trim().strip_prefix("bell ") {
+                        match BellCommand::parse(command) {
+                            Some(command) => {
+                                bell_arc_mutex
+                                    .lock()
+                                    .expect("CAN'T LOCK? SCAM")
+                                    .apply(command, std::time::Instant::now());
+                                writeln!(stream, "OK").unwrap();
+                            }
+                            None => writeln!(stream, "BAD BELL {command}").unwrap(),
+                        }
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    //`ligatures on` or `ligatures off`
+                    if let Some(switch) = the_string.trim().strip_prefix("ligatures ") {
+                        let ligatures = match switch.trim() {
+                            "on" => Some(true),
+                            "off" => Some(false),
+                            _ => None,
+                        };
+                        match ligatures {
+                            Some(ligatures) => {
+                                options_arc_mutex
+                                    .lock()
+                                    .expect("CAN'T LOCK? SCAM")
+                                    .ligatures = ligatures;
+                                writeln!(stream, "OK").unwrap();
+                            }
+                            None => writeln!(stream, "BAD LIGATURES {switch}").unwrap(),
+                        }
+                        stream.flush().unwrap();
+                        continue;
+                    }
+                    //`depth 256`, `depth 16`, `depth mono`, `depth mono #ffb000 #000000`, `depth true`
+                    if let Some(depth) = the_string.trim().strip_prefix("depth ") {
+                        match ColorDepth::parse(depth) {
+                            Some(depth) => {
+                                options_arc_mutex.lock().expect("CAN'T LOCK? SCAM").depth = depth;
+                                writeln!(stream, "OK").unwrap();
+                            }
+                            None => writeln!(stream, "BAD DEPTH {depth}").unwrap(),
+                        }
+                        stream.flush().unwrap();
+                        continue;
+                    }
                     let floats: Vec<f32> = the_string
                         .split(",")
                         .filter_map(|x| {
@@ -112,18 +521,45 @@ fn transform_arc_mutex_server(
     }
 }
 
-fn string_server(string_arc_mutex: std::sync::Arc<std::sync::Mutex<String>>) -> impl FnMut() {
+/// Everything read from port 9040 goes into `string_arc_mutex` as it arrives, and everything put in `outbox_arc_mutex` gets sent back.
+fn string_server(
+    string_arc_mutex: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    outbox_arc_mutex: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+) -> impl FnMut() {
     move || {
         let listener = std::net::TcpListener::bind("0.0.0.0:9040").expect("NO BIND TO 9040? SCAM.");
         loop {
             match listener.accept() {
                 Ok((mut stream, _)) => {
-                    let mut acquired_lock = string_arc_mutex.lock().expect("SCAM");
-                    acquired_lock.clear();
-                    let Ok(_) = stream.read_to_string(&mut acquired_lock) else {
+                    //Anything left over was meant for whoever was connected before
+                    outbox_arc_mutex.lock().expect("SCAM").clear();
+                    //Don't block on reads for long, so answers can go back while the other end is still sending
+                    stream
+                        .set_read_timeout(Some(std::time::Duration::from_millis(10)))
+                        .unwrap();
+                    let mut buf = [0u8; 4096];
+                    loop {
+                        match stream.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(n) => string_arc_mutex
+                                .lock()
+                                .expect("SCAM")
+                                .extend_from_slice(&buf[..n]),
+                            Err(e)
+                                if matches!(
+                                    e.kind(),
+                                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                                ) => {}
+                            Err(_) => break,
+                        }
+                        let outgoing = std::mem::take(&mut *outbox_arc_mutex.lock().expect("SCAM"));
+                        if !outgoing.is_empty() && stream.write_all(&outgoing).is_err() {
+                            break;
+                        }
+                    }
+                    let Ok(_) = writeln!(stream, "OK") else {
                         continue;
                     };
-                    writeln!(stream, "OK").unwrap();
                     stream.flush().unwrap();
                     continue;
                 }
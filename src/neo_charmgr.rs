@@ -1,4 +1,3 @@
-use psf2_font::Psf2Font;
 use vita_gl_helpers::{
     attribute::{AttributeFormat, AttributeTable},
     attribute_table,
@@ -8,19 +7,35 @@ use vita_gl_helpers::{
     shader::{Shader, load_shader},
     uniform_table,
 };
-use vt100::Parser;
 
-use crate::font_rasterizer::{RasterizedFont, rasterize_font};
+use crate::{
+    emoji::{EmojiColors, EmojiSet},
+    font::{Face, Font, FontFaces},
+    font_rasterizer::{LigaturePieces, RasterizedEmoji, RasterizedFont, WithDrawnGlyphs},
+    glyph_cache::CacheStats,
+    image_layer::ImageLayer,
+    image_render::ImageRender,
+    shaping::Shaper,
+    term_backend::TermScreen,
+    tile_encoder::{EncodeOptions, GlyphRef, TileEncoder},
+};
 
 uniform_table!(FgUniformTable,
   transform : UniformMatrix3fv => "transform",
   char_dim : Uniform2fv => "char_dim",
+  texel_dim : Uniform2fv => "texel_dim",
   italic_shift : Uniform1fv => "italic_shift",
-  the_texture: Uniform1iv => "the_texture"
+  the_texture: Uniform1iv => "the_texture",
+  bold_texture: Uniform1iv => "bold_texture",
+  italic_texture: Uniform1iv => "italic_texture",
+  bold_italic_texture: Uniform1iv => "bold_italic_texture",
+  emoji_texture: Uniform1iv => "emoji_texture",
+  emoji_texel_dim : Uniform2fv => "emoji_texel_dim"
 );
 
 attribute_table!(FgAttributeTable,
-  uvxyst => "a_uvxyst",
+  xys => "a_xys",
+  glyph => "a_glyph",
   color => "a_color"
 );
 
@@ -44,15 +59,25 @@ const TILEINFO_FORMAT: AttributeFormat = AttributeFormat {
     normalized: false,
 };
 
+const GLYPH_FORMAT: AttributeFormat = AttributeFormat {
+    size: vita_gl_helpers::attribute::AttributeSize::TWO,
+    type_: vita_gl_helpers::attribute::AttributeType::UnsignedShort,
+    normalized: false,
+};
+
+/// After one for each face.
+const EMOJI_TEXTURE_UNIT: u32 = 4;
+
 pub struct NeoCharRender {
-    rasterized_font: RasterizedFont,
-    big_buffer: Vec<u32>,
+    /// One atlas per face, in [`Face`] order. The regular face is always there.
+    rasterized_fonts: [Option<RasterizedFont>; 4],
+    emoji: RasterizedEmoji,
+    /// Only used when [`EncodeOptions::ligatures`] is on.
+    shaper: Shaper,
+    tiles: TileEncoder,
     big_buffer_vbo: Buffer,
-    fg_texture_counts: Vec<usize>,
-    /// Changing the width or height of this parser won't change the width or height this struct expects to see!
-    pub parser: Parser,
-    rows: usize,
-    cols: usize,
+    image_render: ImageRender,
+    pub options: EncodeOptions,
     fg_program: Program,
     fg_vs: Shader,
     fg_fs: Shader,
@@ -71,26 +96,42 @@ impl NeoCharRender {
     /// If you need more than that, use this crate as a reference and make your own terminal emulator!
     ///
     /// Also, if you need to change the size of the terminal, make a new `NeoCharRender`.
+    ///
+    /// The status bar takes up one of those 256 rows, above the terminal.
     pub fn new(
-        font: &Psf2Font,
-        max_row: u8,
-        max_col: u8,
-        scrollback_len: usize,
+        fonts: &FontFaces,
+        rows: u16,
+        cols: u16,
+        status_bar: bool,
     ) -> Result<NeoCharRender, Box<dyn std::error::Error>> {
-        let rasterized_font = rasterize_font(font);
-        let parser = Parser::new((max_row as u16) + 1, (max_col as u16) + 1, scrollback_len);
-        Self::new_with(rasterized_font, parser)
+        //All the same size, so they can share UV maths in the shader
+        let slots = Face::ALL
+            .into_iter()
+            .filter_map(|face| fonts.get(face))
+            .map(RasterizedFont::slots_for)
+            .max()
+            .unwrap_or(0);
+        let rasterized_fonts = Face::ALL.map(|face| {
+            fonts
+                .get(face)
+                .map(|font| RasterizedFont::with_slots(font, slots))
+        });
+        Self::new_with(rasterized_fonts, rows, cols, status_bar)
     }
+    /// `rasterized_fonts` has to have the regular face, and the others have to be the same size as it.
     pub fn new_with(
-        rasterized_font: RasterizedFont,
-        parser: Parser,
+        rasterized_fonts: [Option<RasterizedFont>; 4],
+        rows: u16,
+        cols: u16,
+        status_bar: bool,
     ) -> Result<NeoCharRender, Box<dyn std::error::Error>> {
-        let (rows, cols) = parser.screen().size();
-        if rows > 256 || cols > 256 {
+        if rasterized_fonts[Face::Regular as usize].is_none() {
+            return Err("No regular face".into());
+        }
+        let grid_rows = rows as usize + status_bar as usize;
+        if grid_rows > 256 || cols > 256 {
             panic!("WHAT DID I TELL YOU ABOUT USING MORE THAN 256 ROWS OR COLUMNS?");
         }
-        let n_tiles = (rows as usize) * (cols as usize);
-        let big_buffer = vec![0u32; n_tiles * 3];
         let mut bbv = [Buffer::default()];
         bbv.gen_buffers();
         let big_buffer_vbo = bbv[0];
@@ -104,15 +145,23 @@ impl NeoCharRender {
         let bg_program = link_program(bg_vs, bg_fs)?;
         let bg_unif_table = bg_program.get_uniform_table()?;
         let bg_attr_table = bg_program.get_attribute_table()?;
-        let n_textures = rasterized_font.textures.len();
+        let tiles = TileEncoder::new(rows as usize, cols as usize, status_bar);
+        let image_render = ImageRender::new()?;
+        let regular = rasterized_fonts[Face::Regular as usize]
+            .as_ref()
+            .expect("Checked above");
+        let emoji = RasterizedEmoji::new(
+            EmojiSet::bundled(),
+            (regular.char_width, regular.char_height),
+        );
         Ok(NeoCharRender {
-            rasterized_font,
-            big_buffer,
+            rasterized_fonts,
+            emoji,
+            shaper: Shaper::default(),
+            tiles,
             big_buffer_vbo,
-            fg_texture_counts: vec![0; n_textures],
-            parser,
-            rows: rows as usize,
-            cols: cols as usize,
+            image_render,
+            options: EncodeOptions::default(),
             fg_program,
             fg_vs,
             fg_fs,
@@ -125,57 +174,113 @@ impl NeoCharRender {
             bg_attr_table,
         })
     }
-    fn put_parser_data_into_buffers(&mut self, psf: &Psf2Font, pal: &[u32; 256]) {
-        let n_tiles = self.rows * self.cols;
-        let mut tileinfos: Vec<Vec<u32>> = vec![vec![]; self.rasterized_font.textures.len()];
-        let mut tilefgs: Vec<Vec<u32>> = vec![vec![]; self.rasterized_font.textures.len()];
-        let mut index = 0;
-        let screen = self.parser.screen();
-        for row in 0..self.rows {
-            for col in 0..self.cols {
-                let cell = screen
-                    .cell(row as u16, col as u16)
-                    .expect("WHY DON'T WE HAVE A CELL? DID YOU CHANGE THE SIZE OF THE PARSER?");
-                let fg_color = map_color(cell.fgcolor(), pal, 0xFFFFFFFF);
-                let bg_color = map_color(cell.bgcolor(), pal, 0xFF000000);
-                let (fg_color, bg_color) = if cell.inverse() {
-                    (bg_color, fg_color)
-                } else {
-                    (fg_color, bg_color)
-                };
-                self.big_buffer[index] = bg_color;
-                let cell_char = cell.contents().chars().next().unwrap_or(' ');
-                let cell_char_number = psf.get_glyph_index(cell_char).unwrap_or(0); //TODO: sub with replacement character, then space, then zero
-                let texture_num = cell_char_number >> 8;
-                //we can assume usize = u32
-                let bit = |b, n| if b { 1usize << n } else { 0 };
-                let tile_style = bit(cell.bold(), 0) | bit(cell.dim(), 1) | bit(cell.italic(), 2);
-                let tile_info =
-                    (cell_char_number & 0xFF) | (col << 8) | (row << 16) | (tile_style << 24);
-                tileinfos[texture_num].push(tile_info as _);
-                tilefgs[texture_num].push(fg_color);
-                index += 1; //IMPORTANT
-            }
+    fn regular(&self) -> &RasterizedFont {
+        self.rasterized_fonts[Face::Regular as usize]
+            .as_ref()
+            .expect("The regular face is always there")
+    }
+    /// The size of a glyph in pixels, `(width, height)`.
+    pub fn cell_size(&self) -> (usize, usize) {
+        (self.regular().char_width, self.regular().char_height)
+    }
+    /// Smooth the glyphs, for when they're being stretched by a fraction. See [`RasterizedFont::set_smooth`].
+    pub fn set_smooth(&self, smooth: bool) {
+        for rasterized_font in self.rasterized_fonts.iter().flatten() {
+            rasterized_font.set_smooth(smooth);
         }
-        for (tc, ti) in self.fg_texture_counts.iter_mut().zip(tileinfos.iter()) {
-            *tc = ti.len();
+        self.emoji.set_smooth(smooth);
+    }
+    /// How often glyphs were already in the atlas when they were drawn, across every face.
+    pub fn glyph_stats(&self) -> CacheStats {
+        self.rasterized_fonts
+            .iter()
+            .flatten()
+            .fold(CacheStats::default(), |stats, r| stats + r.cache.stats)
+    }
+    /// `(rows, cols)` of the terminal this was made for, not counting the status bar.
+    pub fn size(&self) -> (usize, usize) {
+        (self.tiles.rows, self.tiles.cols)
+    }
+    //Unsure what the value of transform should be currently, so perhaps i'll have a tcp listener on another thread to receive values on?
+    ///
+    /// `term` should be the size this was made for, anything missing is drawn blank.
+    pub fn draw(
+        &mut self,
+        term: &impl TermScreen,
+        images: &ImageLayer,
+        fonts: &FontFaces,
+        transform: [f32; 9],
+    ) {
+        let ligatures = self.options.ligatures;
+        if ligatures {
+            self.shaper.shape_screen(term, fonts);
+        }
+        if self.shaper.take_renumbered() {
+            for rasterized_font in self.rasterized_fonts.iter_mut().flatten() {
+                rasterized_font.forget_ligatures();
+            }
         }
-        let mut docpy = |start, vov: &[Vec<u32>]| {
-            let mut index = start;
-            for v in vov {
-                self.big_buffer[index..(index + v.len())].copy_from_slice(v);
-                index += v.len();
+        let mut targets = self.rasterized_fonts.each_mut().map(|r| {
+            r.as_mut().map(|r| {
+                let (cache, atlas) = r.split();
+                cache.begin_frame();
+                (cache, atlas)
+            })
+        });
+        self.emoji
+            .recolor(EmojiColors::new(&self.options, term.palette()));
+        let shaper = &self.shaper;
+        let emoji_set = EmojiSet::bundled();
+        let emoji = &self.emoji.atlas;
+        let mut glyphs = |chr: char, face: Face, wide: bool, at: Option<(usize, usize)>| {
+            //A ligature in a face without an atlas of its own is drawn a cell at a time, like everything else
+            let piece = at
+                .filter(|_| ligatures)
+                .and_then(|at| shaper.piece_at(at))
+                .and_then(|(face, piece)| {
+                    let font = fonts.get(face)?.outline()?;
+                    let (cache, atlas) = targets[face as usize].as_mut()?;
+                    let pieces = LigaturePieces { shaper, font };
+                    let origin = cache.lookup(LigaturePieces::glyph_number(piece), &pieces, atlas);
+                    Some(GlyphRef {
+                        origin,
+                        face,
+                        color: false,
+                    })
+                });
+            if let Some(glyph) = piece {
+                return glyph;
+            }
+            if let Some(origin) = emoji_set
+                .index(chr)
+                .and_then(|index| emoji.origin(index, wide))
+            {
+                return GlyphRef {
+                    origin,
+                    face: Face::Regular,
+                    color: true,
+                };
+            }
+            let face = match fonts.resolve(face) {
+                face if targets[face as usize].is_some() => face,
+                _ => Face::Regular,
+            };
+            let font = WithDrawnGlyphs(fonts.get(face).unwrap_or(fonts.regular()));
+            let (cache, atlas) = targets[face as usize]
+                .as_mut()
+                .expect("The regular face is always there");
+            let glyph = font.glyph_index(chr).unwrap_or(0); //TODO: sub with replacement character, then space, then zero
+            GlyphRef {
+                origin: cache.lookup(glyph, &font, atlas),
+                face,
+                color: false,
             }
         };
-        docpy(n_tiles, &tilefgs);
-        docpy(n_tiles * 2, &tileinfos);
-    }
-    //Unsure what the value of transform should be currently, so perhaps i'll have a tcp listener on another thread to receive values on?
-    pub fn draw(&mut self, psf: &Psf2Font, pal: &[u32; 256], transform: [f32; 9]) {
-        self.put_parser_data_into_buffers(psf, pal);
-        let n_chars = self.rows * self.cols;
+        self.tiles.encode(term, &mut glyphs, self.options);
+        let n_chars = self.tiles.n_tiles();
+        let (rows, cols) = self.size();
         self.big_buffer_vbo
-            .data(gl::ARRAY_BUFFER, &self.big_buffer, gl::DYNAMIC_DRAW);
+            .data(gl::ARRAY_BUFFER, &self.tiles.big_buffer, gl::DYNAMIC_DRAW);
         self.bg_program.use_me();
         self.bg_unif_table.transform.set(transform, false);
         self.bg_attr_table.enable_all();
@@ -183,47 +288,72 @@ impl NeoCharRender {
         b.bind_to(self.bg_attr_table.color, COLOR_FORMAT, 0, 0);
         self.bg_attr_table.color.divisor(1);
         quads(n_chars);
+        let row_offset = self.tiles.status_bar as usize;
+        self.image_render
+            .prepare(images, rows, cols, self.options.cvd);
+        self.image_render
+            .draw(images, rows, cols, row_offset, transform, true);
         self.fg_program.use_me();
 
         self.fg_attr_table.enable_all();
         self.fg_attr_table.color.divisor(1);
-        self.fg_attr_table.uvxyst.divisor(1);
+        self.fg_attr_table.xys.divisor(1);
+        self.fg_attr_table.glyph.divisor(1);
 
         self.fg_unif_table.transform.set(transform, false);
         self.fg_unif_table
             .char_dim
-            .set(self.rasterized_font.char_dim.to_array());
+            .set(self.regular().char_dim.to_array());
+        self.fg_unif_table
+            .texel_dim
+            .set(self.regular().texel_dim.to_array());
         self.fg_unif_table.italic_shift.set(0.25); //hardcoded value for now because it's really not that important
-        self.fg_unif_table.the_texture.set(0);
+        self.fg_unif_table.the_texture.set(Face::Regular as i32);
+        self.fg_unif_table.bold_texture.set(Face::Bold as i32);
+        self.fg_unif_table.italic_texture.set(Face::Italic as i32);
+        self.fg_unif_table
+            .bold_italic_texture
+            .set(Face::BoldItalic as i32);
+        self.fg_unif_table
+            .emoji_texture
+            .set(EMOJI_TEXTURE_UNIT as i32);
+        self.fg_unif_table
+            .emoji_texel_dim
+            .set(self.emoji.texel_dim.to_array());
 
         unsafe {
             gl::Enable(gl::TEXTURE_2D);
-            gl::ActiveTexture(gl::TEXTURE0);
             gl::Enable(gl::BLEND);
             gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
         }
-        let mut index = 0;
-        for (texindex, &n_to_draw) in self.fg_texture_counts.iter().enumerate() {
-            self.rasterized_font.textures[texindex].bind(gl::TEXTURE_2D);
-            b.bind_to(
-                self.fg_attr_table.color,
-                COLOR_FORMAT,
-                0,
-                (n_chars + index) * 4,
-            );
-            b.bind_to(
-                self.fg_attr_table.uvxyst,
-                TILEINFO_FORMAT,
-                0,
-                ((n_chars * 2) + index) * 4,
-            );
-            quads(n_to_draw);
-            index += n_to_draw;
+        //One texture unit per face, faces that aren't loaded never get sampled but still need something bound
+        for (unit, rasterized_font) in self.rasterized_fonts.iter().enumerate() {
+            unsafe {
+                gl::ActiveTexture(gl::TEXTURE0 + unit as u32);
+            }
+            rasterized_font
+                .as_ref()
+                .unwrap_or(self.regular())
+                .texture
+                .bind(gl::TEXTURE_2D);
         }
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0 + EMOJI_TEXTURE_UNIT);
+        }
+        self.emoji.texture.bind(gl::TEXTURE_2D);
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0);
+        }
+        b.bind_to(self.fg_attr_table.color, COLOR_FORMAT, 0, n_chars * 4);
+        b.bind_to(self.fg_attr_table.xys, TILEINFO_FORMAT, 0, n_chars * 2 * 4);
+        b.bind_to(self.fg_attr_table.glyph, GLYPH_FORMAT, 0, n_chars * 3 * 4);
+        quads(n_chars);
         unsafe {
             gl::Disable(gl::BLEND);
             gl::Disable(gl::TEXTURE_2D);
         }
+        self.image_render
+            .draw(images, rows, cols, row_offset, transform, false);
     }
 }
 
@@ -234,14 +364,6 @@ fn quads(count: usize) {
     .draw_instanced(vita_gl_helpers::draw::Mode::Quads, count as _);
 }
 
-fn map_color(c: vt100::Color, p: &[u32; 256], d: u32) -> u32 {
-    match c {
-        vt100::Color::Default => d,
-        vt100::Color::Idx(i) => p[i as usize] | 0xFF000000,
-        vt100::Color::Rgb(r, g, b) => u32::from_ne_bytes([b, g, r, 0xFF]),
-    }
-}
-
 fn create_bg_vs(width: u16) -> Result<Shader, Box<dyn std::error::Error>> {
     let bg_vs_source = include_str!("neo_tty_bg.vert");
     let (_, bg_vs_source) = bg_vs_source
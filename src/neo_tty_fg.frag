@@ -1,9 +1,29 @@
 sampler2D uniform the_texture;
+sampler2D uniform bold_texture;
+sampler2D uniform italic_texture;
+sampler2D uniform bold_italic_texture;
+sampler2D uniform emoji_texture;
 
 float4 in v_color : COLOR0;
 float2 in v_uv : TEXCOORD0;
+float in v_face : TEXCOORD1;
+float in v_emoji : TEXCOORD2;
 
 float4 main() : COLOR {
-    float4 t = tex2D(the_texture, v_uv);
-return float4(v_color.xyz*t.xyz, t.r);
+    if (v_emoji > 1.5) {
+        return float4(v_color.xyz, tex2D(emoji_texture, v_uv).a);
+    } else if (v_emoji > 0.5) {
+        return tex2D(emoji_texture, v_uv);
+    }
+    float4 t;
+    if (v_face < 0.5) {
+        t = tex2D(the_texture, v_uv);
+    } else if (v_face < 1.5) {
+        t = tex2D(bold_texture, v_uv);
+    } else if (v_face < 2.5) {
+        t = tex2D(italic_texture, v_uv);
+    } else {
+        t = tex2D(bold_italic_texture, v_uv);
+    }
+return float4(v_color.xyz, t.r);
 }
@@ -1,25 +1,48 @@
 float3x3 uniform transform;
 float2 uniform char_dim;
+float2 uniform texel_dim;
 float uniform italic_shift;
+float2 uniform emoji_texel_dim;
 
-// {lower_nibble u;upper_nibble v;byte x;byte y;byte style}
+// {byte x;byte y;byte style;byte unused}
 // STYLE_BOLD   = 0b00000001
 // STYLE_DIM    = 0b00000010
-// STYLE_ITALIC = 0b00000100
-unsigned char4 in a_uvxyst;
+// STYLE_ITALIC = 0b00000100 (only set when there's no italic face, so the glyph gets slanted instead)
+// STYLE_FACE   = 0b00011000 which face's atlas the glyph is in
+// STYLE_COLOR  = 0b00100000 the glyph is in the emoji atlas, and keeps its own colours
+// STYLE_WIDE   = 0b01000000 the glyph covers this cell and the next
+// STYLE_TINT   = 0b10000000 with STYLE_COLOR, the emoji is drawn as a shape in a_color instead (monochrome)
+unsigned char4 in a_xys;
+// {ushort x;ushort y} the top left pixel of the glyph in the atlas
+float2 in a_glyph;
 float4 in a_color;
 unsigned int in gl_VertexIndex : INDEX;
 
 float4 out gl_Position : POSITION;
 float4 out v_color : COLOR0;
 float2 out v_uv : TEXCOORD0;
+float out v_face : TEXCOORD1;
+float out v_emoji : TEXCOORD2;
 
 void main() {
     v_color = a_color.zyxw;
     float2 corner = float2(gl_VertexIndex & 1, (gl_VertexIndex & 2) >> 1);
-    v_uv = (float2(a_uvxyst.x & 0xF, a_uvxyst.x >> 4) + corner) * char_dim;
-    corner = corner + float2((gl_VertexIndex & 2 == 0 && a_uvxyst.w & 4 != 0) ? 0.0 : italic_shift, 0.0);
-    float2 vtx_pos = float2(a_uvxyst.yz) + corner;
+    float2 span = float2((a_xys.z & 64) != 0 ? 2.0 : 1.0, 1.0);
+    v_emoji = (a_xys.z & 32) != 0 ? ((a_xys.z & 128) != 0 ? 2.0 : 1.0) : 0.0;
+    //From the middle of the glyph's first texel to the middle of its last, so smoothing at fractional zooms
+    //never reaches into the padding and leaves a seam between cells. Nearest sampling lands on the same texels either way.
+    float2 cell_px = char_dim / texel_dim;
+    if (v_emoji > 0.5) {
+        //Same cell size in pixels, different texture size
+        v_uv = (a_glyph + 0.5 + corner * (span * cell_px - 1.0)) * emoji_texel_dim;
+    } else {
+        v_uv = (a_glyph + 0.5 + corner * (cell_px - 1.0)) * texel_dim;
+    }
+    v_face = (a_xys.z >> 3) & 3;
+    corner = corner * span;
+    //Slant by pushing the top of the quad right
+    corner = corner + float2(((gl_VertexIndex & 2) == 0 && (a_xys.z & 4) != 0) ? italic_shift : 0.0, 0.0);
+    float2 vtx_pos = float2(a_xys.xy) + corner;
     gl_Position = float4(mul(transform, float3(vtx_pos, 1.0)), 1.0);
     //TODO: apply bold/dim style somehow?
 }
@@ -0,0 +1,7 @@
+sampler2D uniform the_texture;
+
+float2 in v_uv : TEXCOORD0;
+
+float4 main() : COLOR {
+return tex2D(the_texture, v_uv);
+}
@@ -0,0 +1,19 @@
+float3x3 uniform transform;
+// top left and size of the quad, in cells
+float2 uniform rect_pos;
+float2 uniform rect_size;
+// which part of the image the quad shows, after clipping
+float2 uniform uv_pos;
+float2 uniform uv_size;
+
+unsigned int in gl_VertexIndex : INDEX;
+
+float4 out gl_Position : POSITION;
+float2 out v_uv : TEXCOORD0;
+
+void main() {
+    float2 corner = float2(gl_VertexIndex & 1, (gl_VertexIndex & 2) >> 1);
+    v_uv = uv_pos + corner * uv_size;
+    float2 vtx_pos = rect_pos + corner * rect_size;
+    gl_Position = float4(mul(transform, float3(vtx_pos, 1.0)), 1.0);
+}
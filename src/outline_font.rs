@@ -0,0 +1,108 @@
+use ab_glyph::{Font as _, FontVec, GlyphId, PxScale, ScaleFont, point};
+
+use crate::{
+    font::{Font, Glyph},
+    shaping::PlacedGlyph,
+};
+
+/// A TrueType or OpenType font, drawn at a fixed pixel size into anti-aliased cells.
+///
+/// The cell is as wide as the font's advance for `M` and as tall as its ascent plus descent, so proportional fonts end up squashed into a grid. Use a monospace one.
+pub struct OutlineFont {
+    font: FontVec,
+    /// What it was asked for, so it can be made again at another size.
+    size: f32,
+    scale: PxScale,
+    width: usize,
+    height: usize,
+    /// Pixels from the top of the cell down to the baseline.
+    baseline: f32,
+}
+
+impl OutlineFont {
+    /// `size` is the height of a cell in pixels.
+    pub fn parse(data: Vec<u8>, size: f32) -> Result<OutlineFont, String> {
+        if !(size.is_finite() && size >= 1.0) {
+            return Err(format!("Can't draw a font {size} pixels tall"));
+        }
+        let font = FontVec::try_from_vec(data).map_err(|e| format!("Not a usable font: {e}"))?;
+        //Sized so ascent plus descent comes out at `size`
+        let units = font.ascent_unscaled() - font.descent_unscaled();
+        if units <= 0.0 {
+            return Err("Font has no height".to_string());
+        }
+        let scale = PxScale::from(size * font.height_unscaled() / units);
+        let scaled = font.as_scaled(scale);
+        let advance = scaled.h_advance(scaled.glyph_id('M'));
+        let width = advance.ceil() as usize;
+        if width == 0 {
+            return Err("Font has no width".to_string());
+        }
+        let height = size.ceil() as usize;
+        let baseline = scaled.ascent().round();
+        Ok(OutlineFont {
+            font,
+            size,
+            scale,
+            width,
+            height,
+            baseline,
+        })
+    }
+    /// The font file, for shaping.
+    pub fn data(&self) -> &[u8] {
+        self.font.as_slice()
+    }
+    /// `(x, y)` pixels per font unit, which is what shaping gives positions in.
+    pub fn units_to_pixels(&self) -> (f32, f32) {
+        let scaled = self.font.as_scaled(self.scale);
+        (scaled.h_scale_factor(), scaled.v_scale_factor())
+    }
+    /// `glyphs` drawn across `cells` cells side by side, as one byte of coverage per pixel, rows top to bottom. Anything outside them is cut off.
+    pub fn draw_glyphs(&self, glyphs: &[PlacedGlyph], cells: usize) -> Vec<u8> {
+        let width = self.width * cells;
+        let mut coverage = vec![0u8; width * self.height];
+        for placed in glyphs {
+            let position = point(placed.x as f32, self.baseline + placed.y as f32);
+            let glyph = GlyphId(placed.id).with_scale_and_position(self.scale, position);
+            //Blank glyphs like space have no outline, but are still glyphs
+            if let Some(outlined) = self.font.outline_glyph(glyph) {
+                let bounds = outlined.px_bounds();
+                outlined.draw(|x, y, c| {
+                    let x = bounds.min.x as i32 + x as i32;
+                    let y = bounds.min.y as i32 + y as i32;
+                    if (0..width as i32).contains(&x) && (0..self.height as i32).contains(&y) {
+                        let pixel = &mut coverage[y as usize * width + x as usize];
+                        *pixel = pixel.saturating_add((c.clamp(0.0, 1.0) * 255.0).round() as u8);
+                    }
+                });
+            }
+        }
+        coverage
+    }
+}
+
+impl Font for OutlineFont {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+    fn glyph_count(&self) -> usize {
+        self.font.glyph_count()
+    }
+    fn glyph_index(&self, c: char) -> Option<usize> {
+        //Glyph 0 is .notdef, which means the font doesn't have it
+        Some(self.font.glyph_id(c).0 as usize).filter(|&i| i != 0)
+    }
+    fn glyph(&self, index: usize) -> Option<Glyph<'_>> {
+        let id = u16::try_from(index).ok()?;
+        let glyph = PlacedGlyph { id, x: 0, y: 0 };
+        Some(Glyph::Coverage(self.draw_glyphs(&[glyph], 1)))
+    }
+    fn scaled(&self, factor: f32) -> Option<Result<Box<dyn Font + Send>, String>> {
+        let font = OutlineFont::parse(self.font.as_slice().to_vec(), self.size * factor);
+        Some(font.map(|f| Box::new(f) as _))
+    }
+    fn outline(&self) -> Option<&OutlineFont> {
+        Some(self)
+    }
+}
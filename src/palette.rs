@@ -0,0 +1,225 @@
+use std::cell::RefCell;
+
+use crate::default_pal::{PAL_16, PAL_256};
+
+/// The levels each channel of the 6×6×6 colour cube (entries 16 to 231) steps through.
+pub const CUBE_LEVELS: [u8; 6] = [0x00, 0x5F, 0x87, 0xAF, 0xD7, 0xFF];
+
+/// Packs channels into 0xRRGGBB.
+pub const fn rgb(r: u8, g: u8, b: u8) -> u32 {
+    ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+}
+
+/// Splits 0xRRGGBB into `[r, g, b]`.
+pub const fn channels(rgb: u32) -> [u8; 3] {
+    let [_, r, g, b] = rgb.to_be_bytes();
+    [r, g, b]
+}
+
+/// An sRGB channel to linear light, 0.0 to 1.0.
+pub fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// The other way round from [`srgb_to_linear`], clamping anything out of range.
+pub fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+/// 0xRRGGBB to what the shaders read out of a colour attribute.
+///
+/// The shaders take the four bytes of each `u32` in memory order as `xyzw` and swizzle with `zyxw`, so the bytes have to be laid out blue, green, red, alpha.
+/// Everything that hands a colour to the GPU goes through here, so there's only one place to get the order wrong.
+pub const fn rgb_to_gpu(rgb: u32) -> u32 {
+    let [r, g, b] = channels(rgb);
+    u32::from_ne_bytes([b, g, r, 0xFF])
+}
+
+/// The other way round from [`rgb_to_gpu`], dropping alpha.
+pub const fn gpu_to_rgb(gpu: u32) -> u32 {
+    let [b, g, r, _] = gpu.to_ne_bytes();
+    rgb(r, g, b)
+}
+
+/// The 256 colour palette, every entry 0xRRGGBB.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Palette([u32; 256]);
+
+impl Palette {
+    /// The xterm palette: [`PAL_16`], then the cube, then the grey ramp.
+    pub const XTERM: Palette = Palette::with_base(&PAL_16);
+
+    /// Uses `base` for the first 16 colours and generates the rest the way xterm does.
+    pub const fn with_base(base: &[u32; 16]) -> Palette {
+        let mut colors = [0u32; 256];
+        let mut i = 0;
+        while i < 16 {
+            colors[i] = base[i] & 0xFFFFFF;
+            i += 1;
+        }
+        while i < 232 {
+            let n = i - 16;
+            colors[i] = rgb(
+                CUBE_LEVELS[n / 36],
+                CUBE_LEVELS[(n / 6) % 6],
+                CUBE_LEVELS[n % 6],
+            );
+            i += 1;
+        }
+        while i < 256 {
+            let level = 8 + 10 * (i - 232) as u8;
+            colors[i] = rgb(level, level, level);
+            i += 1;
+        }
+        Palette(colors)
+    }
+    pub const fn from_array(colors: [u32; 256]) -> Palette {
+        Palette(colors)
+    }
+    pub const fn as_array(&self) -> &[u32; 256] {
+        &self.0
+    }
+    /// 0xRRGGBB
+    pub const fn get(&self, index: u8) -> u32 {
+        self.0[index as usize]
+    }
+    pub fn set(&mut self, index: u8, rgb: u32) {
+        self.0[index as usize] = rgb & 0xFFFFFF;
+    }
+    /// The entry ready to hand to the GPU, see [`rgb_to_gpu`].
+    pub const fn gpu(&self, index: u8) -> u32 {
+        rgb_to_gpu(self.get(index))
+    }
+    /// The index of the entry closest to `rgb`.
+    pub fn nearest(&self, rgb: u32) -> u8 {
+        self.nearest_in(rgb, 0..=255)
+    }
+    /// Like [`Palette::nearest`], only looking at some of the entries, e.g. `0..=15` for the ANSI colours.
+    pub fn nearest_in(&self, rgb: u32, range: std::ops::RangeInclusive<u8>) -> u8 {
+        range
+            .min_by_key(|&i| distance_squared(self.get(i), rgb))
+            .unwrap_or(0)
+    }
+    /// Like [`Palette::nearest_in`], but by how different the colours look rather than how different the numbers are. Slower.
+    pub fn nearest_perceptual_in(&self, rgb: u32, range: std::ops::RangeInclusive<u8>) -> u8 {
+        let target = oklab(rgb);
+        PALETTE_OKLAB.with_borrow_mut(|(palette, entries)| {
+            if palette != self {
+                *palette = *self;
+                *entries = palette.0.map(oklab);
+            }
+            range
+                .map(|i| (i, oklab_distance_squared(entries[i as usize], target)))
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map_or(0, |(i, _)| i)
+        })
+    }
+}
+
+thread_local! {
+    /// Every entry of the last palette [`Palette::nearest_perceptual_in`] looked in, in Oklab.
+    /// It only changes when something sets a colour, so there's no need to work them all out again for every colour snapped.
+    static PALETTE_OKLAB: RefCell<(Palette, [[f32; 3]; 256])> =
+        RefCell::new((Palette::XTERM, Palette::XTERM.0.map(oklab)));
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::XTERM
+    }
+}
+
+/// Plain squared distance in RGB.
+pub fn distance_squared(a: u32, b: u32) -> u32 {
+    channels(a)
+        .iter()
+        .zip(channels(b))
+        .map(|(&a, b)| (a as i32 - b as i32).pow(2) as u32)
+        .sum()
+}
+
+/// 0xRRGGBB in Oklab, where equal distances look about equally different.
+pub fn oklab(color: u32) -> [f32; 3] {
+    let [r, g, b] = channels(color).map(srgb_to_linear);
+    let l = (0.41222146 * r + 0.53633255 * g + 0.051445995 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.10739696 * b).cbrt();
+    let s = (0.08830246 * r + 0.28171885 * g + 0.6299787 * b).cbrt();
+    [
+        0.21045426 * l + 0.7936178 * m - 0.004072047 * s,
+        1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+        0.025904037 * l + 0.78277177 * m - 0.80867577 * s,
+    ]
+}
+
+pub fn oklab_distance_squared(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (0..3).map(|i| (a[i] - b[i]).powi(2)).sum()
+}
+
+/// How different two 0xRRGGBB colours look.
+pub fn perceptual_distance(a: u32, b: u32) -> f32 {
+    oklab_distance_squared(oklab(a), oklab(b)).sqrt()
+}
+
+//The generated palette has to match the table everyone knows, or colours will be subtly off
+const _: () = {
+    let mut i = 0;
+    while i < 256 {
+        assert!(Palette::XTERM.0[i] == PAL_256[i]);
+        i += 1;
+    }
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What [`Palette::nearest_perceptual_in`] should find, worked out the slow way.
+    fn nearest_by_hand(pal: &Palette, rgb: u32, range: std::ops::RangeInclusive<u8>) -> u8 {
+        range
+            .min_by(|&a, &b| {
+                perceptual_distance(pal.get(a), rgb)
+                    .total_cmp(&perceptual_distance(pal.get(b), rgb))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn nearest_perceptual_matches_working_it_out() {
+        let pal = Palette::XTERM;
+        for rgb in [0x000000, 0xFF0000, 0x2E3440, 0x88C0D0, 0x7F7F7F, 0xFFB000] {
+            assert_eq!(
+                pal.nearest_perceptual_in(rgb, 0..=255),
+                nearest_by_hand(&pal, rgb, 0..=255)
+            );
+            assert_eq!(
+                pal.nearest_perceptual_in(rgb, 0..=15),
+                nearest_by_hand(&pal, rgb, 0..=15)
+            );
+        }
+        assert_eq!(pal.nearest_perceptual_in(0xFF0000, 0..=15), 9);
+    }
+
+    #[test]
+    fn nearest_perceptual_follows_changes_to_the_palette() {
+        let mut pal = Palette::XTERM;
+        pal.set(3, 0xFF0000);
+        assert_eq!(pal.nearest_perceptual_in(0xFF0000, 0..=15), 3);
+        assert_eq!(Palette::XTERM.nearest_perceptual_in(0xFF0000, 0..=15), 9);
+        //Entry 9 is red as well, and would win a tie
+        pal.set(3, 0x0000FF);
+        pal.set(9, 0x0000FF);
+        pal.set(12, 0xFF0000);
+        assert_eq!(pal.nearest_perceptual_in(0xFF0000, 0..=15), 12);
+    }
+}
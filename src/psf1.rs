@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use crate::font::{Font, Glyph};
+
+const MAGIC: [u8; 2] = [0x36, 0x04];
+/// 512 glyphs instead of 256.
+const MODE_512: u8 = 0x01;
+/// A unicode table follows the glyphs.
+const MODE_HAS_TAB: u8 = 0x02;
+const TABLE_SEPARATOR: u16 = 0xFFFF;
+/// Starts a multi-codepoint sequence, which we can't draw, so everything after it for that glyph is skipped.
+const TABLE_START_SEQ: u16 = 0xFFFE;
+
+/// A PSF version 1 console font, as shipped by kbd and friends. Always 8 pixels wide.
+pub struct Psf1Font {
+    height: usize,
+    glyph_count: usize,
+    glyphs: Vec<u8>,
+    /// Empty if the file didn't have a unicode table, in which case characters map straight to glyph indices.
+    unicode: HashMap<char, usize>,
+}
+
+impl Psf1Font {
+    pub fn parse(data: &[u8]) -> Result<Psf1Font, String> {
+        let [m0, m1, mode, height, rest @ ..] = data else {
+            return Err("Too short to be a PSF1 font".to_string());
+        };
+        if [*m0, *m1] != MAGIC {
+            return Err("Not a PSF1 font (bad magic)".to_string());
+        }
+        let height = *height as usize;
+        if height == 0 {
+            return Err("PSF1 font has zero height glyphs".to_string());
+        }
+        let glyph_count = if mode & MODE_512 != 0 { 512 } else { 256 };
+        let glyphs_len = glyph_count * height;
+        if rest.len() < glyphs_len {
+            return Err(format!(
+                "PSF1 font is truncated, expected {glyphs_len} bytes of glyphs but only got {}",
+                rest.len()
+            ));
+        }
+        let (glyphs, table) = rest.split_at(glyphs_len);
+        let mut unicode = HashMap::new();
+        if mode & MODE_HAS_TAB != 0 {
+            let mut entries = table
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+            for index in 0..glyph_count {
+                let mut in_sequence = false;
+                for entry in entries.by_ref() {
+                    match entry {
+                        TABLE_SEPARATOR => break,
+                        TABLE_START_SEQ => in_sequence = true,
+                        _ if in_sequence => {}
+                        codepoint => {
+                            if let Some(c) = char::from_u32(codepoint as u32) {
+                                //The first glyph to claim a character keeps it
+                                unicode.entry(c).or_insert(index);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Psf1Font {
+            height,
+            glyph_count,
+            glyphs: glyphs.to_vec(),
+            unicode,
+        })
+    }
+}
+
+impl Font for Psf1Font {
+    fn dimensions(&self) -> (usize, usize) {
+        (8, self.height)
+    }
+    fn glyph_count(&self) -> usize {
+        self.glyph_count
+    }
+    fn glyph_index(&self, c: char) -> Option<usize> {
+        if self.unicode.is_empty() {
+            return Some(c as usize).filter(|&i| i < self.glyph_count);
+        }
+        self.unicode.get(&c).copied()
+    }
+    fn glyph(&self, index: usize) -> Option<Glyph<'_>> {
+        self.glyphs
+            .get(index * self.height..(index + 1) * self.height)
+            .map(Glyph::Bitmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 256 glyphs `height` rows tall, each filled with its own index, then `table`.
+    fn psf1(mode: u8, height: u8, table: &[u16]) -> Vec<u8> {
+        let mut data = vec![MAGIC[0], MAGIC[1], mode, height];
+        for glyph in 0..=255u8 {
+            data.extend(std::iter::repeat_n(glyph, height as usize));
+        }
+        for entry in table {
+            data.extend(entry.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn glyphs_come_out_by_index() {
+        let font = Psf1Font::parse(&psf1(0, 2, &[])).unwrap();
+        assert_eq!(font.dimensions(), (8, 2));
+        assert_eq!(font.glyph_count(), 256);
+        assert!(matches!(font.glyph(65), Some(Glyph::Bitmap([65, 65]))));
+        assert!(font.glyph(256).is_none());
+        assert_eq!(font.glyph_index('A'), Some(65));
+        assert_eq!(font.glyph_index('\u{100}'), None);
+    }
+
+    #[test]
+    fn the_unicode_table_maps_characters() {
+        //Glyph 0 is '€' and 'E', glyph 1 is 'x' and a sequence that's skipped, the rest have nothing
+        let mut table = vec![0x20AC, 0x45, TABLE_SEPARATOR];
+        table.extend([0x78, TABLE_START_SEQ, 0x79, 0x300, TABLE_SEPARATOR]);
+        table.extend([TABLE_SEPARATOR; 254]);
+        let font = Psf1Font::parse(&psf1(MODE_HAS_TAB, 1, &table)).unwrap();
+        assert_eq!(font.glyph_index('€'), Some(0));
+        assert_eq!(font.glyph_index('E'), Some(0));
+        assert_eq!(font.glyph_index('x'), Some(1));
+        assert_eq!(font.glyph_index('y'), None);
+        assert_eq!(font.glyph_index('A'), None);
+    }
+
+    #[test]
+    fn bad_fonts_are_refused() {
+        assert!(Psf1Font::parse(&[0x36, 0x04, 0]).is_err());
+        assert!(Psf1Font::parse(&psf1(0, 0, &[])).is_err());
+        let mut bad_magic = psf1(0, 1, &[]);
+        bad_magic[0] = 0x37;
+        assert!(Psf1Font::parse(&bad_magic).is_err());
+        //Says 512 glyphs, only has 256
+        assert!(Psf1Font::parse(&psf1(MODE_512, 1, &[])).is_err());
+        let mut truncated = psf1(0, 2, &[]);
+        truncated.pop();
+        assert!(Psf1Font::parse(&truncated).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use crate::font::{Font, Glyph, MAX_GLYPH_SIZE};
+
+const MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const HEADER_LEN: usize = 32;
+/// A unicode table follows the glyphs.
+const FLAG_HAS_TAB: u32 = 0x01;
+const TABLE_SEPARATOR: u8 = 0xFF;
+/// Starts a multi-codepoint sequence, which we can't draw, so everything after it for that glyph is skipped.
+const TABLE_START_SEQ: u8 = 0xFE;
+
+/// A PSF version 2 console font loaded from a file. The built in Terminus comes from the `psf2-font` crate instead.
+pub struct Psf2Font {
+    width: usize,
+    height: usize,
+    glyph_len: usize,
+    glyph_count: usize,
+    glyphs: Vec<u8>,
+    /// Empty if the file didn't have a unicode table, in which case characters map straight to glyph indices.
+    unicode: HashMap<char, usize>,
+}
+
+impl Psf2Font {
+    pub fn parse(data: &[u8]) -> Result<Psf2Font, String> {
+        if data.len() < HEADER_LEN {
+            return Err("Too short to be a PSF2 font".to_string());
+        }
+        if data[..4] != MAGIC {
+            return Err("Not a PSF2 font (bad magic)".to_string());
+        }
+        let field = |n: usize| {
+            let at = 4 + n * 4;
+            u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+        };
+        let [
+            _version,
+            header_len,
+            flags,
+            glyph_count,
+            glyph_len,
+            height,
+            width,
+        ] = std::array::from_fn(field);
+        let [header_len, glyph_count, glyph_len, height, width] =
+            [header_len, glyph_count, glyph_len, height, width].map(|x| x as usize);
+        if width == 0 || height == 0 {
+            return Err(format!("PSF2 font has {width}x{height} glyphs"));
+        }
+        //Before they're multiplied together, which could wrap on a 32 bit usize
+        if width > MAX_GLYPH_SIZE || height > MAX_GLYPH_SIZE {
+            return Err(format!(
+                "Glyphs are {width}x{height}, bigger than {MAX_GLYPH_SIZE}x{MAX_GLYPH_SIZE}"
+            ));
+        }
+        if glyph_len < width.div_ceil(8) * height {
+            return Err(format!(
+                "PSF2 font says glyphs are {glyph_len} bytes, too small for {width}x{height}"
+            ));
+        }
+        let glyphs_len = glyph_count
+            .checked_mul(glyph_len)
+            .ok_or("PSF2 font has an impossible number of glyphs")?;
+        let Some(rest) = data.get(header_len..) else {
+            return Err(format!(
+                "PSF2 header says it's {header_len} bytes long, but the file isn't"
+            ));
+        };
+        if rest.len() < glyphs_len {
+            return Err(format!(
+                "PSF2 font is truncated, expected {glyphs_len} bytes of glyphs but only got {}",
+                rest.len()
+            ));
+        }
+        let (glyphs, table) = rest.split_at(glyphs_len);
+        let mut unicode = HashMap::new();
+        if flags & FLAG_HAS_TAB != 0 {
+            let entries = table.split(|&b| b == TABLE_SEPARATOR);
+            for (index, entry) in entries.take(glyph_count).enumerate() {
+                let singles = entry.split(|&b| b == TABLE_START_SEQ).next().unwrap_or(&[]);
+                //Anything that isn't valid UTF-8 is skipped rather than rejecting the whole font
+                for c in String::from_utf8_lossy(singles).chars() {
+                    if c != char::REPLACEMENT_CHARACTER {
+                        //The first glyph to claim a character keeps it
+                        unicode.entry(c).or_insert(index);
+                    }
+                }
+            }
+        }
+        Ok(Psf2Font {
+            width,
+            height,
+            glyph_len,
+            glyph_count,
+            glyphs: glyphs.to_vec(),
+            unicode,
+        })
+    }
+}
+
+impl Font for Psf2Font {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+    fn glyph_count(&self) -> usize {
+        self.glyph_count
+    }
+    fn glyph_index(&self, c: char) -> Option<usize> {
+        if self.unicode.is_empty() {
+            return Some(c as usize).filter(|&i| i < self.glyph_count);
+        }
+        self.unicode.get(&c).copied()
+    }
+    fn glyph(&self, index: usize) -> Option<Glyph<'_>> {
+        //Anything past the rows is padding
+        let rows = self.width.div_ceil(8) * self.height;
+        self.glyphs
+            .get(index * self.glyph_len..index * self.glyph_len + rows)
+            .map(Glyph::Bitmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header for `count` glyphs of `width` by `height`, `glyph_len` bytes each, then `glyphs`.
+    fn psf2(width: u32, height: u32, count: u32, glyph_len: u32, glyphs: &[u8]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        for field in [0, HEADER_LEN as u32, 0, count, glyph_len, height, width] {
+            data.extend(field.to_le_bytes());
+        }
+        data.extend(glyphs);
+        data
+    }
+
+    #[test]
+    fn glyphs_come_out_by_index() {
+        let font = Psf2Font::parse(&psf2(8, 2, 2, 2, &[1, 2, 3, 4])).unwrap();
+        assert_eq!(font.dimensions(), (8, 2));
+        assert_eq!(font.glyph_count(), 2);
+        assert!(matches!(font.glyph(1), Some(Glyph::Bitmap([3, 4]))));
+        assert_eq!(font.glyph_index('\u{1}'), Some(1));
+        assert_eq!(font.glyph_index('\u{2}'), None);
+    }
+
+    #[test]
+    fn huge_glyphs_are_refused_before_they_are_sized() {
+        //2^31 / 8 * 16 wraps to zero on a 32 bit usize, which a zero glyph_len would pass
+        let data = psf2(0x8000_0000, 0x10, 1, 0, &[]);
+        let error = Psf2Font::parse(&data).err().unwrap();
+        assert!(error.contains("bigger than"), "{error}");
+    }
+
+    #[test]
+    fn truncated_fonts_are_refused() {
+        assert!(Psf2Font::parse(&psf2(8, 2, 2, 2, &[1, 2, 3])).is_err());
+        assert!(Psf2Font::parse(&MAGIC).is_err());
+        assert!(Psf2Font::parse(&psf2(8, 2, 1, 1, &[1, 2])).is_err());
+    }
+}
@@ -0,0 +1,714 @@
+use std::{cmp::Ordering, ops::Range};
+
+use vt100::Parser;
+
+use crate::{callbacks::TermCallbacks, image_layer::ImageLayer};
+
+/// Something in the stream that can move or wipe what's on screen, found by [`ScrollScanner::feed`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScreenChange {
+    /// LF, VT or FF, which scroll when the cursor is on the bottom margin.
+    LineFeed,
+    /// `ESC D`, which scrolls like a line feed.
+    Index,
+    /// `ESC E`, a carriage return and a line feed.
+    NextLine,
+    /// `ESC M`, which scrolls down when the cursor is on the top margin.
+    ReverseIndex,
+    /// `CSI n S`
+    ScrollUp(u16),
+    /// `CSI n T`
+    ScrollDown(u16),
+    /// `CSI n L`, which scrolls the cursor's row and the ones under it down.
+    InsertLines(u16),
+    /// `CSI n M`, which scrolls them up.
+    DeleteLines(u16),
+    /// `CSI top ; bottom r`, as sent: counting from 1, with 0 for a missing number.
+    SetScrollRegion(u16, u16),
+    /// `CSI 2 J`. `CSI 3 J` only clears the scrollback, so it isn't one.
+    EraseDisplay,
+    /// `ESC c`
+    Reset,
+}
+
+/// What [`ScrollScanner::feed`] hands back. Between them they're every byte it was given, in order.
+#[derive(PartialEq, Eq, Debug)]
+pub enum ScanEvent<'a> {
+    Text(&'a [u8]),
+    /// Characters to print, outside of any sequence, which can scroll the screen by wrapping at the bottom margin.
+    Print(&'a [u8]),
+    /// The last byte of a [`ScreenChange`], on its own so the screen can be looked at just before the parser acts on it.
+    Change(u8, ScreenChange),
+    /// A BEL ending an OSC string, on its own so answers to the OSC can end with BEL as well.
+    OscBell,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    /// After an intermediate byte, like the `(` in `ESC ( B`.
+    EscapeIntermediate,
+    Csi,
+    /// OSC, SOS and PM, which run until ST or BEL.
+    String,
+    StringEscape,
+}
+
+/// Follows escape sequences closely enough to spot the ones that scroll or clear the screen, however they're split up between reads.
+#[derive(Default)]
+pub struct ScrollScanner {
+    state: State,
+    params: Vec<u16>,
+    /// The CSI has a private marker or intermediate bytes, so it isn't one of ours.
+    private: bool,
+}
+
+impl ScrollScanner {
+    pub fn feed(&mut self, bytes: &[u8], mut on_event: impl FnMut(ScanEvent)) {
+        let run = |bytes, printing| match printing {
+            true => ScanEvent::Print(bytes),
+            false => ScanEvent::Text(bytes),
+        };
+        let mut text_start = 0;
+        let mut printing = false;
+        for (i, &b) in bytes.iter().enumerate() {
+            let bell = self.state == State::String && b == 0x07;
+            let print = self.state == State::Ground && b >= 0x20 && b != 0x7F;
+            let event = match self.step(b) {
+                Some(change) => ScanEvent::Change(b, change),
+                None if bell => ScanEvent::OscBell,
+                None => {
+                    if print != printing {
+                        if text_start < i {
+                            on_event(run(&bytes[text_start..i], printing));
+                        }
+                        text_start = i;
+                        printing = print;
+                    }
+                    continue;
+                }
+            };
+            if text_start < i {
+                on_event(run(&bytes[text_start..i], printing));
+            }
+            on_event(event);
+            text_start = i + 1;
+            printing = false;
+        }
+        if text_start < bytes.len() {
+            on_event(run(&bytes[text_start..], printing));
+        }
+    }
+    fn step(&mut self, b: u8) -> Option<ScreenChange> {
+        match (self.state, b) {
+            //CAN and SUB cancel whatever sequence is going
+            (_, 0x18 | 0x1A) => self.state = State::Ground,
+            (State::String, 0x07) => self.state = State::Ground,
+            (State::String, 0x1B) => self.state = State::StringEscape,
+            (State::String, _) => {}
+            (State::StringEscape, b'\\') => self.state = State::Ground,
+            (State::StringEscape, _) => self.state = State::String,
+            //Everywhere else, control characters happen straight away, even in the middle of a sequence
+            (_, b'\n' | 0x0B | 0x0C) => return Some(ScreenChange::LineFeed),
+            (_, 0x1B) => self.state = State::Escape,
+            (State::Ground, _) => {}
+            (State::Escape, b'[') => {
+                self.state = State::Csi;
+                self.params.clear();
+                self.private = false;
+            }
+            (State::Escape, b']' | b'X' | b'^') => self.state = State::String,
+            (State::Escape | State::EscapeIntermediate, 0x20..=0x2F) => {
+                self.state = State::EscapeIntermediate
+            }
+            (State::Escape, 0x30..=0x7E) => {
+                self.state = State::Ground;
+                return match b {
+                    b'D' => Some(ScreenChange::Index),
+                    b'E' => Some(ScreenChange::NextLine),
+                    b'M' => Some(ScreenChange::ReverseIndex),
+                    b'c' => Some(ScreenChange::Reset),
+                    _ => None,
+                };
+            }
+            (State::EscapeIntermediate, 0x30..=0x7E) => self.state = State::Ground,
+            (State::Csi, b'0'..=b'9') => {
+                if self.params.is_empty() {
+                    self.params.push(0);
+                }
+                let param = self.params.last_mut().expect("Pushed above");
+                *param = param.saturating_mul(10).saturating_add((b - b'0') as u16);
+            }
+            (State::Csi, b';') => {
+                if self.params.is_empty() {
+                    self.params.push(0);
+                }
+                self.params.push(0);
+            }
+            (State::Csi, b':' | 0x20..=0x2F | 0x3C..=0x3F) => self.private = true,
+            (State::Csi, 0x40..=0x7E) => {
+                self.state = State::Ground;
+                if self.private {
+                    return None;
+                }
+                let param = |i: usize| self.params.get(i).copied().unwrap_or(0);
+                return match b {
+                    b'S' => Some(ScreenChange::ScrollUp(param(0).max(1))),
+                    b'T' => Some(ScreenChange::ScrollDown(param(0).max(1))),
+                    b'L' => Some(ScreenChange::InsertLines(param(0).max(1))),
+                    b'M' => Some(ScreenChange::DeleteLines(param(0).max(1))),
+                    b'r' => Some(ScreenChange::SetScrollRegion(param(0), param(1))),
+                    b'J' if param(0) == 2 => Some(ScreenChange::EraseDisplay),
+                    _ => None,
+                };
+            }
+            (State::Escape | State::EscapeIntermediate | State::Csi, _) => {}
+        }
+        None
+    }
+}
+
+/// What a [`ScreenChange`] does to the images on screen.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ImageChange {
+    None,
+    /// Everything in these rows moves up this many rows.
+    ScrollUp(Range<usize>, usize),
+    ScrollDown(Range<usize>, usize),
+    Clear,
+}
+
+/// The scroll margins of both screens, which vt100 doesn't let us see, kept in step by following the same rules it does.
+pub struct ScrollRegions {
+    rows: u16,
+    /// Top and bottom rows, inclusive, of the main screen then the alternate screen.
+    regions: [(u16, u16); 2],
+}
+
+impl ScrollRegions {
+    pub fn new(rows: u16) -> ScrollRegions {
+        ScrollRegions {
+            rows,
+            regions: [(0, rows - 1); 2],
+        }
+    }
+    /// Margins that went to the bottom of the screen still do, like vt100's.
+    pub fn resize(&mut self, rows: u16) {
+        for (top, bottom) in &mut self.regions {
+            if *bottom == self.rows - 1 {
+                *bottom = rows - 1;
+            }
+            *bottom = (*bottom).min(rows - 1);
+            if *bottom < *top {
+                *top = 0;
+            }
+        }
+        self.rows = rows;
+    }
+    /// The bottom margin of the main or `alternate` screen.
+    pub fn bottom(&self, alternate: bool) -> u16 {
+        self.regions[alternate as usize].1
+    }
+    /// Keeps track of `change` on the main or `alternate` screen, where the cursor was on `cursor_row` just before it.
+    pub fn apply(&mut self, change: ScreenChange, alternate: bool, cursor_row: u16) -> ImageChange {
+        let region = &mut self.regions[alternate as usize];
+        let (top, bottom) = *region;
+        let rows = top as usize..bottom as usize + 1;
+        match change {
+            ScreenChange::LineFeed | ScreenChange::Index | ScreenChange::NextLine
+                if cursor_row == bottom =>
+            {
+                ImageChange::ScrollUp(rows, 1)
+            }
+            //Above the top margin still scrolls it, that's just what vt100 does
+            ScreenChange::ReverseIndex if cursor_row == top || cursor_row == 0 => {
+                ImageChange::ScrollDown(rows, 1)
+            }
+            ScreenChange::ScrollUp(n) => {
+                ImageChange::ScrollUp(rows, n.min(self.rows - top) as usize)
+            }
+            ScreenChange::ScrollDown(n) => ImageChange::ScrollDown(rows, n as usize),
+            //Above the top margin too, like ESC M. Below the bottom one vt100 shuffles rows in a way no other terminal does, and that isn't followed
+            ScreenChange::InsertLines(n) if cursor_row <= bottom => {
+                ImageChange::ScrollDown(cursor_row as usize..rows.end, n as usize)
+            }
+            ScreenChange::DeleteLines(n) if cursor_row <= bottom => ImageChange::ScrollUp(
+                cursor_row as usize..rows.end,
+                n.min(self.rows - cursor_row) as usize,
+            ),
+            ScreenChange::SetScrollRegion(new_top, new_bottom) => {
+                let new_top = new_top.max(1) - 1;
+                let new_bottom = match new_bottom {
+                    0 => self.rows,
+                    b => b,
+                };
+                let new_bottom = (new_bottom - 1).min(self.rows - 1);
+                *region = match new_top < new_bottom {
+                    true => (new_top, new_bottom),
+                    false => (0, self.rows - 1),
+                };
+                ImageChange::None
+            }
+            ScreenChange::EraseDisplay => ImageChange::Clear,
+            ScreenChange::Reset => {
+                self.regions = [(0, self.rows - 1); 2];
+                ImageChange::Clear
+            }
+            ScreenChange::LineFeed
+            | ScreenChange::Index
+            | ScreenChange::NextLine
+            | ScreenChange::ReverseIndex
+            | ScreenChange::InsertLines(_)
+            | ScreenChange::DeleteLines(_) => ImageChange::None,
+        }
+    }
+}
+
+/// Moves images along with the text, since vt100 doesn't say when it scrolls.
+///
+/// vt100 doesn't say how an OSC ended either, so this also tells [`TermCallbacks`] when it was with BEL.
+pub struct ScrollTracker {
+    scanner: ScrollScanner,
+    regions: ScrollRegions,
+}
+
+impl ScrollTracker {
+    pub fn new(rows: u16) -> ScrollTracker {
+        ScrollTracker {
+            scanner: ScrollScanner::default(),
+            regions: ScrollRegions::new(rows),
+        }
+    }
+    pub fn resize(&mut self, rows: u16) {
+        self.regions.resize(rows);
+    }
+    /// Passes `text` on to `parser`, scrolling and clearing `images` to match what it does to the text.
+    pub fn process(
+        &mut self,
+        parser: &mut Parser<TermCallbacks>,
+        images: &mut ImageLayer,
+        text: &[u8],
+    ) {
+        let regions = &mut self.regions;
+        self.scanner.feed(text, |event| match event {
+            ScanEvent::Text(text) => {
+                parser.process(text);
+                images.set_alternate(parser.screen().alternate_screen());
+            }
+            ScanEvent::Print(text) => print(parser, images, regions, text),
+            ScanEvent::Change(byte, change) => {
+                let screen = parser.screen();
+                let alternate = screen.alternate_screen();
+                let cursor_row = screen.cursor_position().0;
+                parser.process(&[byte]);
+                //vt100 leaves these to the callbacks, which can't move the cursor, so it's given the line feeds they amount to
+                match change {
+                    ScreenChange::Index => parser.process(b"\n"),
+                    ScreenChange::NextLine => parser.process(b"\r\n"),
+                    _ => {}
+                }
+                images.set_alternate(parser.screen().alternate_screen());
+                match regions.apply(change, alternate, cursor_row) {
+                    ImageChange::None => {}
+                    ImageChange::ScrollUp(rows, n) => images.scroll_up(rows, n),
+                    ImageChange::ScrollDown(rows, n) => images.scroll_down(rows, n),
+                    ImageChange::Clear => images.clear(),
+                }
+            }
+            ScanEvent::OscBell => {
+                parser.callbacks_mut().osc_bell = true;
+                parser.process(b"\x07");
+                parser.callbacks_mut().osc_bell = false;
+            }
+        });
+    }
+}
+
+/// Passes printed `text` on to `parser`, scrolling `images` whenever it wraps past the end of the bottom margin.
+///
+/// vt100 keeps the cursor past the last column until the next character wraps it, so a character that moves the cursor back along the bottom margin wrapped and scrolled.
+fn print(
+    parser: &mut Parser<TermCallbacks>,
+    images: &mut ImageLayer,
+    regions: &mut ScrollRegions,
+    mut text: &[u8],
+) {
+    let cols = parser.screen().size().1 as usize;
+    while !text.is_empty() {
+        let screen = parser.screen();
+        let alternate = screen.alternate_screen();
+        let (row, col) = screen.cursor_position();
+        let bottom = regions.bottom(alternate);
+        //No byte moves the cursor more than a column, so it takes most of a row's worth to wrap onto the next row.
+        //Nothing can scroll until the bottom margin is reached, and never from below it.
+        let len = match row.cmp(&bottom) {
+            Ordering::Greater => text.len(),
+            Ordering::Less => ((bottom - row) as usize * cols.saturating_sub(2)).max(1),
+            //One character, with the rest of its UTF-8
+            Ordering::Equal => 1 + text[1..].iter().take_while(|&&b| b & 0xC0 == 0x80).count(),
+        };
+        let (now, rest) = text.split_at(len.min(text.len()));
+        parser.process(now);
+        text = rest;
+        let (new_row, new_col) = parser.screen().cursor_position();
+        if row == bottom && new_row == bottom && new_col < col {
+            //Wrapping is a line feed as far as scrolling goes
+            if let ImageChange::ScrollUp(rows, n) =
+                regions.apply(ScreenChange::LineFeed, alternate, row)
+            {
+                images.scroll_up(rows, n);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::image_layer::Image;
+
+    fn scan(scanner: &mut ScrollScanner, bytes: &[u8]) -> Vec<String> {
+        let mut events = Vec::new();
+        scanner.feed(bytes, |event| {
+            events.push(match event {
+                ScanEvent::Text(text) => format!("{:?}", String::from_utf8_lossy(text)),
+                ScanEvent::Print(text) => format!("print {:?}", String::from_utf8_lossy(text)),
+                ScanEvent::Change(byte, change) => format!("{:?} {change:?}", byte as char),
+                ScanEvent::OscBell => "OscBell".into(),
+            })
+        });
+        events
+    }
+
+    #[test]
+    fn changes_come_out_on_their_own() {
+        let mut scanner = ScrollScanner::default();
+        assert_eq!(
+            scan(&mut scanner, b"ab\nc\x1B[3Sd\x1BM\x1B[5;10r"),
+            [
+                r#"print "ab""#,
+                "'\\n' LineFeed",
+                r#"print "c""#,
+                r#""\u{1b}[3""#,
+                "'S' ScrollUp(3)",
+                r#"print "d""#,
+                r#""\u{1b}""#,
+                "'M' ReverseIndex",
+                r#""\u{1b}[5;10""#,
+                "'r' SetScrollRegion(5, 10)",
+            ]
+        );
+    }
+
+    #[test]
+    fn sequences_can_be_split_between_reads() {
+        let mut scanner = ScrollScanner::default();
+        assert_eq!(
+            scan(&mut scanner, b"x\x1B["),
+            [r#"print "x""#, r#""\u{1b}[""#]
+        );
+        assert_eq!(scan(&mut scanner, b"2"), [r#""2""#]);
+        assert_eq!(
+            scan(&mut scanner, b"Jy"),
+            ["'J' EraseDisplay", r#"print "y""#]
+        );
+    }
+
+    #[test]
+    fn other_sequences_are_left_alone() {
+        let mut scanner = ScrollScanner::default();
+        //Private modes, partial erases, charsets and line feeds in the middle of a title
+        let events = scan(
+            &mut scanner,
+            b"\x1B[?1049h\x1B[?5r\x1B[J\x1B(M\x1B]0;a\nb\x07",
+        );
+        assert_eq!(events.len(), 2, "{events:?}");
+        assert_eq!(events[1], "OscBell");
+        //CAN gets out of a string that never ends
+        assert_eq!(
+            scan(&mut scanner, b"\x1B]0;a\x18\n"),
+            [r#""\u{1b}]0;a\u{18}""#, "'\\n' LineFeed"]
+        );
+    }
+
+    #[test]
+    fn index_next_line_and_line_changes_are_spotted() {
+        let mut scanner = ScrollScanner::default();
+        assert_eq!(
+            scan(&mut scanner, b"\x1BD\x1BE\x1B[L\x1B[2M"),
+            [
+                r#""\u{1b}""#,
+                "'D' Index",
+                r#""\u{1b}""#,
+                "'E' NextLine",
+                r#""\u{1b}[""#,
+                "'L' InsertLines(1)",
+                r#""\u{1b}[2""#,
+                "'M' DeleteLines(2)",
+            ]
+        );
+        //Clearing the scrollback leaves the screen alone
+        assert_eq!(scan(&mut scanner, b"\x1B[3J"), [r#""\u{1b}[3J""#]);
+    }
+
+    #[test]
+    fn printing_comes_out_apart_from_sequences() {
+        let mut scanner = ScrollScanner::default();
+        assert_eq!(
+            scan(&mut scanner, "a\u{e9}\x1B[1mb\rc\x1B]0;d\x07".as_bytes()),
+            [
+                r#"print "aé""#,
+                r#""\u{1b}[1m""#,
+                r#"print "b""#,
+                r#""\r""#,
+                r#"print "c""#,
+                r#""\u{1b}]0;d""#,
+                "OscBell",
+            ]
+        );
+    }
+
+    #[test]
+    fn line_feeds_scroll_on_the_bottom_margin() {
+        let mut regions = ScrollRegions::new(24);
+        assert_eq!(
+            regions.apply(ScreenChange::LineFeed, false, 22),
+            ImageChange::None
+        );
+        assert_eq!(
+            regions.apply(ScreenChange::LineFeed, false, 23),
+            ImageChange::ScrollUp(0..24, 1)
+        );
+        regions.apply(ScreenChange::SetScrollRegion(5, 10), false, 0);
+        assert_eq!(
+            regions.apply(ScreenChange::LineFeed, false, 9),
+            ImageChange::ScrollUp(4..10, 1)
+        );
+        //Below the region, nothing scrolls
+        assert_eq!(
+            regions.apply(ScreenChange::LineFeed, false, 23),
+            ImageChange::None
+        );
+        //The alternate screen has its own margins
+        assert_eq!(
+            regions.apply(ScreenChange::LineFeed, true, 23),
+            ImageChange::ScrollUp(0..24, 1)
+        );
+        regions.apply(ScreenChange::SetScrollRegion(0, 0), false, 0);
+        assert_eq!(
+            regions.apply(ScreenChange::LineFeed, false, 23),
+            ImageChange::ScrollUp(0..24, 1)
+        );
+    }
+
+    #[test]
+    fn scroll_commands_move_the_region() {
+        let mut regions = ScrollRegions::new(24);
+        regions.apply(ScreenChange::SetScrollRegion(3, 30), false, 0);
+        assert_eq!(
+            regions.apply(ScreenChange::ScrollUp(2), false, 0),
+            ImageChange::ScrollUp(2..24, 2)
+        );
+        assert_eq!(
+            regions.apply(ScreenChange::ScrollDown(1), false, 0),
+            ImageChange::ScrollDown(2..24, 1)
+        );
+        assert_eq!(
+            regions.apply(ScreenChange::ReverseIndex, false, 2),
+            ImageChange::ScrollDown(2..24, 1)
+        );
+        assert_eq!(
+            regions.apply(ScreenChange::ReverseIndex, false, 5),
+            ImageChange::None
+        );
+        //A region that's too small is the whole screen
+        regions.apply(ScreenChange::SetScrollRegion(4, 4), false, 0);
+        assert_eq!(
+            regions.apply(ScreenChange::ScrollUp(1), false, 0),
+            ImageChange::ScrollUp(0..24, 1)
+        );
+    }
+
+    #[test]
+    fn inserting_and_deleting_lines_moves_the_rest_of_the_region() {
+        let mut regions = ScrollRegions::new(24);
+        regions.apply(ScreenChange::SetScrollRegion(5, 10), false, 0);
+        assert_eq!(
+            regions.apply(ScreenChange::InsertLines(2), false, 6),
+            ImageChange::ScrollDown(6..10, 2)
+        );
+        assert_eq!(
+            regions.apply(ScreenChange::DeleteLines(1), false, 9),
+            ImageChange::ScrollUp(9..10, 1)
+        );
+        //vt100 does it above the top margin too, but not below the bottom one
+        assert_eq!(
+            regions.apply(ScreenChange::InsertLines(1), false, 2),
+            ImageChange::ScrollDown(2..10, 1)
+        );
+        assert_eq!(
+            regions.apply(ScreenChange::DeleteLines(1), false, 12),
+            ImageChange::None
+        );
+    }
+
+    #[test]
+    fn resizing_keeps_margins_at_the_bottom() {
+        let mut regions = ScrollRegions::new(24);
+        regions.resize(30);
+        assert_eq!(
+            regions.apply(ScreenChange::LineFeed, false, 29),
+            ImageChange::ScrollUp(0..30, 1)
+        );
+        regions.apply(ScreenChange::SetScrollRegion(20, 25), false, 0);
+        regions.resize(10);
+        assert_eq!(
+            regions.apply(ScreenChange::LineFeed, false, 9),
+            ImageChange::ScrollUp(0..10, 1)
+        );
+    }
+
+    /// A 24 by 80 terminal with a one cell image at each of `rows`.
+    fn terminal(rows: &[u16]) -> (Parser<TermCallbacks>, ImageLayer, ScrollTracker) {
+        let parser = Parser::new_with_callbacks(24, 80, 0, TermCallbacks::default());
+        let mut images = ImageLayer::new(8, 16);
+        for &row in rows {
+            let image = Rc::new(Image {
+                width: 8,
+                height: 16,
+                rgba: vec![0; 8 * 16 * 4],
+            });
+            let key = images.new_image_key();
+            images.place(image, key, row, 0);
+        }
+        (parser, images, ScrollTracker::new(24))
+    }
+
+    fn rows(images: &ImageLayer) -> Vec<isize> {
+        images.visible().map(|p| p.row).collect()
+    }
+
+    #[test]
+    fn images_scroll_with_the_text() {
+        let (mut parser, mut images, mut tracker) = terminal(&[5]);
+        tracker.process(&mut parser, &mut images, b"\x1B[24;1H\n\n");
+        assert_eq!(rows(&images), [3]);
+        tracker.process(&mut parser, &mut images, b"\x1B[2S\x1B[T");
+        assert_eq!(rows(&images), [2]);
+    }
+
+    #[test]
+    fn images_outside_the_scroll_region_stay_put() {
+        let (mut parser, mut images, mut tracker) = terminal(&[0, 12]);
+        tracker.process(&mut parser, &mut images, b"\x1B[10;20r\x1B[20;1H\n");
+        assert_eq!(rows(&images), [0, 11]);
+        //A line feed on the last row, below the region, doesn't scroll anything
+        tracker.process(&mut parser, &mut images, b"\x1B[24;1H\n");
+        assert_eq!(rows(&images), [0, 11]);
+        tracker.process(&mut parser, &mut images, b"\x1B[2T");
+        assert_eq!(rows(&images), [0, 13]);
+    }
+
+    /// What's on row `row` of the screen.
+    fn text(parser: &Parser<TermCallbacks>, row: u16) -> String {
+        parser.screen().contents_between(row, 0, row, 80)
+    }
+
+    #[test]
+    fn index_and_next_line_scroll_like_line_feeds() {
+        let (mut parser, mut images, mut tracker) = terminal(&[5]);
+        tracker.process(&mut parser, &mut images, b"\x1B[24;5Hx\x1BD");
+        assert_eq!(rows(&images), [4]);
+        assert_eq!(text(&parser, 22), "    x");
+        assert_eq!(parser.screen().cursor_position(), (23, 5));
+        tracker.process(&mut parser, &mut images, b"\x1BE");
+        assert_eq!(rows(&images), [3]);
+        assert_eq!(text(&parser, 21), "    x");
+        assert_eq!(parser.screen().cursor_position(), (23, 0));
+        //Above the bottom margin they only move the cursor
+        tracker.process(&mut parser, &mut images, b"\x1B[1;1H\x1BD\x1BE");
+        assert_eq!(rows(&images), [3]);
+        assert_eq!(parser.screen().cursor_position(), (2, 0));
+    }
+
+    #[test]
+    fn inserted_and_deleted_lines_move_images_in_the_region() {
+        let (mut parser, mut images, mut tracker) = terminal(&[2, 6, 8, 15]);
+        tracker.process(&mut parser, &mut images, b"\x1B[5;12r\x1B[7;1H\x1B[2L");
+        //The images from the cursor's row down move, the one above the cursor and the one below the region don't
+        assert_eq!(rows(&images), [2, 8, 10, 15]);
+        tracker.process(&mut parser, &mut images, b"\x1B[9;1H\x1B[2M");
+        //Deleting the row an image starts on takes it off the screen
+        assert_eq!(rows(&images), [2, 8, 15]);
+        //vt100 inserts lines above the top margin too
+        tracker.process(&mut parser, &mut images, b"\x1B[1;1H\x1B[L");
+        assert_eq!(rows(&images), [3, 9, 15]);
+    }
+
+    #[test]
+    fn wrapping_at_the_bottom_margin_scrolls() {
+        let (mut parser, mut images, mut tracker) = terminal(&[5]);
+        let line = [b'x'; 80];
+        //Filling the bottom row leaves the wrap pending, so nothing scrolls yet
+        tracker.process(&mut parser, &mut images, b"\x1B[24;1H");
+        tracker.process(&mut parser, &mut images, &line);
+        assert_eq!(rows(&images), [5]);
+        assert_eq!(parser.screen().cursor_position(), (23, 80));
+        tracker.process(&mut parser, &mut images, b"y");
+        assert_eq!(rows(&images), [4]);
+        assert_eq!(text(&parser, 23), "y");
+        //Several rows at once, from above the bottom, in one read, only scroll for the ones past the bottom
+        tracker.process(&mut parser, &mut images, b"\x1B[21;1H");
+        tracker.process(&mut parser, &mut images, &[b'x'; 80 * 5 + 1]);
+        assert_eq!(rows(&images), [2]);
+        //A character split between reads still wraps
+        tracker.process(&mut parser, &mut images, b"\x1B[24;1H");
+        tracker.process(&mut parser, &mut images, &line);
+        tracker.process(&mut parser, &mut images, &"\u{e9}".as_bytes()[..1]);
+        tracker.process(&mut parser, &mut images, &"\u{e9}".as_bytes()[1..]);
+        assert_eq!(rows(&images), [1]);
+        assert_eq!(text(&parser, 23), "\u{e9}");
+    }
+
+    #[test]
+    fn wrapping_scrolls_only_the_region() {
+        let (mut parser, mut images, mut tracker) = terminal(&[0, 12]);
+        tracker.process(&mut parser, &mut images, b"\x1B[10;20r\x1B[20;1H");
+        tracker.process(&mut parser, &mut images, &[b'x'; 81]);
+        assert_eq!(rows(&images), [0, 11]);
+        //Wrapping on the last row, below the region, doesn't scroll anything
+        tracker.process(&mut parser, &mut images, b"\x1B[24;1H");
+        tracker.process(&mut parser, &mut images, &[b'x'; 81]);
+        assert_eq!(rows(&images), [0, 11]);
+    }
+
+    #[test]
+    fn clearing_the_scrollback_leaves_images() {
+        let (mut parser, mut images, mut tracker) = terminal(&[3]);
+        tracker.process(&mut parser, &mut images, b"\x1B[3J");
+        assert_eq!(rows(&images), [3]);
+    }
+
+    #[test]
+    fn a_clear_split_between_reads_still_clears() {
+        let (mut parser, mut images, mut tracker) = terminal(&[0]);
+        tracker.process(&mut parser, &mut images, b"\x1B[2");
+        assert_eq!(rows(&images), [0]);
+        tracker.process(&mut parser, &mut images, b"J");
+        assert_eq!(rows(&images), []);
+    }
+
+    #[test]
+    fn osc_answers_end_like_the_question() {
+        let (mut parser, mut images, mut tracker) = terminal(&[]);
+        tracker.process(&mut parser, &mut images, b"\x1B]10;?\x07\x1B]4;1;?\x1B\\");
+        assert_eq!(
+            String::from_utf8_lossy(&parser.callbacks().responses),
+            "\x1B]10;rgb:ffff/ffff/ffff\x07\x1B]4;1;rgb:8080/0000/0000\x1B\\"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use vt100::Parser;
+
+use crate::{
+    callbacks::TermCallbacks,
+    image_layer::ImageLayer,
+    kitty::{self, KittyGraphics},
+    scroll_tracker::ScrollTracker,
+    sixel,
+    stream_filter::{FilterEvent, StreamFilter},
+};
+
+/// One terminal: the emulator plus the images drawn on top of it. Knows nothing about GL, `NeoCharRender` draws it.
+pub struct Session {
+    /// Writing to this directly skips the image handling, use [`Session::process`] instead.
+    pub parser: Parser<TermCallbacks>,
+    filter: StreamFilter,
+    scroll: ScrollTracker,
+    pub images: ImageLayer,
+    pub kitty: KittyGraphics,
+}
+
+impl Session {
+    /// `cell_size` is the size of a glyph in pixels, which decides how many cells an image covers.
+    pub fn new(rows: u16, cols: u16, scrollback_len: usize, cell_size: (usize, usize)) -> Session {
+        Session {
+            parser: Parser::new_with_callbacks(
+                rows,
+                cols,
+                scrollback_len,
+                TermCallbacks::default(),
+            ),
+            filter: StreamFilter::default(),
+            scroll: ScrollTracker::new(rows),
+            images: ImageLayer::new(cell_size.0, cell_size.1),
+            kitty: KittyGraphics::new(kitty::DEFAULT_BUDGET),
+        }
+    }
+    /// For when the font or zoom changes. The text stays where it is, it's up to whatever's running to redraw for the new size.
+    ///
+    /// The other end is sent the new size the way xterm reports it (`CSI 8 ; rows ; cols t`), so it can pass it on with `stty`.
+    pub fn resize(&mut self, rows: u16, cols: u16, cell_size: (usize, usize)) {
+        self.parser.screen_mut().set_size(rows, cols);
+        self.scroll.resize(rows);
+        self.images.set_cell_size(cell_size.0, cell_size.1);
+        let report = format!("\x1B[8;{rows};{cols}t");
+        self.parser
+            .callbacks_mut()
+            .responses
+            .extend_from_slice(report.as_bytes());
+    }
+    /// Feeds bytes from the remote end to the parser, picking out any images along the way.
+    pub fn process(&mut self, bytes: &[u8]) {
+        let parser = &mut self.parser;
+        let images = &mut self.images;
+        let kitty = &mut self.kitty;
+        let scroll = &mut self.scroll;
+        self.filter.feed(bytes, |event| match event {
+            FilterEvent::Text(text) => scroll.process(parser, images, text),
+            FilterEvent::Dcs(payload) => {
+                let Some(image) = sixel::parse_dcs(payload) else {
+                    return;
+                };
+                let (row, col) = parser.screen().cursor_position();
+                let key = images.new_image_key();
+                let image_rows = images.place(image.into(), key, row, col);
+                //Leave the cursor on the line after the image, scrolling it up if we need to
+                for _ in 0..image_rows {
+                    scroll.process(parser, images, b"\n");
+                }
+            }
+            FilterEvent::Apc(payload) => {
+                let (row, col) = parser.screen().cursor_position();
+                let outcome = kitty.handle(payload, images, (row, col));
+                if let Some(response) = outcome.response {
+                    parser.callbacks_mut().responses.extend(response);
+                }
+                //The cursor ends up just right of the image, on its last row
+                if let Some((image_rows, image_cols)) = outcome.cursor_move {
+                    for _ in 1..image_rows {
+                        scroll.process(parser, images, b"\n");
+                    }
+                    let cha = format!("\x1B[{}G", col as usize + image_cols + 1);
+                    scroll.process(parser, images, cha.as_bytes());
+                }
+            }
+        });
+    }
+}
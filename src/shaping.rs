@@ -0,0 +1,366 @@
+use std::{collections::HashMap, ops::Range};
+
+use rustybuzz::{Direction, UnicodeBuffer};
+
+use crate::{
+    font::{Face, Font, FontFaces},
+    outline_font::OutlineFont,
+    term_backend::{TermCell, TermScreen},
+};
+
+/// Forget every shaped run once there are this many, so scrolling through a big file doesn't keep them all.
+const MAX_SHAPED_RUNS: usize = 2048;
+/// Start numbering pieces from zero again once there are this many, so they don't crowd the font's own glyphs out of the cache.
+pub const MAX_PIECES: usize = 1024;
+
+/// A glyph from the font, placed in pixels from the top left of the first cell of its [`Ligature`], on the baseline.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PlacedGlyph {
+    pub id: u16,
+    pub x: i32,
+    /// Down from the baseline.
+    pub y: i32,
+}
+
+/// Cells the font draws differently when they're next to each other, like `->` turning into an arrow.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Ligature {
+    /// Which of the cells given to [`shape`].
+    pub cells: Range<usize>,
+    pub glyphs: Vec<PlacedGlyph>,
+}
+
+/// Splits a row into runs that could be shaped together: neighbouring cells with the same colours and attributes.
+///
+/// Wide cells and the cells they cover are left out, as are runs of one cell, which have nothing to join up with.
+pub fn runs(cells: &[TermCell]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    let mut col = 0;
+    while col < cells.len() {
+        let cell = &cells[col];
+        if cell.wide {
+            col += 2;
+            continue;
+        }
+        match runs.last_mut() {
+            Some(run) if run.end == col && same_style(&cells[run.start], cell) => run.end += 1,
+            _ => runs.push(col..col + 1),
+        }
+        col += 1;
+    }
+    runs.retain(|run| run.len() > 1);
+    runs
+}
+
+fn same_style(a: &TermCell, b: &TermCell) -> bool {
+    a.fg == b.fg && a.bg == b.bg && a.attrs == b.attrs
+}
+
+/// Shapes `text`, one character per cell, and finds where the font's ligature and contextual alternate tables changed it.
+///
+/// A cell is changed if it didn't come out as the font's usual glyph for its character, on its own, where it would normally go.
+/// Each stretch of changed cells is one [`Ligature`], with its glyphs kept to the grid so it lines up with the cells around it.
+pub fn shape(font: &OutlineFont, text: &[char]) -> Vec<Ligature> {
+    let Some(face) = rustybuzz::Face::from_slice(font.data(), 0) else {
+        return Vec::new();
+    };
+    let mut buffer = UnicodeBuffer::new();
+    for (i, &c) in text.iter().enumerate() {
+        buffer.add(c, i as u32);
+    }
+    buffer.set_direction(Direction::LeftToRight);
+    buffer.guess_segment_properties();
+    let shaped = rustybuzz::shape(&face, &[], buffer);
+    let (cell_width, _) = font.dimensions();
+    let (x_scale, y_scale) = font.units_to_pixels();
+    //Glyphs grouped by the cells they came from, left to right, positioned from the start of `text`
+    let mut groups: Vec<(Range<usize>, Vec<PlacedGlyph>)> = Vec::new();
+    let mut pen = 0;
+    for (info, position) in shaped.glyph_infos().iter().zip(shaped.glyph_positions()) {
+        let cell = info.cluster as usize;
+        if groups.last().is_none_or(|(cells, _)| cells.start != cell) {
+            if let Some((cells, _)) = groups.last_mut() {
+                cells.end = cell;
+            }
+            groups.push((cell..text.len(), Vec::new()));
+            pen = 0;
+        }
+        let x = (pen + position.x_offset) as f32 * x_scale;
+        groups
+            .last_mut()
+            .expect("Pushed above")
+            .1
+            .push(PlacedGlyph {
+                id: info.glyph_id as u16,
+                x: (cell * cell_width) as i32 + x.round() as i32,
+                y: -(position.y_offset as f32 * y_scale).round() as i32,
+            });
+        pen += position.x_advance;
+    }
+    let mut ligatures: Vec<Ligature> = Vec::new();
+    for (cells, glyphs) in groups {
+        let plain = match (cells.len(), glyphs.as_slice()) {
+            (1, [glyph]) => {
+                glyph.id as usize == font.glyph_index(text[cells.start]).unwrap_or(0)
+                    && glyph.x == (cells.start * cell_width) as i32
+                    && glyph.y == 0
+            }
+            _ => false,
+        };
+        if plain {
+            continue;
+        }
+        match ligatures.last_mut() {
+            Some(ligature) if ligature.cells.end == cells.start => {
+                ligature.cells.end = cells.end;
+                ligature.glyphs.extend(glyphs);
+            }
+            _ => ligatures.push(Ligature { cells, glyphs }),
+        }
+    }
+    for ligature in &mut ligatures {
+        let left = (ligature.cells.start * cell_width) as i32;
+        for glyph in &mut ligature.glyphs {
+            glyph.x -= left;
+        }
+    }
+    ligatures
+}
+
+/// The cells in a run that are part of a ligature, from the start of the run, and the number of each one's piece.
+type ShapedRun = Vec<(usize, usize)>;
+
+/// One cell's worth of a ligature.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Piece {
+    glyphs: Vec<PlacedGlyph>,
+    cells: usize,
+    /// Which cell, from the left.
+    cell: usize,
+}
+
+/// Shapes what's on screen into ligatures, cut up into one piece per cell so the cursor and everything else still works a cell at a time.
+///
+/// Remembers how runs came out, since the same text is drawn frame after frame, and numbers the pieces so they can go in a glyph cache.
+/// Rows are only shaped again when something in them changes.
+/// Once there are [`MAX_PIECES`], numbering starts over, and [`Shaper::take_renumbered`] says glyphs cached under the old numbers have to go.
+#[derive(Default)]
+pub struct Shaper {
+    shaped: HashMap<(Face, Vec<char>), ShapedRun>,
+    pieces: HashMap<Piece, usize>,
+    /// By number.
+    numbered: Vec<Piece>,
+    rows: Vec<ShapedRow>,
+    /// Where each row is read into, to check it against what it was.
+    scratch: Vec<TermCell>,
+    renumbered: bool,
+}
+
+/// A row as it was last shaped.
+#[derive(Default)]
+struct ShapedRow {
+    cells: Vec<TermCell>,
+    /// The face and piece number of each cell that's part of a ligature.
+    pieces: Vec<Option<(Face, usize)>>,
+}
+
+impl Shaper {
+    /// Works out which cells of `term` show part of a ligature, for [`Shaper::piece_at`].
+    ///
+    /// Only faces that are outline fonts are shaped, bitmap fonts don't have ligatures.
+    pub fn shape_screen(&mut self, term: &impl TermScreen, fonts: &FontFaces) {
+        if self.numbered.len() >= MAX_PIECES {
+            self.shaped.clear();
+            self.pieces.clear();
+            self.numbered.clear();
+            self.rows.clear();
+            self.renumbered = true;
+        }
+        let (rows, cols) = term.size();
+        self.rows.resize_with(rows, ShapedRow::default);
+        for row in 0..rows {
+            self.scratch.clear();
+            self.scratch
+                .extend((0..cols).map(|col| term.cell(row, col).unwrap_or_default()));
+            if self.scratch == self.rows[row].cells {
+                continue;
+            }
+            let mut pieces = std::mem::take(&mut self.rows[row].pieces);
+            pieces.clear();
+            pieces.resize(cols, None);
+            for run in runs(&self.scratch) {
+                let attrs = self.scratch[run.start].attrs;
+                let face = fonts.resolve(Face::from_style(attrs.bold, attrs.italic));
+                let Some(font) = fonts.get(face).and_then(|f| f.outline()) else {
+                    continue;
+                };
+                let text = self.scratch[run.clone()].iter().map(|c| c.chr).collect();
+                let key = (face, text);
+                if !self.shaped.contains_key(&key) {
+                    if self.shaped.len() >= MAX_SHAPED_RUNS {
+                        self.shaped.clear();
+                    }
+                    let shaped = self.number(shape(font, &key.1));
+                    self.shaped.insert(key.clone(), shaped);
+                }
+                for &(cell, piece) in &self.shaped[&key] {
+                    pieces[run.start + cell] = Some((face, piece));
+                }
+            }
+            let shaped_row = &mut self.rows[row];
+            std::mem::swap(&mut shaped_row.cells, &mut self.scratch);
+            shaped_row.pieces = pieces;
+        }
+    }
+    /// The face and number of the piece of ligature at `(row, col)`, as of the last [`Shaper::shape_screen`].
+    pub fn piece_at(&self, (row, col): (usize, usize)) -> Option<(Face, usize)> {
+        *self.rows.get(row)?.pieces.get(col)?
+    }
+    /// Whether numbering started over since this was last asked, so whatever's cached under the old numbers is wrong.
+    pub fn take_renumbered(&mut self) -> bool {
+        std::mem::take(&mut self.renumbered)
+    }
+    /// Numbers each cell of `ligatures`, giving the cells and their numbers.
+    fn number(&mut self, ligatures: Vec<Ligature>) -> ShapedRun {
+        let mut numbered = Vec::new();
+        for ligature in ligatures {
+            for (cell, col) in ligature.cells.clone().enumerate() {
+                let piece = Piece {
+                    glyphs: ligature.glyphs.clone(),
+                    cells: ligature.cells.len(),
+                    cell,
+                };
+                let next = self.numbered.len();
+                let number = *self.pieces.entry(piece.clone()).or_insert(next);
+                if number == next {
+                    self.numbered.push(piece);
+                }
+                numbered.push((col, number));
+            }
+        }
+        numbered
+    }
+    /// Piece `number` drawn with `font`, as one cell of coverage, or `None` if there's no such piece.
+    pub fn draw_piece(&self, font: &OutlineFont, number: usize) -> Option<Vec<u8>> {
+        let piece = self.numbered.get(number)?;
+        let (width, _) = font.dimensions();
+        let whole = font.draw_glyphs(&piece.glyphs, piece.cells);
+        let row_width = width * piece.cells;
+        Some(
+            whole
+                .chunks_exact(row_width)
+                .flat_map(|row| &row[piece.cell * width..(piece.cell + 1) * width])
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::term_backend::{CellAttrs, CellColor, SyntheticScreen};
+
+    /// DejaVu Sans has an `fi` ligature, but it's too big to keep in the repo, so these tests only run where it's installed.
+    fn dejavu() -> Option<OutlineFont> {
+        let Ok(data) = std::fs::read("/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf") else {
+            eprintln!("DejaVu Sans isn't installed, skipping");
+            return None;
+        };
+        Some(OutlineFont::parse(data, 16.0).expect("DejaVu Sans should load"))
+    }
+
+    fn row(text: &str) -> Vec<TermCell> {
+        text.chars()
+            .map(|chr| TermCell {
+                chr,
+                ..TermCell::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn runs_split_where_the_style_changes() {
+        let mut cells = row("abcdef");
+        cells[2].fg = CellColor::Idx(1);
+        cells[3].fg = CellColor::Idx(1);
+        cells[4].attrs = CellAttrs {
+            bold: true,
+            ..CellAttrs::default()
+        };
+        //The lone bold `e` has nothing to join up with
+        assert_eq!(runs(&cells), [0..2, 2..4]);
+    }
+
+    #[test]
+    fn runs_skip_wide_cells() {
+        let mut cells = row("ab\u{4E00} cd");
+        cells[2].wide = true;
+        //Neither the wide cell nor the one it covers is part of anything
+        assert_eq!(runs(&cells), [0..2, 4..6]);
+        let mut cells = row("a\u{4E00} b");
+        cells[1].wide = true;
+        assert_eq!(runs(&cells), []);
+    }
+
+    #[test]
+    fn fi_is_one_ligature() {
+        let Some(font) = dejavu() else {
+            return;
+        };
+        let ligatures = shape(&font, &['f', 'i', 'x']);
+        assert_eq!(ligatures.len(), 1);
+        assert_eq!(ligatures[0].cells, 0..2);
+        assert_eq!(ligatures[0].glyphs.len(), 1);
+        assert_ne!(
+            ligatures[0].glyphs[0].id as usize,
+            font.glyph_index('f').unwrap()
+        );
+        assert_eq!(shape(&font, &['a', 'b']), []);
+    }
+
+    #[test]
+    fn the_screen_is_shaped_a_row_at_a_time() {
+        let Some(font) = dejavu() else {
+            return;
+        };
+        let fonts = FontFaces::new(Box::new(font));
+        let mut screen = SyntheticScreen::new(2, 8);
+        screen.put_str(0, 0, "fi ab fi", &TermCell::default());
+        let mut shaper = Shaper::default();
+        shaper.shape_screen(&screen, &fonts);
+        let first = [0, 1].map(|col| shaper.piece_at((0, col)));
+        assert!(first.iter().all(Option::is_some));
+        assert_ne!(first[0], first[1]);
+        assert_eq!([6, 7].map(|col| shaper.piece_at((0, col))), first);
+        assert_eq!(shaper.piece_at((0, 3)), None);
+        assert_eq!(shaper.piece_at((1, 0)), None);
+        //Rows that change are shaped again
+        screen.put_str(0, 0, "ab", &TermCell::default());
+        screen.put_str(1, 0, "fi", &TermCell::default());
+        shaper.shape_screen(&screen, &fonts);
+        assert_eq!(shaper.piece_at((0, 0)), None);
+        assert_eq!([0, 1].map(|col| shaper.piece_at((1, col))), first);
+    }
+
+    #[test]
+    fn numbering_starts_over_when_there_are_too_many_pieces() {
+        let Some(font) = dejavu() else {
+            return;
+        };
+        let fonts = FontFaces::new(Box::new(font));
+        let mut screen = SyntheticScreen::new(1, 2);
+        screen.put_str(0, 0, "fi", &TermCell::default());
+        let mut shaper = Shaper::default();
+        let filler = (0..MAX_PIECES as u16).map(|id| Ligature {
+            cells: 0..1,
+            glyphs: vec![PlacedGlyph { id, x: 0, y: 0 }],
+        });
+        shaper.number(filler.collect());
+        shaper.shape_screen(&screen, &fonts);
+        assert!(shaper.take_renumbered());
+        assert!(!shaper.take_renumbered());
+        assert_eq!(shaper.numbered.len(), 2);
+        assert_eq!(shaper.piece_at((0, 0)).map(|(_, piece)| piece), Some(0));
+    }
+}
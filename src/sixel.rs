@@ -0,0 +1,232 @@
+use crate::image_layer::Image;
+
+/// Anything bigger than this in either direction gets cropped, the Vita doesn't have the memory for it.
+const MAX_DIMENSION: usize = 2048;
+
+/// The VT340's default colour registers, as 0xRRGGBB.
+const VT340_PALETTE: [u32; 16] = [
+    0x000000, 0x3333CC, 0xCC2121, 0x33CC33, 0xCC33CC, 0x33CCCC, 0xCCCC33, 0x878787, 0x424242,
+    0x545499, 0x994242, 0x549954, 0x995499, 0x549999, 0x999954, 0xCCCCCC,
+];
+
+/// Decodes the body of a DCS string, if it's a sixel image (`P1 ; P2 ; P3 q <data>`).
+pub fn parse_dcs(payload: &[u8]) -> Option<Image> {
+    let q = payload
+        .iter()
+        .position(|&b| !(b.is_ascii_digit() || b == b';'))?;
+    if payload[q] != b'q' {
+        return None;
+    }
+    let mut params = payload[..q].split(|&b| b == b';');
+    //P2 = 1 means pixels we never draw to stay transparent
+    let transparent = params.nth(1).and_then(parse_decimal) == Some(1);
+    Some(decode(&payload[q + 1..], transparent))
+}
+
+fn parse_decimal(digits: &[u8]) -> Option<usize> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0usize, |acc, &d| {
+        acc.checked_mul(10)?.checked_add((d - b'0') as usize)
+    })
+}
+
+/// Reads `n;n;n...` starting at `*i`, leaving `*i` on the first byte that isn't part of it.
+fn parse_numbers(data: &[u8], i: &mut usize) -> Vec<usize> {
+    let mut numbers = vec![];
+    loop {
+        let start = *i;
+        while *i < data.len() && data[*i].is_ascii_digit() {
+            *i += 1;
+        }
+        numbers.push(parse_decimal(&data[start..*i]).unwrap_or(0));
+        if *i < data.len() && data[*i] == b';' {
+            *i += 1;
+        } else {
+            return numbers;
+        }
+    }
+}
+
+fn hls_to_rgb(h: usize, l: usize, s: usize) -> u32 {
+    //DEC puts blue at 0 degrees and red at 120, everyone else puts red at 0
+    let h = ((h + 240) % 360) as f32;
+    let l = l.min(100) as f32 / 100.0;
+    let s = s.min(100) as f32 / 100.0;
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+    let m = l - c / 2.0;
+    let (r, g, b) = match (h / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let to_byte = |v: f32| (((v + m) * 255.0).round().clamp(0.0, 255.0)) as u32;
+    (to_byte(r) << 16) | (to_byte(g) << 8) | to_byte(b)
+}
+
+fn rgb_percent_to_rgb(r: usize, g: usize, b: usize) -> u32 {
+    let to_byte = |v: usize| (v.min(100) * 255 / 100) as u32;
+    (to_byte(r) << 16) | (to_byte(g) << 8) | to_byte(b)
+}
+
+/// Paints one sixel (a column of 6 pixels) `count` times going right from `x`, `y`.
+fn put(rows: &mut Vec<Vec<u32>>, x: usize, y: usize, sixel: u8, count: usize, rgb: u32) {
+    for bit in 0..6 {
+        if (sixel >> bit) & 1 == 0 || y + bit >= MAX_DIMENSION {
+            continue;
+        }
+        let py = y + bit;
+        if rows.len() <= py {
+            rows.resize(py + 1, vec![]);
+        }
+        let row = &mut rows[py];
+        let end = x.saturating_add(count).min(MAX_DIMENSION);
+        if row.len() < end {
+            row.resize(end, 0);
+        }
+        row[x.min(end)..end].fill(0xFF000000 | rgb);
+    }
+}
+
+/// Decodes sixel data (everything after the `q`).
+pub fn decode(data: &[u8], transparent: bool) -> Image {
+    let mut palette = [0u32; 256];
+    palette[..16].copy_from_slice(&VT340_PALETTE);
+    //0 means never drawn to, otherwise 0xFF_RRGGBB
+    let mut rows: Vec<Vec<u32>> = vec![];
+    let mut color = 0usize;
+    let (mut x, mut y) = (0usize, 0usize);
+    let (mut min_width, mut min_height) = (0usize, 0usize);
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        i += 1;
+        match b {
+            b'"' => {
+                let attrs = parse_numbers(data, &mut i);
+                if let [_, _, ph, pv, ..] = attrs[..] {
+                    min_width = ph.min(MAX_DIMENSION);
+                    min_height = pv.min(MAX_DIMENSION);
+                }
+            }
+            b'#' => {
+                let args = parse_numbers(data, &mut i);
+                color = args[0] & 0xFF;
+                match args[..] {
+                    [_, 1, h, l, s] => palette[color] = hls_to_rgb(h, l, s),
+                    [_, 2, r, g, b] => palette[color] = rgb_percent_to_rgb(r, g, b),
+                    _ => {}
+                }
+            }
+            b'!' => {
+                let count = parse_numbers(data, &mut i)[0].max(1);
+                if let Some(&sixel @ 0x3F..=0x7E) = data.get(i) {
+                    i += 1;
+                    put(&mut rows, x, y, sixel - 0x3F, count, palette[color]);
+                    x = x.saturating_add(count);
+                }
+            }
+            b'$' => x = 0,
+            b'-' => {
+                x = 0;
+                y = y.saturating_add(6);
+            }
+            0x3F..=0x7E => {
+                put(&mut rows, x, y, b - 0x3F, 1, palette[color]);
+                x = x.saturating_add(1);
+            }
+            _ => {}
+        }
+    }
+    let width = rows
+        .iter()
+        .map(|r| r.len())
+        .max()
+        .unwrap_or(0)
+        .max(min_width);
+    let height = rows.len().max(min_height);
+    let background = if transparent {
+        [0; 4]
+    } else {
+        let [_, r, g, b] = palette[0].to_be_bytes();
+        [r, g, b, 0xFF]
+    };
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for py in 0..height {
+        let row = rows.get(py).map(|r| &r[..]).unwrap_or(&[]);
+        for px in 0..width {
+            match row.get(px) {
+                Some(&p) if p != 0 => {
+                    let [_, r, g, b] = p.to_be_bytes();
+                    rgba.extend_from_slice(&[r, g, b, 0xFF]);
+                }
+                _ => rgba.extend_from_slice(&background),
+            }
+        }
+    }
+    Image {
+        width,
+        height,
+        rgba,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(image: &Image, x: usize, y: usize) -> [u8; 4] {
+        let at = (y * image.width + x) * 4;
+        image.rgba[at..at + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn one_sixel_is_a_column_of_six_pixels() {
+        let image = decode(b"#1~", false);
+        assert_eq!((image.width, image.height), (1, 6));
+        for y in 0..6 {
+            assert_eq!(pixel(&image, 0, y), [0x33, 0x33, 0xCC, 0xFF]);
+        }
+    }
+
+    #[test]
+    fn repeats_and_new_lines() {
+        let image = decode(b"#2;2;100;0;0!3~-!2@", false);
+        assert_eq!((image.width, image.height), (3, 7));
+        assert_eq!(pixel(&image, 2, 5), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(pixel(&image, 1, 6), [0xFF, 0, 0, 0xFF]);
+        //Never drawn to, so it's colour 0
+        assert_eq!(pixel(&image, 2, 6), [0, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn hls_puts_red_at_120_degrees() {
+        let image = decode(b"#1;1;120;50;100@", false);
+        assert_eq!(pixel(&image, 0, 0), [0xFF, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn raster_attributes_set_the_smallest_size() {
+        let image = decode(b"\"1;1;4;12#1@", false);
+        assert_eq!((image.width, image.height), (4, 12));
+    }
+
+    #[test]
+    fn p2_of_1_leaves_the_background_transparent() {
+        let image = parse_dcs(b"0;1q#1A").unwrap();
+        assert_eq!((image.width, image.height), (1, 2));
+        assert_eq!(pixel(&image, 0, 0), [0; 4]);
+        assert_eq!(pixel(&image, 0, 1), [0x33, 0x33, 0xCC, 0xFF]);
+    }
+
+    #[test]
+    fn other_dcs_strings_are_not_sixel() {
+        assert!(parse_dcs(b"$qm").is_none());
+        assert!(parse_dcs(b"1;2").is_none());
+    }
+}
@@ -0,0 +1,20 @@
+/// Fits `title` into `width` cells by cutting out the middle, since for `user@host:cwd` titles both ends matter.
+///
+/// Control characters are dropped, and every char is assumed to take up a single cell.
+pub fn truncate_title(title: &str, width: usize) -> String {
+    let chars: Vec<char> = title.chars().filter(|c| !c.is_control()).collect();
+    if chars.len() <= width {
+        return chars.into_iter().collect();
+    }
+    if width == 0 {
+        return String::new();
+    }
+    //one cell goes to the ellipsis, give the tail (usually the cwd) the bigger share of the rest
+    let head = (width - 1) / 3;
+    let tail = width - 1 - head;
+    chars[..head]
+        .iter()
+        .chain(std::iter::once(&'…'))
+        .chain(&chars[chars.len() - tail..])
+        .collect()
+}
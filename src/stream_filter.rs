@@ -0,0 +1,114 @@
+/// Longest DCS/APC string we'll hold onto, anything longer gets dropped instead of eating all our memory.
+const MAX_STRING_LEN: usize = 8 * 1024 * 1024;
+
+/// What [`StreamFilter::feed`] hands back.
+pub enum FilterEvent<'a> {
+    /// Plain bytes to pass on to the parser.
+    Text(&'a [u8]),
+    /// The body of an `ESC P ... ESC \` string, without the introducer or terminator.
+    Dcs(&'a [u8]),
+    /// The body of an `ESC _ ... ESC \` string, without the introducer or terminator.
+    Apc(&'a [u8]),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StringKind {
+    Dcs,
+    Apc,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    String(StringKind),
+    StringEscape(StringKind),
+}
+
+/// Sits in front of the vt100 parser and pulls out the DCS and APC strings it would otherwise throw away (sixel, kitty graphics).
+///
+/// Only the 7-bit introducers are recognised, as the 8-bit ones are valid UTF-8 continuation bytes.
+/// Strings can be split across any number of [`StreamFilter::feed`] calls.
+#[derive(Default)]
+pub struct StreamFilter {
+    state: State,
+    buf: Vec<u8>,
+    overflowed: bool,
+}
+
+impl StreamFilter {
+    pub fn feed(&mut self, bytes: &[u8], mut on_event: impl FnMut(FilterEvent)) {
+        let mut text_start = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let b = bytes[i];
+            match self.state {
+                State::Ground => {
+                    if b == 0x1B {
+                        if text_start < i {
+                            on_event(FilterEvent::Text(&bytes[text_start..i]));
+                        }
+                        self.state = State::Escape;
+                    }
+                }
+                State::Escape => {
+                    let kind = match b {
+                        b'P' => Some(StringKind::Dcs),
+                        b'_' => Some(StringKind::Apc),
+                        _ => None,
+                    };
+                    match kind {
+                        Some(kind) => {
+                            self.state = State::String(kind);
+                            self.buf.clear();
+                            self.overflowed = false;
+                        }
+                        None => {
+                            //Not ours, give the parser back its ESC and carry on from this byte as normal text
+                            on_event(FilterEvent::Text(b"\x1B"));
+                            self.state = State::Ground;
+                            text_start = i;
+                            continue;
+                        }
+                    }
+                }
+                State::String(kind) => match b {
+                    0x1B => self.state = State::StringEscape(kind),
+                    //CAN and SUB cancel the string
+                    0x18 | 0x1A => {
+                        self.state = State::Ground;
+                        text_start = i + 1;
+                    }
+                    _ => {
+                        if self.buf.len() < MAX_STRING_LEN {
+                            self.buf.push(b);
+                        } else {
+                            self.overflowed = true;
+                        }
+                    }
+                },
+                State::StringEscape(kind) => {
+                    if b == b'\\' {
+                        if !self.overflowed {
+                            on_event(match kind {
+                                StringKind::Dcs => FilterEvent::Dcs(&self.buf),
+                                StringKind::Apc => FilterEvent::Apc(&self.buf),
+                            });
+                        }
+                        self.state = State::Ground;
+                        text_start = i + 1;
+                    } else {
+                        //An ESC that isn't ST aborts the string and starts a new escape sequence
+                        self.state = State::Escape;
+                        continue;
+                    }
+                }
+            }
+            i += 1;
+        }
+        if self.state == State::Ground && text_start < bytes.len() {
+            on_event(FilterEvent::Text(&bytes[text_start..]));
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use crate::box_drawing::Canvas;
+
+/// Samples per pixel in each direction, for anti-aliasing the separators' edges.
+const SUPERSAMPLE: usize = 4;
+
+/// Icons are 8 by 8, one byte per row, most significant bit on the left.
+const ICON_SIZE: usize = 8;
+
+const BRANCH: [u8; 8] = [0x44, 0x44, 0x44, 0x48, 0x50, 0x60, 0x40, 0x40];
+const LINE_NUMBER: [u8; 8] = [0x80, 0x80, 0x80, 0xF0, 0x09, 0x0D, 0x0B, 0x09];
+const LOCK: [u8; 8] = [0x00, 0x3C, 0x42, 0x42, 0xFF, 0xE7, 0xE7, 0xFF];
+const COLUMN_NUMBER: [u8; 8] = [0x70, 0x80, 0x80, 0x70, 0x09, 0x0D, 0x0B, 0x09];
+const HOME: [u8; 8] = [0x18, 0x3C, 0x7E, 0xFF, 0x7E, 0x66, 0x66, 0x66];
+const FOLDER: [u8; 8] = [0x00, 0xE0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+const CLOCK: [u8; 8] = [0x3C, 0x42, 0x91, 0x91, 0x9D, 0x81, 0x42, 0x3C];
+const CHECK: [u8; 8] = [0x00, 0x01, 0x03, 0x86, 0xCC, 0x78, 0x30, 0x00];
+const CROSS: [u8; 8] = [0xC3, 0xE7, 0x7E, 0x3C, 0x3C, 0x7E, 0xE7, 0xC3];
+const WARNING: [u8; 8] = [0x18, 0x3C, 0x24, 0x66, 0x66, 0xFF, 0xE7, 0xFF];
+const COG: [u8; 8] = [0x18, 0x7E, 0x66, 0xE7, 0xE7, 0x66, 0x7E, 0x18];
+const TERMINAL: [u8; 8] = [0xFF, 0x81, 0xA1, 0x91, 0xA1, 0x8D, 0x81, 0xFF];
+const BOLT: [u8; 8] = [0x0C, 0x18, 0x30, 0x7E, 0x0C, 0x18, 0x30, 0x40];
+
+/// The icons prompts and status lines use most, from Powerline and the Nerd Fonts private use area.
+/// Nerd Fonts has several copies of some of them, under different icon sets.
+const ICONS: [(char, [u8; 8]); 17] = [
+    ('\u{E0A0}', BRANCH),
+    ('\u{E0A1}', LINE_NUMBER),
+    ('\u{E0A2}', LOCK),
+    ('\u{E0A3}', COLUMN_NUMBER),
+    ('\u{E725}', BRANCH),
+    ('\u{F418}', BRANCH),
+    ('\u{F126}', BRANCH),
+    ('\u{F015}', HOME),
+    ('\u{F023}', LOCK),
+    ('\u{F07B}', FOLDER),
+    ('\u{F017}', CLOCK),
+    ('\u{F00C}', CHECK),
+    ('\u{F00D}', CROSS),
+    ('\u{F071}', WARNING),
+    ('\u{F013}', COG),
+    ('\u{F120}', TERMINAL),
+    ('\u{F0E7}', BOLT),
+];
+
+/// How many characters [`covers`]: 16 separators, 18 Powerline Extra shapes and the icons.
+pub const COVERED: usize = 16 + 18 + ICONS.len();
+
+/// Whether `c` is a Powerline separator or icon we can draw, for when the font doesn't have it.
+///
+/// That's the separators from U+E0B0 to U+E0BF, and the Powerline Extra shapes from U+E0C0 to U+E0D4 apart from the three codepoints in there nothing uses.
+pub fn covers(c: char) -> bool {
+    matches!(c, '\u{E0B0}'..='\u{E0C8}' | '\u{E0CA}' | '\u{E0CC}'..='\u{E0D2}' | '\u{E0D4}')
+        || icon(c).is_some()
+}
+
+fn icon(c: char) -> Option<&'static [u8; 8]> {
+    ICONS
+        .iter()
+        .find(|(icon, _)| *icon == c)
+        .map(|(_, bitmap)| bitmap)
+}
+
+/// `c` drawn into a `width` by `height` cell, as one byte of coverage per pixel, or `None` if [`covers`] doesn't.
+///
+/// Separators stretch to fill the whole cell, so they meet the backgrounds either side of them with no gap.
+/// The Powerline Extra flames, waves and the like are drawn as a cell's worth of the shape, not the two or three cells some fonts spread them over.
+/// Icons are scaled up by whole pixels to fit and go in the middle.
+pub fn draw(c: char, width: usize, height: usize) -> Option<Vec<u8>> {
+    let mut canvas = Canvas::new(width, height);
+    if let Some(bitmap) = icon(c) {
+        draw_icon(&mut canvas, bitmap);
+        return Some(canvas.pixels);
+    }
+    let (w, h) = (width as f32, height as f32);
+    //Half the cell's height, for the arrows and half circles which meet in the middle of it
+    let mid = h / 2.0;
+    let t = canvas.light as f32;
+    let ellipse = |x: f32, y: f32, cx: f32, rx: f32, ry: f32| {
+        let (dx, dy) = ((x - cx) / rx, (y - mid) / ry);
+        dx * dx + dy * dy <= 1.0
+    };
+    match c {
+        '\u{E0B0}' => fill(&mut canvas, |x, y| x <= w * (1.0 - (y - mid).abs() / mid)),
+        '\u{E0B2}' => fill(&mut canvas, |x, y| x >= w * (y - mid).abs() / mid),
+        '\u{E0B1}' => stroke(&mut canvas, &[(0.0, 0.0), (w, mid), (0.0, h)]),
+        '\u{E0B3}' => stroke(&mut canvas, &[(w, 0.0), (0.0, mid), (w, h)]),
+        '\u{E0B4}' => fill(&mut canvas, |x, y| ellipse(x, y, 0.0, w, mid)),
+        '\u{E0B6}' => fill(&mut canvas, |x, y| ellipse(x, y, w, w, mid)),
+        '\u{E0B5}' => fill(&mut canvas, |x, y| {
+            ellipse(x, y, 0.0, w, mid) && !ellipse(x, y, 0.0, w - t, mid - t)
+        }),
+        '\u{E0B7}' => fill(&mut canvas, |x, y| {
+            ellipse(x, y, w, w, mid) && !ellipse(x, y, w, w - t, mid - t)
+        }),
+        '\u{E0B8}' => fill(&mut canvas, |x, y| x / w <= y / h),
+        '\u{E0BA}' => fill(&mut canvas, |x, y| x / w >= 1.0 - y / h),
+        '\u{E0BC}' => fill(&mut canvas, |x, y| x / w <= 1.0 - y / h),
+        '\u{E0BE}' => fill(&mut canvas, |x, y| x / w >= y / h),
+        '\u{E0B9}' | '\u{E0BF}' => stroke(&mut canvas, &[(0.0, 0.0), (w, h)]),
+        '\u{E0BB}' | '\u{E0BD}' => stroke(&mut canvas, &[(0.0, h), (w, 0.0)]),
+        //Powerline Extra, filled from the left up to an edge, and mirrored to be filled from the right
+        '\u{E0C0}' => fill(&mut canvas, |x, y| x <= w * flame(y / h)),
+        '\u{E0C2}' => fill(&mut canvas, |x, y| w - x <= w * flame(y / h)),
+        '\u{E0C1}' => stroke(&mut canvas, &outline(w, h, flame, false)),
+        '\u{E0C3}' => stroke(&mut canvas, &outline(w, h, flame, true)),
+        '\u{E0C4}' => pixelated(&mut canvas, 4, false),
+        '\u{E0C5}' => pixelated(&mut canvas, 4, true),
+        '\u{E0C6}' => pixelated(&mut canvas, 2, false),
+        '\u{E0C7}' => pixelated(&mut canvas, 2, true),
+        '\u{E0C8}' => fill(&mut canvas, |x, y| x <= w * ice(y / h)),
+        '\u{E0CA}' => fill(&mut canvas, |x, y| w - x <= w * ice(y / h)),
+        '\u{E0CC}' => fill(&mut canvas, |x, y| hex_distance(x, y, w) <= HONEYCOMB_GAP),
+        '\u{E0CD}' => fill(&mut canvas, |x, y| hex_distance(x, y, w) > HONEYCOMB_GAP),
+        '\u{E0CE}' => fill(&mut canvas, |x, y| x <= w * lego(y / h)),
+        '\u{E0CF}' => stroke(&mut canvas, &outline(w, h, lego, false)),
+        //A brick seen from the front, with its stud on top, and from the side, with its stud to the right
+        '\u{E0D0}' => fill(&mut canvas, |x, y| {
+            y >= h * 0.3 || (y >= h * 0.1 && (w * 0.3..=w * 0.7).contains(&x))
+        }),
+        '\u{E0D1}' => fill(&mut canvas, |x, y| {
+            x <= w * 0.7 || (x <= w * 0.9 && (h * 0.35..=h * 0.65).contains(&y))
+        }),
+        '\u{E0D2}' => fill(&mut canvas, |x, y| x <= w * trapezoid(y / h)),
+        '\u{E0D4}' => fill(&mut canvas, |x, y| w - x <= w * trapezoid(y / h)),
+        _ => return None,
+    }
+    Some(canvas.pixels)
+}
+
+/// How far the edges of the Powerline Extra shapes reach across the cell, from 0 to 1, at `v` of the way down it.
+/// Three tongues of flame.
+fn flame(v: f32) -> f32 {
+    let tongue = 1.0 - (2.0 * (v * 3.0).fract() - 1.0).abs();
+    0.35 + 0.6 * tongue * tongue
+}
+
+/// Four icicles.
+fn ice(v: f32) -> f32 {
+    0.45 + 0.5 * (1.0 - (2.0 * (v * 4.0).fract() - 1.0).abs())
+}
+
+/// A stud sticking out of the middle.
+fn lego(v: f32) -> f32 {
+    if (v - 0.5).abs() <= 0.2 { 1.0 } else { 0.6 }
+}
+
+/// Out at the top and bottom, in at the middle.
+fn trapezoid(v: f32) -> f32 {
+    0.5 + 0.5 * (2.0 * v - 1.0).abs()
+}
+
+/// The line along one of those edges, from top to bottom, in pixel coordinates.
+fn outline(w: f32, h: f32, edge: fn(f32) -> f32, mirrored: bool) -> Vec<(f32, f32)> {
+    const SEGMENTS: usize = 48;
+    (0..=SEGMENTS)
+        .map(|i| {
+            let v = i as f32 / SEGMENTS as f32;
+            let x = w * edge(v);
+            (if mirrored { w - x } else { x }, v * h)
+        })
+        .collect()
+}
+
+/// Blocks that thin out towards the far side, `across` of them across the cell, and as many down it as make them square.
+fn pixelated(canvas: &mut Canvas, across: usize, mirrored: bool) {
+    let size = (canvas.width / across).max(1);
+    for row in 0..canvas.height.div_ceil(size) {
+        for col in 0..across {
+            //Solid for the first half, then every other block, then every fourth
+            let far = (col * 4) / across;
+            let on = match far {
+                0 | 1 => true,
+                2 => (row + col) % 2 == 0,
+                _ => (row + col) % 4 == 0,
+            };
+            if on {
+                //The rightmost blocks take up whatever's left, so they still reach the edge
+                let at = if mirrored { across - 1 - col } else { col };
+                let end = if at == across - 1 {
+                    canvas.width
+                } else {
+                    (at + 1) * size
+                };
+                canvas.rect(at * size..end, row * size..(row + 1) * size, 0xFF);
+            }
+        }
+    }
+}
+
+/// How much of the honeycomb's hexagons is filled in, the rest being the gaps between them.
+const HONEYCOMB_GAP: f32 = 0.75;
+
+/// How far `(x, y)` is from the middle of the nearest hexagon of a honeycomb with hexagons `w` wide, 0 at the middle and 1 at the edge.
+fn hex_distance(x: f32, y: f32, w: f32) -> f32 {
+    //Flat topped hexagons, in columns 3/4 of a hexagon apart, every other column half a hexagon down
+    let radius = w / 2.0;
+    let (across, down) = (radius * 1.5, radius * 3f32.sqrt());
+    let col = (x / across).floor() as i32;
+    let mut nearest = f32::INFINITY;
+    for col in col - 1..=col + 1 {
+        let offset = if col.rem_euclid(2) == 1 {
+            down / 2.0
+        } else {
+            0.0
+        };
+        let row = ((y - offset) / down).round() as i32;
+        for row in row - 1..=row + 1 {
+            let (dx, dy) = (
+                (x - col as f32 * across).abs(),
+                (y - offset - row as f32 * down).abs(),
+            );
+            let distance = dy.max(dx * 3f32.sqrt() / 2.0 + dy / 2.0) / (down / 2.0);
+            nearest = nearest.min(distance);
+        }
+    }
+    nearest
+}
+
+/// Covers each pixel as much as `inside` says its samples are inside the shape. `inside` takes pixel coordinates from the top left.
+fn fill(canvas: &mut Canvas, inside: impl Fn(f32, f32) -> bool) {
+    let step = 1.0 / SUPERSAMPLE as f32;
+    for y in 0..canvas.height {
+        for x in 0..canvas.width {
+            let hits = (0..SUPERSAMPLE * SUPERSAMPLE)
+                .filter(|i| {
+                    let sx = x as f32 + (i % SUPERSAMPLE) as f32 * step + step / 2.0;
+                    let sy = y as f32 + (i / SUPERSAMPLE) as f32 * step + step / 2.0;
+                    inside(sx, sy)
+                })
+                .count();
+            canvas.plot(x, y, hits as f32 / (SUPERSAMPLE * SUPERSAMPLE) as f32);
+        }
+    }
+}
+
+/// A light line through `points`, in pixel coordinates.
+fn stroke(canvas: &mut Canvas, points: &[(f32, f32)]) {
+    let thickness = canvas.light as f32;
+    for y in 0..canvas.height {
+        for x in 0..canvas.width {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            let distance = points
+                .windows(2)
+                .map(|ends| {
+                    let [(ax, ay), (bx, by)] = [ends[0], ends[1]];
+                    let (dx, dy) = (bx - ax, by - ay);
+                    let along =
+                        (((px - ax) * dx + (py - ay) * dy) / (dx * dx + dy * dy)).clamp(0.0, 1.0);
+                    (px - ax - along * dx).hypot(py - ay - along * dy)
+                })
+                .fold(f32::INFINITY, f32::min);
+            canvas.plot(x, y, thickness / 2.0 + 0.5 - distance);
+        }
+    }
+}
+
+fn draw_icon(canvas: &mut Canvas, bitmap: &[u8; 8]) {
+    let scale = (canvas.width.min(canvas.height) / ICON_SIZE).max(1);
+    let left = canvas.width.saturating_sub(ICON_SIZE * scale) / 2;
+    let top = canvas.height.saturating_sub(ICON_SIZE * scale) / 2;
+    for (row, bits) in bitmap.iter().enumerate() {
+        for col in 0..ICON_SIZE {
+            if bits & (0x80 >> col) != 0 {
+                let x = left + col * scale;
+                let y = top + row * scale;
+                canvas.rect(x..x + scale, y..y + scale, 0xFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(pixels: &[u8], width: usize, x: usize) -> Vec<u8> {
+        pixels.chunks_exact(width).map(|row| row[x]).collect()
+    }
+
+    /// Whether a row or column of pixels is covered from end to end, where only the ends can be partly covered as a slope starts there.
+    fn solid(edge: &[u8]) -> bool {
+        let ends = edge[0] > 0 && edge[edge.len() - 1] > 0;
+        ends && edge[1..edge.len() - 1].iter().all(|&p| p == 0xFF)
+    }
+
+    #[test]
+    fn covered_is_counted_right() {
+        let covered = ('\u{E000}'..='\u{F8FF}').filter(|&c| covers(c)).count();
+        assert_eq!(covered, COVERED);
+        for c in ['\u{E0C9}', '\u{E0CB}', '\u{E0D3}', '\u{E0D5}', 'A'] {
+            assert!(!covers(c), "{c:?}");
+            assert_eq!(draw(c, 8, 16), None);
+        }
+    }
+
+    #[test]
+    fn everything_covered_draws_something() {
+        for c in ('\u{E000}'..='\u{F8FF}').filter(|&c| covers(c)) {
+            for (width, height) in [(8, 16), (12, 24), (5, 9)] {
+                let pixels = draw(c, width, height).unwrap();
+                assert_eq!(pixels.len(), width * height);
+                assert!(pixels.iter().any(|&p| p > 0), "{c:?} at {width}x{height}");
+            }
+        }
+    }
+
+    #[test]
+    fn separators_fill_the_cell_edge_to_edge() {
+        //Filled from the left, then filled from the right
+        let left = "\u{E0B0}\u{E0B4}\u{E0C0}\u{E0C4}\u{E0C6}\u{E0C8}\u{E0CE}\u{E0D2}";
+        let right = "\u{E0B2}\u{E0B6}\u{E0C2}\u{E0C5}\u{E0C7}\u{E0CA}\u{E0D4}";
+        for (width, height) in [(8, 16), (12, 24), (7, 15)] {
+            for (shapes, x) in [(left, 0), (right, width - 1)] {
+                for c in shapes.chars() {
+                    let pixels = draw(c, width, height).unwrap();
+                    let edge = column(&pixels, width, x);
+                    assert!(solid(&edge), "{c:?} at {width}x{height}: {edge:?}");
+                }
+            }
+            //The arrows reach right across to the other side in the middle
+            let arrow = draw('\u{E0B0}', width, height).unwrap();
+            assert!(column(&arrow, width, width - 1)[height / 2] > 0);
+            let arrow = draw('\u{E0B2}', width, height).unwrap();
+            assert!(column(&arrow, width, 0)[height / 2] > 0);
+            //The triangles fill their bottom row, top row for the upside down ones
+            for (c, y) in [
+                ('\u{E0B8}', height - 1),
+                ('\u{E0BA}', height - 1),
+                ('\u{E0BC}', 0),
+                ('\u{E0BE}', 0),
+            ] {
+                let triangle = draw(c, width, height).unwrap();
+                let edge = &triangle[y * width..(y + 1) * width];
+                assert!(solid(edge), "{c:?} at {width}x{height}: {edge:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn icons_are_looked_up() {
+        assert_eq!(icon('\u{E0A0}'), Some(&BRANCH));
+        assert_eq!(icon('\u{F015}'), Some(&HOME));
+        assert_eq!(icon('\u{E0B0}'), None);
+        assert!(covers('\u{F120}'));
+    }
+
+    #[test]
+    fn icons_go_in_the_middle() {
+        //Scale, left and top for each cell size
+        for (width, height, scale, left, top) in
+            [(8, 16, 1, 0, 4), (10, 20, 1, 1, 6), (16, 32, 2, 0, 8)]
+        {
+            let pixels = draw('\u{F015}', width, height).unwrap();
+            for y in 0..height {
+                for x in 0..width {
+                    let inside = (left..left + 8 * scale).contains(&x)
+                        && (top..top + 8 * scale).contains(&y);
+                    let set =
+                        inside && HOME[(y - top) / scale] & (0x80 >> ((x - left) / scale)) != 0;
+                    assert_eq!(
+                        pixels[y * width + x],
+                        if set { 0xFF } else { 0 },
+                        "({x}, {y}) at {width}x{height}"
+                    );
+                }
+            }
+        }
+    }
+}
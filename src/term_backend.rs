@@ -0,0 +1,199 @@
+use vt100::Parser;
+
+use crate::{callbacks::TermCallbacks, colors::ColorState, palette::Palette};
+
+/// A cell colour, before it's been looked up in a palette.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum CellColor {
+    #[default]
+    Default,
+    Idx(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CellAttrs {
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+    /// SGR 8, the text is there but shouldn't be seen.
+    pub invisible: bool,
+}
+
+/// Everything the renderer wants to know about one cell.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TermCell {
+    /// Only the first character is drawn, combining characters are dropped.
+    pub chr: char,
+    pub fg: CellColor,
+    pub bg: CellColor,
+    pub attrs: CellAttrs,
+    /// The cell to the right is the other half of this one.
+    pub wide: bool,
+}
+
+impl Default for TermCell {
+    fn default() -> Self {
+        TermCell {
+            chr: ' ',
+            fg: CellColor::Default,
+            bg: CellColor::Default,
+            attrs: CellAttrs::default(),
+            wide: false,
+        }
+    }
+}
+
+/// What the renderer needs from a terminal emulator, so it doesn't have to care which one it's drawing.
+///
+/// Rows and columns count from the top left, starting at zero.
+pub trait TermScreen {
+    /// `(rows, cols)`
+    fn size(&self) -> (usize, usize);
+    /// `None` if `row`, `col` is off the screen.
+    fn cell(&self, row: usize, col: usize) -> Option<TermCell>;
+    /// `(row, col)`
+    fn cursor_position(&self) -> (usize, usize);
+    fn hide_cursor(&self) -> bool;
+    fn alternate_screen(&self) -> bool;
+    /// What the status bar shows, empty if nothing has been set.
+    fn title(&self) -> &str;
+    /// The default colours, the cursor colour and the margin fill.
+    fn colors(&self) -> ColorState;
+    fn palette(&self) -> &Palette;
+}
+
+impl From<vt100::Color> for CellColor {
+    fn from(c: vt100::Color) -> Self {
+        match c {
+            vt100::Color::Default => CellColor::Default,
+            vt100::Color::Idx(i) => CellColor::Idx(i),
+            vt100::Color::Rgb(r, g, b) => CellColor::Rgb(r, g, b),
+        }
+    }
+}
+
+impl TermScreen for Parser<TermCallbacks> {
+    fn size(&self) -> (usize, usize) {
+        let (rows, cols) = self.screen().size();
+        (rows as usize, cols as usize)
+    }
+    fn cell(&self, row: usize, col: usize) -> Option<TermCell> {
+        let cell = self
+            .screen()
+            .cell(row.try_into().ok()?, col.try_into().ok()?)?;
+        Some(TermCell {
+            chr: cell.contents().chars().next().unwrap_or(' '),
+            fg: cell.fgcolor().into(),
+            bg: cell.bgcolor().into(),
+            attrs: CellAttrs {
+                bold: cell.bold(),
+                dim: cell.dim(),
+                italic: cell.italic(),
+                underline: cell.underline(),
+                inverse: cell.inverse(),
+                //vt100 drops SGR 8 on the floor
+                invisible: false,
+            },
+            wide: cell.is_wide(),
+        })
+    }
+    fn cursor_position(&self) -> (usize, usize) {
+        let (row, col) = self.screen().cursor_position();
+        (row as usize, col as usize)
+    }
+    fn hide_cursor(&self) -> bool {
+        self.screen().hide_cursor()
+    }
+    fn alternate_screen(&self) -> bool {
+        self.screen().alternate_screen()
+    }
+    fn title(&self) -> &str {
+        let callbacks = self.callbacks();
+        if callbacks.title.is_empty() {
+            &callbacks.icon_name
+        } else {
+            &callbacks.title
+        }
+    }
+    fn colors(&self) -> ColorState {
+        self.callbacks().colors
+    }
+    fn palette(&self) -> &Palette {
+        &self.callbacks().palette
+    }
+}
+
+/// A screen you fill in by hand, for trying out the renderer without an emulator behind it.
+pub struct SyntheticScreen {
+    pub rows: usize,
+    pub cols: usize,
+    /// Row major, `rows * cols` long.
+    pub cells: Vec<TermCell>,
+    pub cursor: (usize, usize),
+    pub cursor_hidden: bool,
+    pub alternate: bool,
+    pub title: String,
+    pub colors: ColorState,
+    pub palette: Palette,
+}
+
+impl SyntheticScreen {
+    pub fn new(rows: usize, cols: usize) -> SyntheticScreen {
+        SyntheticScreen {
+            rows,
+            cols,
+            cells: vec![TermCell::default(); rows * cols],
+            cursor: (0, 0),
+            cursor_hidden: false,
+            alternate: false,
+            title: String::new(),
+            colors: ColorState::default(),
+            palette: Palette::XTERM,
+        }
+    }
+    /// Writes `text` from `row`, `col` onwards with the same colours and attributes, stopping at the end of the row.
+    pub fn put_str(&mut self, row: usize, col: usize, text: &str, template: &TermCell) {
+        if row >= self.rows {
+            return;
+        }
+        for (col, chr) in (col..self.cols).zip(text.chars()) {
+            self.cells[row * self.cols + col] = TermCell {
+                chr,
+                ..template.clone()
+            };
+        }
+    }
+}
+
+impl TermScreen for SyntheticScreen {
+    fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+    fn cell(&self, row: usize, col: usize) -> Option<TermCell> {
+        if row >= self.rows || col >= self.cols {
+            return None;
+        }
+        self.cells.get(row * self.cols + col).cloned()
+    }
+    fn cursor_position(&self) -> (usize, usize) {
+        self.cursor
+    }
+    fn hide_cursor(&self) -> bool {
+        self.cursor_hidden
+    }
+    fn alternate_screen(&self) -> bool {
+        self.alternate
+    }
+    fn title(&self) -> &str {
+        &self.title
+    }
+    fn colors(&self) -> ColorState {
+        self.colors
+    }
+    fn palette(&self) -> &Palette {
+        &self.palette
+    }
+}
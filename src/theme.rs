@@ -0,0 +1,410 @@
+use crate::{callbacks::TermCallbacks, colors::ColorState, palette::Palette};
+
+/// Where a theme is looked for at startup.
+pub const THEME_PATH: &str = "ux0:data/vitatty/theme";
+
+/// A colour scheme: the palette plus the default colours. Anything the theme file doesn't mention keeps the built in value.
+#[derive(Clone, Debug)]
+pub struct Theme {
+    /// Themes usually only set the first 16, the cube and grey ramp stay as they are.
+    pub palette: Palette,
+    pub colors: ColorState,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            palette: Palette::XTERM,
+            colors: ColorState::default(),
+        }
+    }
+}
+
+impl Theme {
+    /// Works out the format from the contents, so it doesn't matter what the file was called.
+    pub fn parse(text: &str) -> Result<Theme, String> {
+        let mut builder = ThemeBuilder::default();
+        if text.contains("<plist") || text.contains("<dict>") {
+            parse_iterm(text, &mut builder)?;
+        } else if text.lines().any(|l| base16_key(l).is_some()) {
+            parse_base16(text, &mut builder);
+        } else if text.lines().any(|l| l.trim_start().starts_with("[colors")) {
+            parse_alacritty(text, &mut builder);
+        } else {
+            parse_xresources(text, &mut builder);
+        }
+        builder.finish()
+    }
+    /// Makes this the session's colours, and what the reset sequences go back to.
+    pub fn apply(&self, callbacks: &mut TermCallbacks) {
+        callbacks.palette = self.palette;
+        callbacks.default_palette = self.palette;
+        callbacks.colors = self.colors;
+        callbacks.default_colors = self.colors;
+    }
+}
+
+struct ThemeBuilder {
+    palette: [Option<u32>; 256],
+    foreground: Option<u32>,
+    background: Option<u32>,
+    cursor: Option<u32>,
+}
+
+impl Default for ThemeBuilder {
+    fn default() -> Self {
+        ThemeBuilder {
+            palette: [None; 256],
+            foreground: None,
+            background: None,
+            cursor: None,
+        }
+    }
+}
+
+impl ThemeBuilder {
+    fn finish(self) -> Result<Theme, String> {
+        if self.palette.iter().all(Option::is_none)
+            && self.foreground.is_none()
+            && self.background.is_none()
+        {
+            return Err("No colours found in theme".to_string());
+        }
+        let mut theme = Theme::default();
+        for (i, color) in (0..=255).zip(self.palette) {
+            if let Some(color) = color {
+                theme.palette.set(i, color);
+            }
+        }
+        let colors = &mut theme.colors;
+        colors.foreground = self.foreground.unwrap_or(colors.foreground);
+        colors.background = self.background.unwrap_or(colors.background);
+        colors.cursor = self.cursor.unwrap_or(colors.foreground);
+        //Otherwise a dark theme ends up with a white border round it
+        colors.margin = colors.background;
+        Ok(theme)
+    }
+}
+
+/// `#RRGGBB`, `0xRRGGBB`, bare `RRGGBB`, or anything [`crate::colors::parse_color_spec`] understands.
+fn parse_hex_color(value: &str) -> Option<u32> {
+    let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+    let hex = value
+        .strip_prefix('#')
+        .or_else(|| value.strip_prefix("0x"))
+        .unwrap_or(value);
+    if hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return u32::from_str_radix(hex, 16).ok();
+    }
+    crate::colors::parse_color_spec(value.as_bytes())
+}
+
+/// Lines like `*.color4: #268bd2` or `URxvt.foreground: #839496`, with `#define` macros and `!` comments.
+fn parse_xresources(text: &str, builder: &mut ThemeBuilder) {
+    let mut defines: Vec<(&str, &str)> = vec![];
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('!') {
+            continue;
+        }
+        if let Some(define) = line.strip_prefix("#define") {
+            let mut parts = define.split_whitespace();
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                defines.push((name, value));
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let value = defines
+            .iter()
+            .find(|(name, _)| *name == value)
+            .map_or(value, |(_, v)| v);
+        let Some(color) = parse_hex_color(value) else {
+            continue;
+        };
+        //Only the part after the last `.` or `*` matters
+        let name = key.trim().rsplit(['.', '*']).next().unwrap_or("");
+        match name {
+            "foreground" => builder.foreground = Some(color),
+            "background" => builder.background = Some(color),
+            "cursorColor" => builder.cursor = Some(color),
+            _ => {
+                if let Some(i) = name
+                    .strip_prefix("color")
+                    .and_then(|i| i.parse::<usize>().ok())
+                    .filter(|&i| i < 256)
+                {
+                    builder.palette[i] = Some(color);
+                }
+            }
+        }
+    }
+}
+
+/// Which base16 colour each of the first 22 palette entries gets, the same as base16-shell does it.
+const BASE16_SLOTS: [usize; 22] = [
+    0x0, 0x8, 0xB, 0xA, 0xD, 0xE, 0xC, 0x5, 0x3, 0x8, 0xB, 0xA, 0xD, 0xE, 0xC, 0x7, 0x9, 0xF, 0x1,
+    0x2, 0x4, 0x6,
+];
+
+/// Which base16 colour a YAML line like `base0D: "81a2be"` sets, and the rest of the line.
+///
+/// Xresources files often `#define base0D`, so it has to be the key of the line, not just somewhere in it.
+fn base16_key(line: &str) -> Option<(usize, &str)> {
+    let (key, value) = line.split_once(':')?;
+    let slot = key
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'')
+        .strip_prefix("base0")?;
+    if slot.len() != 1 {
+        return None;
+    }
+    Some((usize::from_str_radix(slot, 16).ok()?, value))
+}
+
+/// `base00: "1d1f21"` and so on, wherever they turn up in the file.
+fn parse_base16(text: &str, builder: &mut ThemeBuilder) {
+    let mut base = [None; 16];
+    for line in text.lines() {
+        let Some((slot, value)) = base16_key(line) else {
+            continue;
+        };
+        //Comments can follow the value
+        let value = value.split(" #").next().unwrap_or("");
+        base[slot] = parse_hex_color(value);
+    }
+    for (out, &slot) in builder.palette.iter_mut().zip(BASE16_SLOTS.iter()) {
+        *out = base[slot];
+    }
+    builder.foreground = base[0x5];
+    builder.background = base[0x0];
+    builder.cursor = base[0x5];
+}
+
+const ANSI_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+/// The `[colors.primary]`, `[colors.cursor]`, `[colors.normal]` and `[colors.bright]` tables.
+fn parse_alacritty(text: &str, builder: &mut ThemeBuilder) {
+    let mut section = "";
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            section = header.trim_end_matches(']').trim();
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.split(" #").next().unwrap_or("");
+        let Some(color) = parse_hex_color(value) else {
+            continue;
+        };
+        let key = key.trim();
+        match (section, key) {
+            ("colors.primary", "foreground") => builder.foreground = Some(color),
+            ("colors.primary", "background") => builder.background = Some(color),
+            ("colors.cursor", "cursor") => builder.cursor = Some(color),
+            ("colors.normal" | "colors.bright", _) => {
+                if let Some(i) = ANSI_NAMES.iter().position(|n| *n == key) {
+                    let i = if section == "colors.bright" { i + 8 } else { i };
+                    builder.palette[i] = Some(color);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// An `.itermcolors` plist: a dict of `Ansi N Color`, `Foreground Color` and so on, each a dict of float components.
+fn parse_iterm(text: &str, builder: &mut ThemeBuilder) -> Result<(), String> {
+    let mut tags = PlistTags { rest: text };
+    let mut current_name: Option<String> = None;
+    let mut component: Option<String> = None;
+    let mut rgb = [0.0f32; 3];
+    let mut depth = 0usize;
+    while let Some((tag, content)) = tags.next() {
+        match tag {
+            "dict" => depth += 1,
+            "/dict" => {
+                //Leaving a colour's dict means it's complete
+                if depth == 2
+                    && let Some(name) = current_name.take()
+                {
+                    let [r, g, b] = rgb.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u32);
+                    let color = (r << 16) | (g << 8) | b;
+                    match name.as_str() {
+                        "Foreground Color" => builder.foreground = Some(color),
+                        "Background Color" => builder.background = Some(color),
+                        "Cursor Color" => builder.cursor = Some(color),
+                        _ => {
+                            if let Some(i) = name
+                                .strip_prefix("Ansi ")
+                                .and_then(|n| n.strip_suffix(" Color"))
+                                .and_then(|n| n.parse::<usize>().ok())
+                                .filter(|&i| i < 256)
+                            {
+                                builder.palette[i] = Some(color);
+                            }
+                        }
+                    }
+                }
+                depth = depth.saturating_sub(1);
+            }
+            "key" if depth == 1 => {
+                current_name = Some(content.to_string());
+                rgb = [0.0; 3];
+            }
+            "key" if depth == 2 => component = Some(content.to_string()),
+            "real" | "integer" if depth == 2 => {
+                let value: f32 = content
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Bad number in theme: {content}"))?;
+                match component.take().as_deref() {
+                    Some("Red Component") => rgb[0] = value,
+                    Some("Green Component") => rgb[1] = value,
+                    Some("Blue Component") => rgb[2] = value,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Just enough XML to walk a plist: yields each tag name along with the text that follows it.
+struct PlistTags<'a> {
+    rest: &'a str,
+}
+
+impl<'a> PlistTags<'a> {
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        loop {
+            let start = self.rest.find('<')?;
+            let end = start + self.rest[start..].find('>')?;
+            let tag = &self.rest[start + 1..end];
+            self.rest = &self.rest[end + 1..];
+            //Skip the prolog, doctype and comments
+            if tag.starts_with('?') || tag.starts_with('!') {
+                continue;
+            }
+            let content = &self.rest[..self.rest.find('<').unwrap_or(self.rest.len())];
+            //`<dict/>` comes out as `dict/`, so it isn't mistaken for an opening tag
+            let name = tag.split_whitespace().next().unwrap_or("");
+            return Some((name, content));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base16_yaml() {
+        let mut text = String::from("scheme: \"Tomorrow Night\"\nauthor: \"Chris Kempson\"\n");
+        for slot in 0..16 {
+            text += &format!("base0{slot:X}: \"{:06x}\" # comment\n", slot * 0x10101);
+        }
+        let theme = Theme::parse(&text).unwrap();
+        assert_eq!(theme.colors.background, 0x000000);
+        assert_eq!(theme.colors.foreground, 0x050505);
+        assert_eq!(theme.palette.get(1), 0x080808);
+        assert_eq!(theme.palette.get(4), 0x0D0D0D);
+        assert_eq!(theme.palette.get(15), 0x070707);
+        assert_eq!(theme.palette.get(21), 0x060606);
+    }
+
+    #[test]
+    fn xresources_with_base16_defines() {
+        let text = "\
+! Base16 Tomorrow Night
+#define base00 #1d1f21
+#define base05 #c5c8c6
+#define base08 #cc6666
+*.foreground: base05
+*.background: base00
+*.color1: base08
+URxvt*color12: #81a2be
+*cursorColor: #ffffff
+";
+        let theme = Theme::parse(text).unwrap();
+        assert_eq!(theme.colors.foreground, 0xC5C8C6);
+        assert_eq!(theme.colors.background, 0x1D1F21);
+        assert_eq!(theme.colors.cursor, 0xFFFFFF);
+        assert_eq!(theme.colors.margin, 0x1D1F21);
+        assert_eq!(theme.palette.get(1), 0xCC6666);
+        assert_eq!(theme.palette.get(12), 0x81A2BE);
+        //Untouched
+        assert_eq!(theme.palette.get(2), Palette::XTERM.get(2));
+    }
+
+    #[test]
+    fn alacritty_toml() {
+        let text = "\
+# Solarized
+[colors.primary]
+background = '#002b36'
+foreground = \"#839496\"
+
+[colors.normal]
+red = '0xdc322f'
+
+[colors.bright]
+blue = '#839496' # not really
+";
+        let theme = Theme::parse(text).unwrap();
+        assert_eq!(theme.colors.background, 0x002B36);
+        assert_eq!(theme.colors.foreground, 0x839496);
+        assert_eq!(theme.colors.cursor, 0x839496);
+        assert_eq!(theme.palette.get(1), 0xDC322F);
+        assert_eq!(theme.palette.get(12), 0x839496);
+    }
+
+    #[test]
+    fn iterm_plist() {
+        let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Ansi 1 Color</key>
+	<dict>
+		<key>Blue Component</key>
+		<real>0.0</real>
+		<key>Green Component</key>
+		<real>0.5</real>
+		<key>Red Component</key>
+		<integer>1</integer>
+	</dict>
+	<key>Background Color</key>
+	<dict>
+		<key>Alpha Component</key>
+		<real>1</real>
+		<key>Blue Component</key>
+		<real>0.2</real>
+		<key>Green Component</key>
+		<real>0.0</real>
+		<key>Red Component</key>
+		<real>0.0</real>
+	</dict>
+</dict>
+</plist>
+"#;
+        let theme = Theme::parse(text).unwrap();
+        assert_eq!(theme.palette.get(1), 0xFF8000);
+        assert_eq!(theme.colors.background, 0x000033);
+        assert_eq!(theme.colors.foreground, ColorState::default().foreground);
+    }
+
+    #[test]
+    fn nothing_to_find() {
+        assert!(Theme::parse("hello: world\n").is_err());
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+
+use crate::{
+    contrast::ensure_contrast,
+    cvd::CvdMode,
+    depth::ColorDepth,
+    font::Face,
+    palette::{self, rgb_to_gpu},
+    status_bar::truncate_title,
+    term_backend::{CellColor, TermScreen},
+};
+
+/// Changes made to every cell on the way to the GPU, on top of what the terminal asked for.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncodeOptions {
+    /// Swap foreground and background on every cell, and invert the emoji, used for the visual bell.
+    pub reverse_video: bool,
+    /// Lighten or darken foregrounds that don't stand out this much from their background, as a WCAG contrast ratio (4.5 is a good start).
+    ///
+    /// Cells that are hidden on purpose, being invisible or having the same foreground and background, are left alone.
+    pub min_contrast: Option<f32>,
+    /// Done after contrast, to every colour that ends up on screen. The margin, images and emoji get it too, outside of here.
+    pub cvd: CvdMode,
+    /// Done last, so whatever contrast and colour blindness do still ends up as one of the colours allowed.
+    pub depth: ColorDepth,
+    /// Draw runs of cells with the font's programming ligatures, like `->` as an arrow. Only outline fonts have them.
+    pub ligatures: bool,
+}
+
+const STYLE_BOLD: usize = 0b001;
+const STYLE_DIM: usize = 0b010;
+const STYLE_ITALIC: usize = 0b100;
+const STYLE_COLOR: usize = 0b0100000;
+const STYLE_WIDE: usize = 0b1000000;
+const STYLE_TINT: usize = 0b10000000;
+
+/// Where to find a cell's glyph, from the `glyphs` given to [`TileEncoder::encode`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GlyphRef {
+    /// The top left pixel of the glyph in its atlas.
+    pub origin: [u16; 2],
+    /// Which face's atlas it's in, which isn't always the face asked for.
+    pub face: Face,
+    /// It's in the colour emoji atlas instead, and is drawn in its own colours, or in monochrome as a shape in the text colour.
+    pub color: bool,
+}
+
+/// Turns a [`TermScreen`] into the buffer `NeoCharRender` hands to GL. Nothing in here touches GL, so it can be run against a fake screen.
+///
+/// The buffer is four runs of one `u32` per tile, all in grid order:
+/// background colours, foreground colours, tile infos, then where each tile's glyph is in the atlas.
+/// A tile info is column, row and style bits, one byte each from the bottom up.
+/// The style bits are bold, dim and slant (for faking italics) in bits 0 to 2, then which face's atlas the glyph is in, in bits 3 and 4.
+/// Bit 5 means the glyph is a colour emoji, drawn from the emoji atlas in its own colours, and bit 6 that it's two cells wide.
+/// Bit 7 goes with bit 5 in monochrome, and draws the emoji's shape in the foreground colour instead, since its own colours can't be shown.
+/// A glyph position is the x of the glyph's top left pixel in the atlas in the bottom 16 bits, and the y in the top 16.
+pub struct TileEncoder {
+    pub big_buffer: Vec<u32>,
+    /// Whether the top row of the grid shows the window title instead of being part of the terminal.
+    pub status_bar: bool,
+    pub rows: usize,
+    pub cols: usize,
+}
+
+impl TileEncoder {
+    /// `rows` and `cols` are the size of the terminal, not counting the status bar.
+    pub fn new(rows: usize, cols: usize, status_bar: bool) -> TileEncoder {
+        let n_tiles = (rows + status_bar as usize) * cols;
+        TileEncoder {
+            big_buffer: vec![0u32; n_tiles * 4],
+            status_bar,
+            rows,
+            cols,
+        }
+    }
+    pub fn grid_rows(&self) -> usize {
+        self.rows + self.status_bar as usize
+    }
+    pub fn n_tiles(&self) -> usize {
+        self.grid_rows() * self.cols
+    }
+    /// Fills the buffer from `term`. Cells outside `term` are drawn blank, so a screen of the wrong size still works.
+    ///
+    /// `glyphs` says where a character is for the face it asks for, and whether its cell is wide.
+    /// A colour glyph for a wide cell covers the cell to its right as well, which is left blank.
+    /// It's also told which cell of `term` it's for, as `(row, col)`, or `None` for the status bar.
+    pub fn encode(
+        &mut self,
+        term: &impl TermScreen,
+        glyphs: &mut impl FnMut(char, Face, bool, Option<(usize, usize)>) -> GlyphRef,
+        options: EncodeOptions,
+    ) {
+        let pal = term.palette();
+        let colors = term.colors();
+        let n_tiles = self.n_tiles();
+        let mut index = 0;
+        let big_buffer = &mut self.big_buffer;
+        let row_offset = self.status_bar as usize;
+        //A screen only has a handful of colours on it, no point transforming each one thousands of times
+        let mut cvd_cache: HashMap<u32, u32> = HashMap::new();
+        let mut cvd = |rgb: u32| match options.cvd {
+            CvdMode::Off => rgb,
+            mode => *cvd_cache.entry(rgb).or_insert_with(|| mode.apply(rgb)),
+        };
+        let mut depth_cache: HashMap<u32, u32> = HashMap::new();
+        let mut snap = |rgb: u32| match options.depth {
+            ColorDepth::TrueColor => rgb,
+            depth => *depth_cache
+                .entry(rgb)
+                .or_insert_with(|| depth.quantize(rgb, pal)),
+        };
+        //Colours go in as 0xRRGGBB, and are made colour blind friendly and then snapped to the depth, in that order
+        let mut push_tile =
+            |row: usize, col: usize, chr: char, fg: u32, bg: u32, style: usize, wide: bool| {
+                let (fg, bg) = (cvd(fg), cvd(bg));
+                let (fg, bg) = match options.depth {
+                    ColorDepth::Mono { .. } => {
+                        let bg_snapped = snap(bg);
+                        let fg = options.depth.quantize_text(fg, bg, bg_snapped, pal);
+                        (fg, bg_snapped)
+                    }
+                    _ => (snap(fg), snap(bg)),
+                };
+                let (fg, bg) = (rgb_to_gpu(fg), rgb_to_gpu(bg));
+                let italic = style & STYLE_ITALIC != 0;
+                let face = Face::from_style(style & STYLE_BOLD != 0, italic);
+                let at = row.checked_sub(row_offset).map(|row| (row, col));
+                let glyph = glyphs(chr, face, wide, at);
+                let [u, v] = glyph.origin.map(|x| x as u32);
+                //Only slant glyphs that asked for italics and didn't get them, and never emoji
+                let style = if glyph.face.is_italic() || glyph.color {
+                    style & !STYLE_ITALIC
+                } else {
+                    style
+                };
+                let style = style | ((glyph.face as usize) << 3);
+                let style = match (glyph.color, wide) {
+                    (true, true) => style | STYLE_COLOR | STYLE_WIDE,
+                    (true, false) => style | STYLE_COLOR,
+                    (false, _) => style,
+                };
+                let style = match options.depth {
+                    ColorDepth::Mono { .. } if glyph.color => style | STYLE_TINT,
+                    _ => style,
+                };
+                //we can assume usize = u32
+                let tile_info = col | (row << 8) | (style << 16);
+                big_buffer[index] = bg;
+                big_buffer[n_tiles + index] = fg;
+                big_buffer[n_tiles * 2 + index] = tile_info as _;
+                big_buffer[n_tiles * 3 + index] = u | (v << 16);
+                index += 1; //IMPORTANT
+            };
+        if self.status_bar {
+            let title = truncate_title(term.title(), self.cols);
+            let mut title_chars = title.chars();
+            //Inverse of the default colours, so it stands apart from whatever is running
+            let (fg_color, bg_color) = (0x000000, pal.get(7));
+            for col in 0..self.cols {
+                let chr = title_chars.next().unwrap_or(' ');
+                push_tile(0, col, chr, fg_color, bg_color, 0, false);
+            }
+        }
+        let resolve = |c: CellColor, d: u32| match c {
+            CellColor::Default => d,
+            CellColor::Idx(i) => pal.get(i),
+            CellColor::Rgb(r, g, b) => palette::rgb(r, g, b),
+        };
+        let cursor = (!term.hide_cursor()).then(|| term.cursor_position());
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                let cell = term.cell(row, col).unwrap_or_default();
+                let fg_color = resolve(cell.fg, colors.foreground);
+                let bg_color = resolve(cell.bg, colors.background);
+                let (fg_color, bg_color) = if cursor == Some((row, col)) {
+                    //A block cursor, with the text under it cut out of the cursor colour
+                    (colors.background, colors.cursor)
+                } else if cell.attrs.inverse != options.reverse_video {
+                    (bg_color, fg_color)
+                } else {
+                    (fg_color, bg_color)
+                };
+                let fg_color = match options.min_contrast {
+                    Some(ratio) if !cell.attrs.invisible && fg_color != bg_color => {
+                        ensure_contrast(fg_color, bg_color, ratio)
+                    }
+                    _ => fg_color,
+                };
+                //Draw the glyph, but in the background colour
+                let fg_color = if cell.attrs.invisible {
+                    bg_color
+                } else {
+                    fg_color
+                };
+                let bit = |b, n| if b { n } else { 0 };
+                let tile_style = bit(cell.attrs.bold, STYLE_BOLD)
+                    | bit(cell.attrs.dim, STYLE_DIM)
+                    | bit(cell.attrs.italic, STYLE_ITALIC);
+                push_tile(
+                    row + row_offset,
+                    col,
+                    cell.chr,
+                    fg_color,
+                    bg_color,
+                    tile_style,
+                    cell.wide,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        palette::{Palette, gpu_to_rgb},
+        term_backend::{CellAttrs, SyntheticScreen, TermCell},
+    };
+
+    /// Everything is at `[chr, face]` in the atlas, and italics never have a face of their own.
+    fn glyphs(chr: char, face: Face, _wide: bool, _at: Option<(usize, usize)>) -> GlyphRef {
+        let face = Face::from_style(matches!(face, Face::Bold | Face::BoldItalic), false);
+        GlyphRef {
+            origin: [chr as u16, face as u16],
+            face,
+            color: false,
+        }
+    }
+
+    /// `(bg, fg, tile info, glyph)` for every tile.
+    fn tiles(encoder: &TileEncoder) -> Vec<[u32; 4]> {
+        let n = encoder.n_tiles();
+        let b = &encoder.big_buffer;
+        (0..n)
+            .map(|i| [b[i], b[n + i], b[n * 2 + i], b[n * 3 + i]])
+            .collect()
+    }
+
+    fn info(row: usize, col: usize, style: usize) -> u32 {
+        (col | (row << 8) | (style << 16)) as u32
+    }
+
+    fn screen() -> SyntheticScreen {
+        let mut screen = SyntheticScreen::new(2, 3);
+        let red = TermCell {
+            fg: CellColor::Idx(1),
+            ..TermCell::default()
+        };
+        screen.put_str(0, 0, "a", &red);
+        let bold_italic = TermCell {
+            attrs: CellAttrs {
+                bold: true,
+                italic: true,
+                ..CellAttrs::default()
+            },
+            ..TermCell::default()
+        };
+        screen.put_str(0, 1, "b", &bold_italic);
+        let inverse = TermCell {
+            bg: CellColor::Rgb(0x12, 0x34, 0x56),
+            attrs: CellAttrs {
+                inverse: true,
+                ..CellAttrs::default()
+            },
+            ..TermCell::default()
+        };
+        screen.put_str(0, 2, "c", &inverse);
+        screen.put_str(1, 0, "de", &TermCell::default());
+        screen.cursor = (1, 1);
+        screen.colors.cursor = 0x00FF00;
+        screen
+    }
+
+    #[test]
+    fn cells_are_encoded_in_grid_order() {
+        let screen = screen();
+        let mut encoder = TileEncoder::new(2, 3, false);
+        encoder.encode(&screen, &mut glyphs, EncodeOptions::default());
+        let (white, black) = (rgb_to_gpu(0xFFFFFF), rgb_to_gpu(0x000000));
+        let glyph = |c: char, face: Face| c as u32 | ((face as u32) << 16);
+        assert_eq!(
+            tiles(&encoder),
+            [
+                [
+                    black,
+                    Palette::XTERM.gpu(1),
+                    info(0, 0, 0),
+                    glyph('a', Face::Regular)
+                ],
+                //Slanted, the bold face it got isn't italic
+                [
+                    black,
+                    white,
+                    info(0, 1, STYLE_BOLD | STYLE_ITALIC | (Face::Bold as usize) << 3),
+                    glyph('b', Face::Bold)
+                ],
+                [
+                    white,
+                    rgb_to_gpu(0x123456),
+                    info(0, 2, 0),
+                    glyph('c', Face::Regular)
+                ],
+                [black, white, info(1, 0, 0), glyph('d', Face::Regular)],
+                //The cursor
+                [
+                    rgb_to_gpu(0x00FF00),
+                    black,
+                    info(1, 1, 0),
+                    glyph('e', Face::Regular)
+                ],
+                [black, white, info(1, 2, 0), glyph(' ', Face::Regular)],
+            ]
+        );
+    }
+
+    #[test]
+    fn reverse_video_swaps_every_cell_but_the_cursor() {
+        let screen = screen();
+        let mut encoder = TileEncoder::new(2, 3, false);
+        let options = EncodeOptions {
+            reverse_video: true,
+            ..EncodeOptions::default()
+        };
+        encoder.encode(&screen, &mut glyphs, options);
+        let (white, black) = (rgb_to_gpu(0xFFFFFF), rgb_to_gpu(0x000000));
+        let colors: Vec<[u32; 2]> = tiles(&encoder).iter().map(|t| [t[0], t[1]]).collect();
+        assert_eq!(
+            colors,
+            [
+                [Palette::XTERM.gpu(1), black],
+                [white, black],
+                //Inverse twice over is back to normal
+                [rgb_to_gpu(0x123456), white],
+                [white, black],
+                [rgb_to_gpu(0x00FF00), black],
+                [white, black],
+            ]
+        );
+    }
+
+    #[test]
+    fn the_status_bar_pushes_the_screen_down() {
+        let mut screen = screen();
+        screen.title = "hi".into();
+        screen.cursor_hidden = true;
+        let mut encoder = TileEncoder::new(2, 3, true);
+        let mut asked = Vec::new();
+        encoder.encode(
+            &screen,
+            &mut |chr, face, wide, at| {
+                asked.push(at);
+                glyphs(chr, face, wide, at)
+            },
+            EncodeOptions::default(),
+        );
+        let tiles = tiles(&encoder);
+        assert_eq!(tiles.len(), 9);
+        let title: String = tiles[..3].iter().map(|t| char::from(t[3] as u8)).collect();
+        assert_eq!(title, "hi ");
+        assert_eq!(tiles[0][..2], [Palette::XTERM.gpu(7), rgb_to_gpu(0x000000)]);
+        assert_eq!(tiles[3][2], info(1, 0, 0));
+        assert_eq!(asked[..4], [None, None, None, Some((0, 0))]);
+        //The cursor is hidden
+        assert_eq!(tiles[7][..2], [rgb_to_gpu(0x000000), rgb_to_gpu(0xFFFFFF)]);
+    }
+
+    #[test]
+    fn depth_is_done_after_contrast() {
+        let mut screen = SyntheticScreen::new(1, 1);
+        screen.cursor_hidden = true;
+        screen.cells[0].fg = CellColor::Rgb(0x10, 0x10, 0x30);
+        screen.cells[0].bg = CellColor::Idx(0);
+        let mut encoder = TileEncoder::new(1, 1, false);
+        let options = EncodeOptions {
+            min_contrast: Some(4.5),
+            depth: ColorDepth::Colors16,
+            ..EncodeOptions::default()
+        };
+        encoder.encode(&screen, &mut glyphs, options);
+        let fg = gpu_to_rgb(tiles(&encoder)[0][1]);
+        assert!((0..16).any(|i| Palette::XTERM.get(i) == fg), "{fg:06x}");
+        assert!(crate::contrast::contrast_ratio(fg, 0x000000) >= 4.5);
+    }
+
+    #[test]
+    fn default_colours_are_snapped_too() {
+        let mut screen = SyntheticScreen::new(1, 2);
+        screen.colors.foreground = 0xD8DEE9;
+        screen.colors.background = 0x2E3440;
+        screen.colors.cursor = 0x88C0D0;
+        screen.cursor = (0, 1);
+        let mut encoder = TileEncoder::new(1, 2, false);
+        let options = EncodeOptions {
+            depth: ColorDepth::Colors16,
+            ..EncodeOptions::default()
+        };
+        encoder.encode(&screen, &mut glyphs, options);
+        let pal = Palette::XTERM;
+        let snapped = |rgb| rgb_to_gpu(pal.get(pal.nearest_perceptual_in(rgb, 0..=15)));
+        let colors: Vec<[u32; 2]> = tiles(&encoder).iter().map(|t| [t[0], t[1]]).collect();
+        assert_eq!(
+            colors,
+            [
+                [snapped(0x2E3440), snapped(0xD8DEE9)],
+                [snapped(0x88C0D0), snapped(0x2E3440)],
+            ]
+        );
+        assert_ne!(colors[0][0], rgb_to_gpu(0x2E3440));
+    }
+
+    #[test]
+    fn mono_keeps_dark_text_on_dark_backgrounds() {
+        let mut screen = SyntheticScreen::new(1, 3);
+        screen.cursor_hidden = true;
+        //Dark blue on black, then hidden by matching, then black on dark blue
+        let navy = CellColor::Idx(4);
+        let black = CellColor::Idx(0);
+        for (cell, (fg, bg)) in
+            screen
+                .cells
+                .iter_mut()
+                .zip([(navy, black), (navy, navy), (black, navy)])
+        {
+            cell.fg = fg;
+            cell.bg = bg;
+        }
+        let mut encoder = TileEncoder::new(1, 3, false);
+        let options = EncodeOptions {
+            depth: ColorDepth::GREEN_SCREEN,
+            ..EncodeOptions::default()
+        };
+        encoder.encode(&screen, &mut glyphs, options);
+        let (green, black) = (rgb_to_gpu(0x33FF33), rgb_to_gpu(0x000000));
+        let colors: Vec<[u32; 2]> = tiles(&encoder).iter().map(|t| [t[0], t[1]]).collect();
+        assert_eq!(colors, [[black, green], [black, black], [black, green]]);
+    }
+
+    #[test]
+    fn colour_glyphs_are_marked() {
+        let mut screen = SyntheticScreen::new(1, 2);
+        screen.cursor_hidden = true;
+        screen.cells[0] = TermCell {
+            chr: '\u{1F680}',
+            wide: true,
+            ..TermCell::default()
+        };
+        let mut encoder = TileEncoder::new(1, 2, false);
+        encoder.encode(
+            &screen,
+            &mut |chr, face, wide, at| GlyphRef {
+                color: wide,
+                ..glyphs(chr, face, wide, at)
+            },
+            EncodeOptions::default(),
+        );
+        let tiles = tiles(&encoder);
+        assert_eq!(tiles[0][2], info(0, 0, STYLE_COLOR | STYLE_WIDE));
+        assert_eq!(tiles[1][2], info(0, 1, 0));
+    }
+
+    #[test]
+    fn monochrome_colour_glyphs_are_drawn_in_the_text_colour() {
+        let mut screen = SyntheticScreen::new(1, 1);
+        screen.cursor_hidden = true;
+        screen.cells[0].chr = '\u{1F525}';
+        let mut encoder = TileEncoder::new(1, 1, false);
+        let mut color_glyphs = |chr, face, wide, at| GlyphRef {
+            color: true,
+            ..glyphs(chr, face, wide, at)
+        };
+        encoder.encode(&screen, &mut color_glyphs, EncodeOptions::default());
+        assert_eq!(tiles(&encoder)[0][2], info(0, 0, STYLE_COLOR));
+        let options = EncodeOptions {
+            depth: ColorDepth::GREEN_SCREEN,
+            ..EncodeOptions::default()
+        };
+        encoder.encode(&screen, &mut color_glyphs, options);
+        let tile = tiles(&encoder)[0];
+        assert_eq!(tile[2], info(0, 0, STYLE_COLOR | STYLE_TINT));
+        assert_eq!(tile[1], rgb_to_gpu(0x33FF33));
+    }
+}
@@ -9,6 +9,11 @@ unsigned char4 in bg;
 // int uniform bigAssUniform[bigAssUniformSize] : BUFFER[0]; //pleeeease don't work
 // int uniform otherBigAssUniform[bigAssUniformSize] : BUFFER[0];
 float2 uniform charDim;
+// A glyph plus its padding, and the padding above and left of the first glyph, in UVs
+float2 uniform slotDim;
+float2 uniform paddingDim;
+// Glyph slots per row of the atlas
+float uniform atlasColumns;
 float3x2 uniform transform;
 unsigned int in gl_VertexIndex : INDEX;
 unsigned int in gl_InstanceID : INSTANCE;
@@ -29,9 +34,10 @@ void main() {
     float charCol = gl_InstanceID - (charRow * termWidth);
     float2 corner = float2(gl_VertexIndex & 1, (gl_VertexIndex & 2) >> 1);
     // tex_coord = (float2(uvfg.w >> 4, uvfg.w & 0xF) + corner) * charDim;
-    float2 uv = float2(uvfg.w & 0xF, uvfg.w >> 4) + corner;
+    float glyph = uvfg.w;
+    float2 slot = float2(glyph - floor(glyph / atlasColumns) * atlasColumns, floor(glyph / atlasColumns));
     // float2 uv = float2(8, 4) + corner;
-    tex_coord = uv * charDim;
+    tex_coord = slot * slotDim + paddingDim + corner * charDim;
     gl_Position = float4((float2(charCol, charRow) + corner) * float2(0.1, -0.1), 0.0, 1.0) + float4(-1.0, 1.0, 0.0, 0.0);
 
     // tex_coord = float4((float2(uvfg >> 28, (uvfg >> 24) & 0xF) + corner) * charDim, 0.0, 0.0);
@@ -0,0 +1,124 @@
+/// The Vita's screen, in pixels.
+pub const SCREEN_WIDTH: usize = 960;
+pub const SCREEN_HEIGHT: usize = 544;
+/// What `zoom in` and `zoom out` step between.
+pub const ZOOM_LEVELS: [f32; 9] = [0.5, 0.75, 1.0, 1.25, 1.5, 2.0, 2.5, 3.0, 4.0];
+
+/// A request to change the zoom, from port 9039.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ZoomCommand {
+    In,
+    Out,
+    To(f32),
+}
+
+impl ZoomCommand {
+    /// `in`, `out`, or a factor like `1.5`.
+    pub fn parse(text: &str) -> Option<ZoomCommand> {
+        match text.trim() {
+            "in" => Some(ZoomCommand::In),
+            "out" => Some(ZoomCommand::Out),
+            factor => factor
+                .parse::<f32>()
+                .ok()
+                .filter(|f| (ZOOM_LEVELS[0]..=ZOOM_LEVELS[ZOOM_LEVELS.len() - 1]).contains(f))
+                .map(ZoomCommand::To),
+        }
+    }
+    /// The zoom to go to from `current`. Stepping from somewhere between levels goes to the next level along.
+    pub fn apply(self, current: f32) -> f32 {
+        match self {
+            ZoomCommand::In => ZOOM_LEVELS
+                .into_iter()
+                .find(|&z| z > current)
+                .unwrap_or(current),
+            ZoomCommand::Out => ZOOM_LEVELS
+                .into_iter()
+                .rfind(|&z| z < current)
+                .unwrap_or(current),
+            ZoomCommand::To(zoom) => zoom,
+        }
+    }
+}
+
+/// `(rows, cols)` of cells `cell_size` pixels big that fit on the screen, not counting the status bar if there is one.
+pub fn fit_screen(cell_size: (f32, f32), status_bar: bool) -> (u16, u16) {
+    let grid_rows = (SCREEN_HEIGHT as f32 / cell_size.1) as usize;
+    let cols = (SCREEN_WIDTH as f32 / cell_size.0) as usize;
+    let rows = grid_rows.clamp(1 + status_bar as usize, 256) - status_bar as usize;
+    (rows as u16, cols.clamp(1, 256) as u16)
+}
+
+/// The transform that draws cells `cell_size` pixels big from the top left corner of the screen.
+pub fn screen_transform(cell_size: (f32, f32)) -> [f32; 9] {
+    let x_scale = 2.0 * cell_size.0 / SCREEN_WIDTH as f32;
+    let y_scale = 2.0 * cell_size.1 / SCREEN_HEIGHT as f32;
+    [x_scale, 0.0, -1.0, 0.0, -y_scale, 1.0, 0.0, 0.0, 1.0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(ZoomCommand::parse("in"), Some(ZoomCommand::In));
+        assert_eq!(ZoomCommand::parse(" out\n"), Some(ZoomCommand::Out));
+        assert_eq!(ZoomCommand::parse("1.5"), Some(ZoomCommand::To(1.5)));
+        assert_eq!(ZoomCommand::parse("1.1"), Some(ZoomCommand::To(1.1)));
+        assert_eq!(ZoomCommand::parse("4"), Some(ZoomCommand::To(4.0)));
+        assert_eq!(ZoomCommand::parse("0.25"), None);
+        assert_eq!(ZoomCommand::parse("5"), None);
+        assert_eq!(ZoomCommand::parse("NaN"), None);
+        assert_eq!(ZoomCommand::parse("sideways"), None);
+    }
+
+    #[test]
+    fn stepping_goes_between_levels() {
+        assert_eq!(ZoomCommand::In.apply(1.0), 1.25);
+        assert_eq!(ZoomCommand::Out.apply(1.0), 0.75);
+        assert_eq!(ZoomCommand::In.apply(2.0), 2.5);
+        assert_eq!(ZoomCommand::To(1.5).apply(3.0), 1.5);
+    }
+
+    #[test]
+    fn stepping_from_between_levels_goes_to_the_next_one_along() {
+        assert_eq!(ZoomCommand::In.apply(1.1), 1.25);
+        assert_eq!(ZoomCommand::Out.apply(1.1), 1.0);
+        assert_eq!(ZoomCommand::Out.apply(3.7), 3.0);
+    }
+
+    #[test]
+    fn stepping_stops_at_either_end() {
+        assert_eq!(ZoomCommand::In.apply(4.0), 4.0);
+        assert_eq!(ZoomCommand::Out.apply(0.5), 0.5);
+    }
+
+    #[test]
+    fn fitting_the_screen() {
+        //960x544 in 8x16 cells is 120x34, one of those rows going to the status bar
+        assert_eq!(fit_screen((8.0, 16.0), false), (34, 120));
+        assert_eq!(fit_screen((8.0, 16.0), true), (33, 120));
+        //Partial cells don't count
+        assert_eq!(fit_screen((12.0, 24.0), true), (21, 80));
+        assert_eq!(fit_screen((10.0, 20.0), false), (27, 96));
+    }
+
+    #[test]
+    fn fitting_the_screen_is_clamped() {
+        //Tiny cells still only get 256 of each
+        assert_eq!(fit_screen((1.0, 1.0), false), (256, 256));
+        assert_eq!(fit_screen((1.0, 1.0), true), (255, 256));
+        //And huge ones still get a cell, with the status bar on top
+        assert_eq!(fit_screen((2000.0, 2000.0), false), (1, 1));
+        assert_eq!(fit_screen((2000.0, 2000.0), true), (1, 1));
+    }
+
+    #[test]
+    fn the_transform_maps_cells_to_the_screen() {
+        let [a, b, c, d, e, f, ..] = screen_transform((8.0, 16.0));
+        let at = |x: f32, y: f32| (a * x + b * y + c, d * x + e * y + f);
+        assert_eq!(at(0.0, 0.0), (-1.0, 1.0));
+        assert_eq!(at(120.0, 34.0), (1.0, -1.0));
+    }
+}